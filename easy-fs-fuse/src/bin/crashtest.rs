@@ -0,0 +1,233 @@
+//! Crash easy-fs at every write of a workload, checking the image each time
+//!
+//! ```text
+//! crashtest [-n <crash points>]
+//! ```
+//!
+//! The workload creates, writes, links, unlinks and truncates files on a new
+//! image. A first run counts the blocks it writes. Every later run starts over
+//! from the new image and stops right before one of those writes, as a power
+//! cut or a killed QEMU would. Once mounted again, `fsck` must find the image
+//! consistent. Every write is a crash point, unless `-n` asks for fewer spread
+//! evenly. A block is assumed to be written entirely or not at all.
+//!
+//! Each run is a process of its own, as the block cache of easy-fs is global.
+
+use easy_fs::{block_cache_sync_all, fsck, BlockDevice, EasyFileSystem, Inode, BLOCK_SZ};
+use easy_fs_fuse::BlockFile;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::process::{exit, Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// blocks of the images, 2 MiB
+const TOTAL_BLOCKS: u32 = 4096;
+/// exit code of a run stopped at its crash point
+const CRASHED: i32 = 3;
+
+/// number of blocks written by this process
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// An image stopping the process right before its `crash_at`th write, or
+/// never if it is 0
+struct CrashFile {
+    file: BlockFile,
+    crash_at: usize,
+}
+
+impl BlockDevice for CrashFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.file.read_block(block_id, buf);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        if WRITES.fetch_add(1, Ordering::SeqCst) + 1 == self.crash_at {
+            exit(CRASHED);
+        }
+        self.file.write_block(block_id, buf);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: crashtest [-n <crash points>]");
+    exit(2);
+}
+
+/// `len` bytes of data that differ with `seed`
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+/// The operations crashed into, on a new file system
+fn workload(root: &Arc<Inode>) {
+    let dir = root.create_dir("dir").unwrap();
+    let a = dir.create("a").unwrap();
+    assert_eq!(a.write_at(0, &data(3000, 1)), 3000);
+    // past the direct blocks, by several transactions
+    let big = dir.create("big").unwrap();
+    assert_eq!(big.write_at(0, &data(40 * BLOCK_SZ, 2)), 40 * BLOCK_SZ);
+    assert!(root.link("a2", &a));
+    assert!(dir.unlink("a"));
+    // a second block of entries, then free entries to reuse
+    for i in 0..20 {
+        dir.create(&format!("f{}", i)).unwrap();
+    }
+    for i in (0..20).step_by(2) {
+        assert!(dir.unlink(&format!("f{}", i)));
+    }
+    // a hole up to the double indirect blocks
+    let g = dir.create("g").unwrap();
+    assert_eq!(g.write_at(170 * BLOCK_SZ, &data(100, 3)), 100);
+    big.clear();
+    assert!(dir.unlink("big"));
+    assert!(root.unlink("a2"));
+    assert!(!root.unlink("dir"));
+    assert_eq!(root.ls(), ["dir"]);
+    assert_eq!(dir.ls().len(), 11);
+    let mut buf = [0u8; 100];
+    assert_eq!(g.read_at(170 * BLOCK_SZ, &mut buf), 100);
+    assert_eq!(buf[..], data(100, 3)[..]);
+}
+
+/// Open the image at `path`, exiting if it cannot be
+fn open_image(path: &Path) -> BlockFile {
+    BlockFile::open(path).unwrap_or_else(|e| {
+        eprintln!("crashtest: {}", e);
+        exit(2);
+    })
+}
+
+/// Run the workload on the image at `path`, crashing at write `crash_at`, or
+/// printing the number of writes if it is 0
+fn run_workload(path: &Path, crash_at: usize) -> ! {
+    let block_file: Arc<dyn BlockDevice> = Arc::new(CrashFile {
+        file: open_image(path),
+        crash_at,
+    });
+    let efs = EasyFileSystem::open(block_file).expect("not an easy-fs image");
+    let root = Arc::new(EasyFileSystem::root_inode(&efs));
+    workload(&root);
+    block_cache_sync_all();
+    println!("{}", WRITES.load(Ordering::SeqCst));
+    exit(0);
+}
+
+/// Check the image at `path`, printing its problems
+fn check(path: &Path) -> ! {
+    let block_file: Arc<dyn BlockDevice> = Arc::new(open_image(path));
+    // mounting it again replays the journal, which a check alone does not
+    drop(EasyFileSystem::open(Arc::clone(&block_file)).expect("not an easy-fs image"));
+    let report = fsck(block_file, false).unwrap();
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    exit(if report.is_clean() { 0 } else { 1 });
+}
+
+/// Create a file system on a new image at `path`
+fn create_image(path: &Path) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    file.set_len(TOTAL_BLOCKS as u64 * BLOCK_SZ as u64)
+        .map_err(|e| e.to_string())?;
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(file)));
+    EasyFileSystem::create(block_file, TOTAL_BLOCKS, 1);
+    Ok(())
+}
+
+/// Run this program again with `args`
+fn run(args: &[&OsStr]) -> Result<Output, String> {
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    Command::new(exe)
+        .args(args)
+        .output()
+        .map_err(|e| e.to_string())
+}
+
+/// Crash the workload at `points` evenly spread writes, or all of them,
+/// returning the number of crashes leaving the image inconsistent
+fn crashtest(points: Option<usize>, base: &Path, work: &Path) -> Result<usize, String> {
+    create_image(base)?;
+    std::fs::copy(base, work).map_err(|e| e.to_string())?;
+    let output = run(&["--workload".as_ref(), work.as_ref(), "0".as_ref()])?;
+    if !output.status.success() {
+        return Err(format!(
+            "the workload fails without crashing: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let writes: usize = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|_| "the workload prints no number of writes")?;
+    let points = points.unwrap_or(writes).min(writes);
+    println!("{} writes, crashing at {} of them", writes, points);
+    let mut inconsistent = 0;
+    for i in 1..=points {
+        let crash_at = i * writes / points;
+        std::fs::copy(base, work).map_err(|e| e.to_string())?;
+        let crash_at_arg = crash_at.to_string();
+        let output = run(&["--workload".as_ref(), work.as_ref(), crash_at_arg.as_ref()])?;
+        if output.status.code() != Some(CRASHED) {
+            return Err(format!(
+                "the workload does not crash at write {}: {}",
+                crash_at,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        let output = run(&["--check".as_ref(), work.as_ref()])?;
+        if !output.status.success() {
+            inconsistent += 1;
+            print!(
+                "crash at write {}:\n{}{}",
+                crash_at,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+    }
+    Ok(inconsistent)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let points = match args[..] {
+        ["--workload", path, crash_at] => run_workload(
+            Path::new(path),
+            crash_at.parse().unwrap_or_else(|_| usage()),
+        ),
+        ["--check", path] => check(Path::new(path)),
+        [] => None,
+        ["-n", points] => match points.parse() {
+            Ok(points) if points > 0 => Some(points),
+            _ => usage(),
+        },
+        _ => usage(),
+    };
+    let prefix = format!("crashtest-{}", std::process::id());
+    let base = std::env::temp_dir().join(format!("{}.img", prefix));
+    let work = std::env::temp_dir().join(format!("{}-work.img", prefix));
+    let result = crashtest(points, &base, &work);
+    let _ = std::fs::remove_file(&base);
+    let _ = std::fs::remove_file(&work);
+    match result {
+        Ok(0) => println!("every crash left the image consistent"),
+        Ok(inconsistent) => {
+            println!("{} crashes left the image inconsistent", inconsistent);
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("crashtest: {}", e);
+            exit(1);
+        }
+    }
+}
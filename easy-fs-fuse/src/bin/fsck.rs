@@ -0,0 +1,80 @@
+//! Check an easy-fs image, and repair it with `-r`
+//!
+//! ```text
+//! fsck [-r] ../os/fs.img
+//! ```
+//!
+//! Without `-r`, the image is only read, and a transaction committed in the
+//! journal is reported but not replayed.
+//!
+//! The exit code follows e2fsck: 0 if the image is consistent, 1 if it was
+//! repaired, 4 if problems are left, and 8 if it cannot be checked.
+
+use easy_fs::{fsck, BlockDevice};
+use easy_fs_fuse::BlockFile;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+
+fn usage() -> ! {
+    eprintln!("usage: fsck [-r] <image>");
+    exit(8);
+}
+
+fn main() {
+    let mut repair = false;
+    let mut image = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "-r" => repair = true,
+            _ if image.is_none() && !arg.starts_with('-') => image = Some(PathBuf::from(arg)),
+            _ => usage(),
+        }
+    }
+    let Some(image) = image else {
+        usage();
+    };
+    let block_file: Arc<dyn BlockDevice> = match BlockFile::open(&image) {
+        Ok(block_file) => Arc::new(block_file),
+        Err(e) => {
+            eprintln!("fsck: {}", e);
+            exit(8);
+        }
+    };
+    let Some(report) = fsck(Arc::clone(&block_file), repair) else {
+        eprintln!("fsck: {}: not an easy-fs image", image.display());
+        exit(8);
+    };
+    if report.replayed {
+        println!("replayed the transaction left in the journal");
+    }
+    if report.pending {
+        println!("a transaction is left in the journal, replayed by -r or by mounting");
+    }
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    if report.is_clean() {
+        println!("{}: clean", image.display());
+        return;
+    }
+    if !repair {
+        println!("{}: {} problems", image.display(), report.problems.len());
+        exit(4);
+    }
+    // the repair holds if a second pass finds nothing left
+    let recheck = fsck(block_file, false).unwrap();
+    for problem in recheck.problems.iter() {
+        println!("left: {}", problem);
+    }
+    if !recheck.is_clean() {
+        println!(
+            "{}: {} problems left",
+            image.display(),
+            recheck.problems.len()
+        );
+        exit(4);
+    }
+    println!("{}: repaired", image.display());
+    exit(1);
+}
@@ -0,0 +1,41 @@
+//! Host tools for easy-fs images
+//!
+//! `easy-fs-fuse` packs the apps into an image, `fsck` checks and repairs an
+//! image, and `crashtest` checks that a crash at any write leaves an image
+//! consistent.
+
+use easy_fs::{BlockDevice, BLOCK_SZ};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// An image file used as a block device
+pub struct BlockFile(pub Mutex<File>);
+
+impl BlockFile {
+    /// Open the image at `path` for reading and writing
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Self(Mutex::new(file)))
+    }
+}
+
+impl BlockDevice for BlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.read_exact(buf).expect("Not a complete block!");
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.write_all(buf).expect("Not a complete block!");
+    }
+}
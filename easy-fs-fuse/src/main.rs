@@ -0,0 +1,132 @@
+//! Pack the user apps into an easy-fs image
+//!
+//! Every `<name>.elf` file of the source directories becomes `bin/<name>` in
+//! a new image, where the kernel loads the apps from:
+//!
+//! ```text
+//! easy-fs-fuse -s ../ci-user/user/build/elf [-s ../shell/build/elf] -o ../os/fs.img [-m 16]
+//! ```
+//!
+//! The apps are linked at the spaces given by the order of their names, and
+//! the shell at the last one. The kernel finds them again by sorting the
+//! names of the files in `bin`.
+
+use easy_fs::{block_cache_sync_all, BlockDevice, EasyFileSystem, BLOCK_SZ};
+use easy_fs_fuse::BlockFile;
+use std::fs::{read_dir, OpenOptions};
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+
+/// directory of the apps in the image, `APP_DIR` of the kernel
+const APP_DIR: &str = "bin";
+/// blocks of the inode bitmap, which is enough for 4096 inodes
+const INODE_BITMAP_BLOCKS: u32 = 1;
+/// size of the image unless given
+const DEFAULT_SIZE_MB: usize = 16;
+
+/// Command line of the packer
+struct Args {
+    /// directories of the ELF files of the apps
+    sources: Vec<PathBuf>,
+    /// image to create, replacing any file there
+    output: PathBuf,
+    /// size of the image in MiB
+    size_mb: usize,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: easy-fs-fuse -s <elf dir>... -o <image> [-m <size in MiB>]");
+    exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut sources = Vec::new();
+    let mut output = None;
+    let mut size_mb = DEFAULT_SIZE_MB;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "-s" => sources.push(PathBuf::from(value)),
+            "-o" => output = Some(PathBuf::from(value)),
+            "-m" => size_mb = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+    match output {
+        Some(output) if !sources.is_empty() && size_mb > 0 => Args {
+            sources,
+            output,
+            size_mb,
+        },
+        _ => usage(),
+    }
+}
+
+/// Names of the apps in `sources`, sorted, with the paths of their ELF files
+fn find_apps(sources: &[PathBuf]) -> Result<Vec<(String, PathBuf)>, String> {
+    let mut apps: Vec<(String, PathBuf)> = Vec::new();
+    for source in sources.iter() {
+        let dir = read_dir(source).map_err(|e| format!("{}: {}", source.display(), e))?;
+        for entry in dir {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().map_or(true, |ext| ext != "elf") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                return Err(format!("{}: name is not UTF-8", path.display()));
+            };
+            if let Some((_, other)) = apps.iter().find(|(other_name, _)| other_name == name) {
+                return Err(format!(
+                    "{}: also found at {}",
+                    path.display(),
+                    other.display()
+                ));
+            }
+            apps.push((String::from(name), path));
+        }
+    }
+    apps.sort();
+    Ok(apps)
+}
+
+fn pack(args: &Args) -> Result<(), String> {
+    let apps = find_apps(&args.sources)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&args.output)
+        .map_err(|e| format!("{}: {}", args.output.display(), e))?;
+    let total_blocks = args.size_mb * 1024 * 1024 / BLOCK_SZ;
+    file.set_len((total_blocks * BLOCK_SZ) as u64)
+        .map_err(|e| e.to_string())?;
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(file)));
+    let efs = EasyFileSystem::create(block_file, total_blocks as u32, INODE_BITMAP_BLOCKS);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let bin = root_inode
+        .create_dir(APP_DIR)
+        .ok_or("cannot create the app directory")?;
+    for (name, path) in apps.iter() {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let inode = bin
+            .create(name)
+            .ok_or_else(|| format!("{}: name too long, or the image is full", name))?;
+        if inode.write_at(0, &data) != data.len() {
+            return Err(format!("{}: the image is full", name));
+        }
+        println!("{} ({} bytes)", name, data.len());
+    }
+    block_cache_sync_all();
+    Ok(())
+}
+
+fn main() {
+    let args = parse_args();
+    if let Err(e) = pack(&args) {
+        eprintln!("easy-fs-fuse: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,130 @@
+//! Bitmaps telling which inodes and data blocks are in use
+
+use super::{get_block_cache, BlockDevice, BLOCK_SZ};
+use alloc::sync::Arc;
+
+/// A bitmap block
+type BitmapBlock = [u64; 64];
+/// Number of bits in a block
+const BLOCK_BITS: usize = BLOCK_SZ * 8;
+
+/// A bitmap spanning `blocks` blocks from `start_block_id`
+pub struct Bitmap {
+    start_block_id: usize,
+    blocks: usize,
+}
+
+/// Decompose bits into (block_pos, bits64_pos, inner_pos)
+fn decomposition(mut bit: usize) -> (usize, usize, usize) {
+    let block_pos = bit / BLOCK_BITS;
+    bit %= BLOCK_BITS;
+    (block_pos, bit / 64, bit % 64)
+}
+
+impl Bitmap {
+    /// A new bitmap from start block id and number of blocks
+    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+        Self {
+            start_block_id,
+            blocks,
+        }
+    }
+    /// Allocate a new block from a block device
+    ///
+    /// The full bitmap blocks are only read, so that the block taking the bit
+    /// is the only one the running transaction has to journal.
+    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+        for block_id in 0..self.blocks {
+            let block_cache =
+                get_block_cache(block_id + self.start_block_id, Arc::clone(block_device));
+            let mut block_cache = block_cache.lock();
+            let Some((bits64_pos, inner_pos)) =
+                block_cache.read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block
+                        .iter()
+                        .enumerate()
+                        .find(|(_, bits64)| **bits64 != u64::MAX)
+                        .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
+                })
+            else {
+                continue;
+            };
+            block_cache.modify(0, |bitmap_block: &mut BitmapBlock| {
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            });
+            return Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos);
+        }
+        None
+    }
+    /// Deallocate a block
+    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
+                bitmap_block[bits64_pos] -= 1u64 << inner_pos;
+            });
+    }
+    /// Whether `bit` is set
+    pub(crate) fn get(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+            })
+    }
+    /// Set or clear `bit`
+    pub(crate) fn set(&self, block_device: &Arc<dyn BlockDevice>, bit: usize, value: bool) {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                if value {
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                } else {
+                    bitmap_block[bits64_pos] &= !(1u64 << inner_pos);
+                }
+            });
+    }
+    /// Get the max number of allocatable blocks
+    pub fn maximum(&self) -> usize {
+        self.blocks * BLOCK_BITS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::exclusive_block_cache;
+    use crate::block_cache_sync_all;
+    use crate::block_dev::mem::MemDevice;
+
+    #[test]
+    fn alloc_only_dirties_the_block_it_takes_a_bit_from() {
+        let _cache = exclusive_block_cache();
+        let device = MemDevice::new(3);
+        let block_device: Arc<dyn BlockDevice> = device.clone();
+        for block_id in 0..2 {
+            device.write_block(block_id, &[0xff; BLOCK_SZ]);
+        }
+        let bitmap = Bitmap::new(0, 3);
+        assert_eq!(bitmap.alloc(&block_device), Some(2 * BLOCK_BITS));
+        assert_eq!(bitmap.alloc(&block_device), Some(2 * BLOCK_BITS + 1));
+        block_cache_sync_all();
+        assert_eq!(device.writes(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn alloc_fails_when_full() {
+        let _cache = exclusive_block_cache();
+        let device = MemDevice::new(1);
+        let block_device: Arc<dyn BlockDevice> = device.clone();
+        device.write_block(0, &[0xff; BLOCK_SZ]);
+        let bitmap = Bitmap::new(0, 1);
+        assert_eq!(bitmap.alloc(&block_device), None);
+        bitmap.dealloc(&block_device, 5);
+        assert_eq!(bitmap.alloc(&block_device), Some(5));
+    }
+}
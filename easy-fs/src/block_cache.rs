@@ -0,0 +1,338 @@
+//! Cache of the blocks of the device
+//!
+//! At most [`BLOCK_CACHE_SIZE`] blocks are kept in memory. A block is written
+//! back only when it is evicted or synced, and only if it was modified. When
+//! the cache is full, the least recently used block nobody holds is evicted.
+//!
+//! While a transaction runs, no block it modified may reach its home on the
+//! disk before the transaction commits through the journal. Such a block is
+//! then kept aside when evicted, and given back when loaded again.
+//!
+//! The cache is meant for a single device, as blocks are only told apart by
+//! their ids.
+
+use super::{BlockDevice, BLOCK_SZ};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::*;
+use spin::Mutex;
+
+/// The bytes of a block, aligned for any on-disk structure
+#[repr(C, align(8))]
+struct BlockData([u8; BLOCK_SZ]);
+
+/// A block in memory
+pub struct BlockCache {
+    /// cached block data
+    cache: BlockData,
+    /// underlying block id
+    block_id: usize,
+    /// underlying block device
+    block_device: Arc<dyn BlockDevice>,
+    /// whether the block is dirty
+    modified: bool,
+}
+
+impl BlockCache {
+    /// Load block `block_id` from `block_device`
+    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
+        let mut cache = BlockData([0u8; BLOCK_SZ]);
+        block_device.read_block(block_id, &mut cache.0);
+        Self {
+            cache,
+            block_id,
+            block_device,
+            modified: false,
+        }
+    }
+    /// A block holding `data` kept aside by a transaction, still dirty
+    fn with_data(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        data: [u8; BLOCK_SZ],
+    ) -> Self {
+        Self {
+            cache: BlockData(data),
+            block_id,
+            block_device,
+            modified: true,
+        }
+    }
+    /// Get the address of the byte at `offset` in the block
+    fn addr_of_offset(&self, offset: usize) -> usize {
+        &self.cache.0[offset] as *const _ as usize
+    }
+    /// Get the `T` at `offset` in the block
+    pub fn get_ref<T>(&self, offset: usize) -> &T
+    where
+        T: Sized,
+    {
+        let type_size = core::mem::size_of::<T>();
+        assert!(offset + type_size <= BLOCK_SZ);
+        let addr = self.addr_of_offset(offset);
+        assert_eq!(addr % core::mem::align_of::<T>(), 0);
+        unsafe { &*(addr as *const T) }
+    }
+    /// Get the `T` at `offset` in the block, marking the block dirty
+    pub fn get_mut<T>(&mut self, offset: usize) -> &mut T
+    where
+        T: Sized,
+    {
+        let type_size = core::mem::size_of::<T>();
+        assert!(offset + type_size <= BLOCK_SZ);
+        self.modified = true;
+        let addr = self.addr_of_offset(offset);
+        assert_eq!(addr % core::mem::align_of::<T>(), 0);
+        unsafe { &mut *(addr as *mut T) }
+    }
+    /// Run `f` on the `T` at `offset` in the block
+    pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
+        f(self.get_ref(offset))
+    }
+    /// Run `f` on the `T` at `offset` in the block, which it may modify
+    pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
+        f(self.get_mut(offset))
+    }
+    /// Write the block back if it is dirty
+    pub fn sync(&mut self) {
+        if self.modified {
+            self.modified = false;
+            self.block_device.write_block(self.block_id, &self.cache.0);
+        }
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        self.sync()
+    }
+}
+
+/// number of blocks kept in memory
+pub const BLOCK_CACHE_SIZE: usize = 16;
+
+/// The cached blocks, from the least to the most recently used
+struct BlockCacheManager {
+    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    /// dirty blocks evicted during the running transaction, if there is one
+    held: Option<BTreeMap<usize, [u8; BLOCK_SZ]>>,
+}
+
+impl BlockCacheManager {
+    fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            held: None,
+        }
+    }
+    fn get_block_cache(
+        &mut self,
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Arc<Mutex<BlockCache>> {
+        if let Some(idx) = self.queue.iter().position(|(id, _)| *id == block_id) {
+            // it becomes the most recently used
+            let pair = self.queue.remove(idx).unwrap();
+            let block_cache = pair.1.clone();
+            self.queue.push_back(pair);
+            return block_cache;
+        }
+        // substitute
+        if self.queue.len() == BLOCK_CACHE_SIZE {
+            // from front to tail
+            if let Some((idx, _)) = self
+                .queue
+                .iter()
+                .enumerate()
+                .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
+            {
+                // dropping it writes it back, unless it must wait for the
+                // transaction to commit
+                let (id, block_cache) = self.queue.remove(idx).unwrap();
+                if let Some(held) = self.held.as_mut() {
+                    let mut block_cache = block_cache.lock();
+                    if block_cache.modified {
+                        block_cache.modified = false;
+                        held.insert(id, block_cache.cache.0);
+                    }
+                }
+            } else {
+                panic!("Run out of BlockCache!");
+            }
+        }
+        // load block into mem and push back
+        let block_cache = match self.held.as_mut().and_then(|held| held.remove(&block_id)) {
+            Some(data) => BlockCache::with_data(block_id, block_device, data),
+            None => BlockCache::new(block_id, block_device),
+        };
+        let block_cache = Arc::new(Mutex::new(block_cache));
+        self.queue.push_back((block_id, block_cache.clone()));
+        block_cache
+    }
+    fn sync_all(&self) {
+        assert!(self.held.is_none(), "sync in a transaction");
+        for (_, cache) in self.queue.iter() {
+            cache.lock().sync();
+        }
+    }
+    fn begin_transaction(&mut self) {
+        assert!(self.held.is_none(), "nested transaction");
+        self.held = Some(BTreeMap::new());
+    }
+    fn end_transaction(&mut self) -> Vec<(usize, [u8; BLOCK_SZ])> {
+        let mut blocks: Vec<_> = self.held.take().unwrap().into_iter().collect();
+        for (id, cache) in self.queue.iter() {
+            let mut cache = cache.lock();
+            if cache.modified {
+                cache.modified = false;
+                blocks.push((*id, cache.cache.0));
+            }
+        }
+        blocks
+    }
+}
+
+lazy_static! {
+    /// The global block cache manager
+    static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
+        Mutex::new(BlockCacheManager::new());
+}
+
+/// Serializes the tests going through the global cache, which tells blocks
+/// apart by their ids only. Each of them starts with an empty cache, and
+/// without a transaction.
+#[cfg(test)]
+pub(crate) fn exclusive_block_cache() -> spin::MutexGuard<'static, ()> {
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+    let guard = TEST_LOCK.lock();
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    // the dirty blocks still go back to their own devices
+    manager.held = None;
+    manager.queue.clear();
+    guard
+}
+
+/// Get the cache of block `block_id` of `block_device`, loading it if needed
+pub fn get_block_cache(
+    block_id: usize,
+    block_device: Arc<dyn BlockDevice>,
+) -> Arc<Mutex<BlockCache>> {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .get_block_cache(block_id, block_device)
+}
+
+/// Write every dirty block back
+pub fn block_cache_sync_all() {
+    BLOCK_CACHE_MANAGER.lock().sync_all();
+}
+
+/// Start a transaction, keeping the blocks modified from now on off the disk
+pub(crate) fn begin_transaction() {
+    BLOCK_CACHE_MANAGER.lock().begin_transaction();
+}
+
+/// End the running transaction, returning the ids and the contents of the
+/// blocks it modified, which the caller has to write. Their caches are clean
+/// again.
+pub(crate) fn end_transaction() -> Vec<(usize, [u8; BLOCK_SZ])> {
+    BLOCK_CACHE_MANAGER.lock().end_transaction()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::mem::MemDevice;
+
+    fn cached_ids(manager: &BlockCacheManager) -> Vec<usize> {
+        manager.queue.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let device = MemDevice::new(2 * BLOCK_CACHE_SIZE);
+        let mut manager = BlockCacheManager::new();
+        for block_id in 0..BLOCK_CACHE_SIZE {
+            manager.get_block_cache(block_id, device.clone());
+        }
+        // block 0 becomes the most recently used, leaving block 1 the least
+        manager.get_block_cache(0, device.clone());
+        manager.get_block_cache(BLOCK_CACHE_SIZE, device.clone());
+        let ids = cached_ids(&manager);
+        assert_eq!(ids.len(), BLOCK_CACHE_SIZE);
+        assert!(!ids.contains(&1));
+        assert_eq!(ids[ids.len() - 2..], [0, BLOCK_CACHE_SIZE]);
+        manager.get_block_cache(BLOCK_CACHE_SIZE + 1, device.clone());
+        assert!(!cached_ids(&manager).contains(&2));
+    }
+
+    #[test]
+    fn skips_blocks_in_use() {
+        let device = MemDevice::new(2 * BLOCK_CACHE_SIZE);
+        let mut manager = BlockCacheManager::new();
+        let first = manager.get_block_cache(0, device.clone());
+        for block_id in 1..BLOCK_CACHE_SIZE {
+            manager.get_block_cache(block_id, device.clone());
+        }
+        manager.get_block_cache(BLOCK_CACHE_SIZE, device.clone());
+        let ids = cached_ids(&manager);
+        assert!(ids.contains(&0));
+        assert!(!ids.contains(&1));
+        drop(first);
+    }
+
+    #[test]
+    fn writes_back_only_dirty_blocks() {
+        let device = MemDevice::new(2 * BLOCK_CACHE_SIZE);
+        let mut manager = BlockCacheManager::new();
+        manager
+            .get_block_cache(0, device.clone())
+            .lock()
+            .modify(0, |value: &mut u64| *value = 0xdead_beef);
+        manager
+            .get_block_cache(1, device.clone())
+            .lock()
+            .read(0, |value: &u64| assert_eq!(*value, 0));
+        assert!(device.writes().is_empty());
+        // evict both blocks
+        for block_id in 2..BLOCK_CACHE_SIZE + 2 {
+            manager.get_block_cache(block_id, device.clone());
+        }
+        assert_eq!(device.writes(), [0]);
+        let mut buf = [0u8; BLOCK_SZ];
+        device.read_block(0, &mut buf);
+        assert_eq!(buf[..8], 0xdead_beef_u64.to_ne_bytes());
+        // loading it again reads what was written back
+        manager
+            .get_block_cache(0, device.clone())
+            .lock()
+            .read(0, |value: &u64| assert_eq!(*value, 0xdead_beef));
+    }
+
+    #[test]
+    fn syncs_dirty_blocks_once() {
+        let device = MemDevice::new(BLOCK_CACHE_SIZE);
+        let mut manager = BlockCacheManager::new();
+        manager
+            .get_block_cache(3, device.clone())
+            .lock()
+            .modify(8, |value: &mut u32| *value = 7);
+        manager.get_block_cache(4, device.clone());
+        manager.sync_all();
+        assert_eq!(device.writes(), [3]);
+        manager.sync_all();
+        assert_eq!(device.writes(), [3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Run out of BlockCache")]
+    fn panics_when_every_block_is_in_use() {
+        let device = MemDevice::new(2 * BLOCK_CACHE_SIZE);
+        let mut manager = BlockCacheManager::new();
+        let _held: Vec<_> = (0..BLOCK_CACHE_SIZE)
+            .map(|block_id| manager.get_block_cache(block_id, device.clone()))
+            .collect();
+        manager.get_block_cache(BLOCK_CACHE_SIZE, device.clone());
+    }
+}
@@ -0,0 +1,47 @@
+//! The device under the file system
+
+/// A device made of blocks of [`crate::BLOCK_SZ`] bytes
+pub trait BlockDevice: Send + Sync {
+    /// Read block `block_id` into `buf`
+    fn read_block(&self, block_id: usize, buf: &mut [u8]);
+    /// Write `buf` to block `block_id`
+    fn write_block(&self, block_id: usize, buf: &[u8]);
+}
+
+/// A device in memory for the tests
+#[cfg(test)]
+pub(crate) mod mem {
+    use super::BlockDevice;
+    use crate::BLOCK_SZ;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    /// A device in memory, logging the blocks written to it
+    pub(crate) struct MemDevice {
+        blocks: Mutex<Vec<[u8; BLOCK_SZ]>>,
+        writes: Mutex<Vec<usize>>,
+    }
+
+    impl MemDevice {
+        pub(crate) fn new(total_blocks: usize) -> Arc<Self> {
+            Arc::new(Self {
+                blocks: Mutex::new(alloc::vec![[0u8; BLOCK_SZ]; total_blocks]),
+                writes: Mutex::new(Vec::new()),
+            })
+        }
+        pub(crate) fn writes(&self) -> Vec<usize> {
+            self.writes.lock().clone()
+        }
+    }
+
+    impl BlockDevice for MemDevice {
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+            buf.copy_from_slice(&self.blocks.lock()[block_id]);
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) {
+            self.blocks.lock()[block_id].copy_from_slice(buf);
+            self.writes.lock().push(block_id);
+        }
+    }
+}
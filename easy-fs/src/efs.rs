@@ -0,0 +1,200 @@
+//! The file system as a whole, allocating inodes and data blocks
+
+use super::{
+    begin_transaction, block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode,
+    DiskInodeType, Inode, Journal, SuperBlock, JOURNAL_BLOCKS,
+};
+use crate::BLOCK_SZ;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+/// An easy file system on a block device
+pub struct EasyFileSystem {
+    /// Real device
+    pub block_device: Arc<dyn BlockDevice>,
+    /// Inode bitmap
+    pub inode_bitmap: Bitmap,
+    /// Data bitmap
+    pub data_bitmap: Bitmap,
+    /// Journal of the transactions
+    pub journal: Journal,
+    pub(crate) inode_area_start_block: u32,
+    pub(crate) data_area_start_block: u32,
+    /// number of blocks of the data area, which may be fewer than the data
+    /// bitmap has bits for
+    pub(crate) data_area_blocks: u32,
+}
+
+type DataBlock = [u8; BLOCK_SZ];
+
+impl EasyFileSystem {
+    /// Create a filesystem of `total_blocks` blocks on `block_device`, with
+    /// room for the inodes `inode_bitmap_blocks` blocks of bitmap can track,
+    /// and an empty root directory
+    pub fn create(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+    ) -> Arc<Mutex<Self>> {
+        // calculate block size of areas & create bitmaps
+        let inode_bitmap = Bitmap::new(1 + JOURNAL_BLOCKS as usize, inode_bitmap_blocks as usize);
+        let inode_num = inode_bitmap.maximum();
+        let inode_area_blocks =
+            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+        let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
+        let data_total_blocks = total_blocks - 1 - JOURNAL_BLOCKS - inode_total_blocks;
+        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
+        let data_area_blocks = data_total_blocks - data_bitmap_blocks;
+        let data_bitmap = Bitmap::new(
+            (1 + JOURNAL_BLOCKS + inode_total_blocks) as usize,
+            data_bitmap_blocks as usize,
+        );
+        let mut efs = Self {
+            block_device: Arc::clone(&block_device),
+            inode_bitmap,
+            data_bitmap,
+            journal: Journal::new(1, JOURNAL_BLOCKS as usize),
+            inode_area_start_block: 1 + JOURNAL_BLOCKS + inode_bitmap_blocks,
+            data_area_start_block: 1 + JOURNAL_BLOCKS + inode_total_blocks + data_bitmap_blocks,
+            data_area_blocks,
+        };
+        // clear all blocks
+        for i in 0..total_blocks {
+            get_block_cache(i as usize, Arc::clone(&block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    for byte in data_block.iter_mut() {
+                        *byte = 0;
+                    }
+                });
+        }
+        // initialize SuperBlock
+        get_block_cache(0, Arc::clone(&block_device)).lock().modify(
+            0,
+            |super_block: &mut SuperBlock| {
+                super_block.initialize(
+                    total_blocks,
+                    JOURNAL_BLOCKS,
+                    inode_bitmap_blocks,
+                    inode_area_blocks,
+                    data_bitmap_blocks,
+                    data_area_blocks,
+                );
+            },
+        );
+        // write back immediately
+        // create an inode for root node "/"
+        assert_eq!(efs.alloc_inode(), Some(0));
+        let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
+        get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))
+            .lock()
+            .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.initialize(DiskInodeType::Directory);
+            });
+        block_cache_sync_all();
+        Arc::new(Mutex::new(efs))
+    }
+    /// Open the filesystem on `block_device`, or None if there is none. The
+    /// transaction left committed in the journal by a crash is replayed.
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Option<Arc<Mutex<Self>>> {
+        let efs = Self::open_unreplayed(block_device)?;
+        efs.journal.replay(&efs.block_device);
+        Some(Arc::new(Mutex::new(efs)))
+    }
+    /// Open the filesystem on `block_device`, leaving the journal as it is
+    pub(crate) fn open_unreplayed(block_device: Arc<dyn BlockDevice>) -> Option<Self> {
+        // read SuperBlock
+        get_block_cache(0, Arc::clone(&block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                if !super_block.is_valid() {
+                    return None;
+                }
+                let inode_bitmap_start_block = 1 + super_block.journal_blocks;
+                let inode_total_blocks =
+                    super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
+                Some(Self {
+                    block_device,
+                    inode_bitmap: Bitmap::new(
+                        inode_bitmap_start_block as usize,
+                        super_block.inode_bitmap_blocks as usize,
+                    ),
+                    data_bitmap: Bitmap::new(
+                        (inode_bitmap_start_block + inode_total_blocks) as usize,
+                        super_block.data_bitmap_blocks as usize,
+                    ),
+                    journal: Journal::new(1, super_block.journal_blocks as usize),
+                    inode_area_start_block: inode_bitmap_start_block
+                        + super_block.inode_bitmap_blocks,
+                    data_area_start_block: inode_bitmap_start_block
+                        + inode_total_blocks
+                        + super_block.data_bitmap_blocks,
+                    data_area_blocks: super_block.data_area_blocks,
+                })
+            })
+    }
+    /// Get the root inode of the filesystem
+    pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
+        let block_device = Arc::clone(&efs.lock().block_device);
+        // acquire efs lock temporarily
+        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
+        // release efs lock
+        Inode::new(0, block_id, block_offset, Arc::clone(efs), block_device)
+    }
+    /// Start a transaction, which has to be committed before the file system
+    /// is unlocked
+    pub fn begin(&mut self) {
+        begin_transaction();
+    }
+    /// Commit the running transaction through the journal
+    pub fn commit(&mut self) {
+        self.journal.commit(&self.block_device);
+    }
+    /// Get inode by id
+    pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
+        (
+            block_id,
+            (inode_id % inodes_per_block) as usize * inode_size,
+        )
+    }
+    /// Get data block by id
+    pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
+        self.data_area_start_block + data_block_id
+    }
+    /// Allocate a new inode, or None if there is no room left
+    pub fn alloc_inode(&mut self) -> Option<u32> {
+        self.inode_bitmap
+            .alloc(&self.block_device)
+            .map(|inode_id| inode_id as u32)
+    }
+    /// Allocate a cleared data block, returning its block id, or None if the
+    /// disk is full
+    pub fn alloc_data(&mut self) -> Option<u32> {
+        let data_block_id = self.data_bitmap.alloc(&self.block_device)?;
+        if data_block_id >= self.data_area_blocks as usize {
+            // the last bitmap block has bits past the end of the device
+            self.data_bitmap.dealloc(&self.block_device, data_block_id);
+            return None;
+        }
+        let block_id = self.get_data_block_id(data_block_id as u32);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |data_block: &mut DataBlock| {
+                data_block.iter_mut().for_each(|p| {
+                    *p = 0;
+                })
+            });
+        Some(block_id)
+    }
+    /// Deallocate a data block, whose contents stay until it is allocated
+    /// again
+    pub fn dealloc_data(&mut self, block_id: u32) {
+        self.data_bitmap.dealloc(
+            &self.block_device,
+            (block_id - self.data_area_start_block) as usize,
+        )
+    }
+}
@@ -0,0 +1,527 @@
+//! Checking, and repairing, a file system
+//!
+//! The check walks the directory tree from the root, claiming the blocks of
+//! every inode it reaches, and then compares what it reached with the link
+//! counts of the inodes and with the bitmaps. Repairing removes the entries it
+//! cannot follow, truncates the inodes whose blocks are invalid or belong to
+//! another inode, and sets the link counts and the bitmaps to what the tree
+//! says, which frees whatever is left unreachable.
+
+use super::layout::{
+    IndirectBlock, INDIRECT1_BOUND, INDIRECT2_BOUND, INODE_DIRECT_COUNT, INODE_INDIRECT1_COUNT,
+};
+use super::{
+    block_cache_sync_all, get_block_cache, BlockDevice, DirEntry, DiskInode, EasyFileSystem,
+    SuperBlock, BLOCK_SZ, DIRENT_SZ, NAME_LENGTH_LIMIT,
+};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Number of bits in a bitmap block
+const BLOCK_BITS: u64 = BLOCK_SZ as u64 * 8;
+
+/// What [`fsck()`] found
+pub struct FsckReport {
+    /// whether a transaction committed in the journal was replayed
+    pub replayed: bool,
+    /// whether a transaction committed in the journal was left to replay, by
+    /// a check without repair
+    pub pending: bool,
+    /// the inconsistencies found, which are repaired if asked to
+    pub problems: Vec<String>,
+}
+
+impl FsckReport {
+    /// Whether the file system was consistent
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check the file system on `block_device`, repairing it if `repair` is set.
+/// Returns None if there is no file system there.
+///
+/// A repair first replays the transaction left committed in the journal, if
+/// any, as opening the file system does. A check alone never writes, so it
+/// only reports such a transaction, and then checks the blocks as they are
+/// before the replay, which may find problems the replay fixes. The file
+/// system must not be open, and the block cache must not hold blocks of
+/// another device.
+pub fn fsck(block_device: Arc<dyn BlockDevice>, repair: bool) -> Option<FsckReport> {
+    let efs = EasyFileSystem::open_unreplayed(Arc::clone(&block_device))?;
+    let (replayed, pending) = if repair {
+        (efs.journal.replay(&block_device), false)
+    } else {
+        (false, efs.journal.is_pending(&block_device))
+    };
+    let mut checker = Checker::new(efs, repair);
+    checker.check();
+    if repair {
+        block_cache_sync_all();
+    }
+    Some(FsckReport {
+        replayed,
+        pending,
+        problems: checker.problems,
+    })
+}
+
+/// State of a check
+struct Checker {
+    efs: EasyFileSystem,
+    repair: bool,
+    problems: Vec<String>,
+    /// number of entries naming each inode, for the inodes reached
+    links: Vec<Option<u32>>,
+    /// whether each block of the data area belongs to an inode reached
+    claimed: Vec<bool>,
+    /// directories reached whose entries are still to check
+    dirs: Vec<u32>,
+}
+
+impl Checker {
+    fn new(efs: EasyFileSystem, repair: bool) -> Self {
+        Self {
+            efs,
+            repair,
+            problems: Vec::new(),
+            links: Vec::new(),
+            claimed: Vec::new(),
+            dirs: Vec::new(),
+        }
+    }
+    fn check(&mut self) {
+        if !self.check_super_block() {
+            return;
+        }
+        self.links = vec![None; self.efs.inode_bitmap.maximum()];
+        self.claimed = vec![false; self.efs.data_area_blocks as usize];
+        if !self.read_inode(0, |disk_inode| disk_inode.is_dir()) {
+            self.problems
+                .push(String::from("inode 0: the root is not a directory"));
+            return;
+        }
+        self.links[0] = Some(1);
+        if self.check_blocks(0) {
+            self.dirs.push(0);
+        }
+        while let Some(inode_id) = self.dirs.pop() {
+            self.check_dir(inode_id);
+        }
+        self.check_links();
+        self.check_inode_bitmap();
+        self.check_data_bitmap();
+    }
+    /// Whether the areas of the super block fit together, which the rest of
+    /// the check relies on
+    fn check_super_block(&mut self) -> bool {
+        let problem = get_block_cache(0, Arc::clone(&self.efs.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                let inodes_per_block = (BLOCK_SZ / core::mem::size_of::<DiskInode>()) as u64;
+                let areas = 1
+                    + super_block.journal_blocks as u64
+                    + super_block.inode_bitmap_blocks as u64
+                    + super_block.inode_area_blocks as u64
+                    + super_block.data_bitmap_blocks as u64
+                    + super_block.data_area_blocks as u64;
+                if areas != super_block.total_blocks as u64 {
+                    Some("the areas do not add up to the size of the file system")
+                } else if super_block.journal_blocks < 2 {
+                    Some("the journal is too small")
+                } else if super_block.inode_area_blocks as u64 * inodes_per_block
+                    < super_block.inode_bitmap_blocks as u64 * BLOCK_BITS
+                {
+                    Some("the inode area is too small for the inode bitmap")
+                } else if (super_block.data_bitmap_blocks as u64 * BLOCK_BITS)
+                    < super_block.data_area_blocks as u64
+                {
+                    Some("the data bitmap is too small for the data area")
+                } else {
+                    None
+                }
+            });
+        if let Some(problem) = problem {
+            self.problems.push(format!("super block: {}", problem));
+        }
+        problem.is_none()
+    }
+    /// Run `f` on the disk inode `inode_id`
+    fn read_inode<V>(&self, inode_id: u32, f: impl FnOnce(&DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.efs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device))
+            .lock()
+            .read(block_offset, f)
+    }
+    /// Run `f` on the disk inode `inode_id`, which it may modify
+    fn modify_inode<V>(&self, inode_id: u32, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.efs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device))
+            .lock()
+            .modify(block_offset, f)
+    }
+    /// `block_id` if it is in the data area
+    fn data_block(&self, block_id: u32) -> Result<u32, String> {
+        match block_id.checked_sub(self.efs.data_area_start_block) {
+            Some(data_block_id) if data_block_id < self.efs.data_area_blocks => Ok(block_id),
+            _ => Err(format!("block {} is out of the data area", block_id)),
+        }
+    }
+    /// The first `n` block ids held by block `block_id`, which must all be
+    /// in the data area
+    fn block_ids(&self, block_id: u32, n: usize) -> Result<Vec<u32>, String> {
+        let block_id = self.data_block(block_id)?;
+        let ids = get_block_cache(block_id as usize, Arc::clone(&self.efs.block_device))
+            .lock()
+            .read(0, |indirect_block: &IndirectBlock| {
+                indirect_block[..n].to_vec()
+            });
+        for id in ids.iter() {
+            self.data_block(*id)?;
+        }
+        Ok(ids)
+    }
+    /// All the blocks of a disk inode, data and indirect ones
+    fn blocks_of(&self, disk_inode: &DiskInode) -> Result<Vec<u32>, String> {
+        let data_blocks = (disk_inode.size as usize + BLOCK_SZ - 1) / BLOCK_SZ;
+        if data_blocks > INDIRECT2_BOUND {
+            return Err(format!("size {} is too large", disk_inode.size));
+        }
+        let mut blocks = Vec::new();
+        for block_id in disk_inode.direct[..data_blocks.min(INODE_DIRECT_COUNT)].iter() {
+            blocks.push(self.data_block(*block_id)?);
+        }
+        if data_blocks > INODE_DIRECT_COUNT {
+            let n = (data_blocks - INODE_DIRECT_COUNT).min(INODE_INDIRECT1_COUNT);
+            blocks.push(disk_inode.indirect1);
+            blocks.extend(self.block_ids(disk_inode.indirect1, n)?);
+        }
+        if data_blocks > INDIRECT1_BOUND {
+            let left = data_blocks - INDIRECT1_BOUND;
+            let n = (left + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+            blocks.push(disk_inode.indirect2);
+            let indirect1s = self.block_ids(disk_inode.indirect2, n)?;
+            blocks.extend(indirect1s.iter());
+            for (i, indirect1) in indirect1s.into_iter().enumerate() {
+                let n = (left - i * INODE_INDIRECT1_COUNT).min(INODE_INDIRECT1_COUNT);
+                blocks.extend(self.block_ids(indirect1, n)?);
+            }
+        }
+        Ok(blocks)
+    }
+    /// Claim the blocks of inode `inode_id`, just reached, truncating it if
+    /// they are not all valid and its own. Returns whether its data can be
+    /// read.
+    fn check_blocks(&mut self, inode_id: u32) -> bool {
+        let (is_dir, size) = self.read_inode(inode_id, |disk_inode| {
+            (disk_inode.is_dir(), disk_inode.size as usize)
+        });
+        if is_dir && size % DIRENT_SZ != 0 {
+            self.problems.push(format!(
+                "inode {}: a directory of {} bytes, not a number of entries",
+                inode_id, size
+            ));
+            if !self.repair {
+                return false;
+            }
+            self.modify_inode(inode_id, |disk_inode| {
+                disk_inode.size -= (size % DIRENT_SZ) as u32;
+            });
+        }
+        let error = match self.read_inode(inode_id, |disk_inode| self.blocks_of(disk_inode)) {
+            Ok(blocks) => {
+                let mut sorted = blocks.clone();
+                sorted.sort_unstable();
+                sorted.dedup();
+                let start = self.efs.data_area_start_block;
+                if sorted.len() < blocks.len() {
+                    Some(String::from("a block is used twice"))
+                } else if let Some(block_id) = blocks
+                    .iter()
+                    .find(|block_id| self.claimed[(**block_id - start) as usize])
+                {
+                    Some(format!("block {} belongs to another inode", block_id))
+                } else {
+                    for block_id in blocks.iter() {
+                        self.claimed[(*block_id - start) as usize] = true;
+                    }
+                    None
+                }
+            }
+            Err(error) => Some(error),
+        };
+        let Some(error) = error else {
+            return true;
+        };
+        self.problems.push(format!("inode {}: {}", inode_id, error));
+        if self.repair {
+            self.modify_inode(inode_id, |disk_inode| {
+                disk_inode.size = 0;
+                disk_inode.direct.iter_mut().for_each(|v| *v = 0);
+                disk_inode.indirect1 = 0;
+                disk_inode.indirect2 = 0;
+            });
+        }
+        false
+    }
+    /// Check the entries of directory `inode_id`, following them
+    fn check_dir(&mut self, inode_id: u32) {
+        let dirents = self.read_inode(inode_id, |disk_inode| {
+            let mut dirents = Vec::new();
+            for i in 0..disk_inode.size as usize / DIRENT_SZ {
+                let mut dirent = DirEntry::empty();
+                disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.efs.block_device);
+                dirents.push(dirent);
+            }
+            dirents
+        });
+        for (i, dirent) in dirents.iter().enumerate() {
+            if dirent.is_free() {
+                continue;
+            }
+            let Err(error) = self.check_dirent(dirent) else {
+                continue;
+            };
+            self.problems
+                .push(format!("inode {}: entry {}: {}", inode_id, i, error));
+            if self.repair {
+                self.modify_inode(inode_id, |disk_inode| {
+                    let dirent = DirEntry::empty();
+                    disk_inode.write_at(i * DIRENT_SZ, dirent.as_bytes(), &self.efs.block_device);
+                });
+            }
+        }
+    }
+    /// Check an entry, and count it as a link to the inode it names
+    fn check_dirent(&mut self, dirent: &DirEntry) -> Result<(), String> {
+        let name = dirent.name_bytes();
+        if name.len() > NAME_LENGTH_LIMIT {
+            return Err(String::from("the name is not terminated"));
+        }
+        if core::str::from_utf8(name).is_err() || name.contains(&b'/') {
+            return Err(String::from("the name is invalid"));
+        }
+        let target = dirent.inode_number();
+        if target as usize >= self.links.len() {
+            return Err(format!("inode {} does not exist", target));
+        }
+        let (is_dir, is_file) = self.read_inode(target, |disk_inode| {
+            (disk_inode.is_dir(), disk_inode.is_file())
+        });
+        if !is_dir && !is_file {
+            return Err(format!("inode {} is of no known type", target));
+        }
+        match self.links[target as usize].as_mut() {
+            Some(_) if is_dir => Err(format!("directory {} is named already", target)),
+            Some(links) => {
+                *links += 1;
+                Ok(())
+            }
+            None => {
+                self.links[target as usize] = Some(1);
+                if self.check_blocks(target) && is_dir {
+                    self.dirs.push(target);
+                }
+                Ok(())
+            }
+        }
+    }
+    /// Check that the link count of every inode reached is the number of
+    /// entries naming it
+    fn check_links(&mut self) {
+        for inode_id in 0..self.links.len() as u32 {
+            let Some(links) = self.links[inode_id as usize] else {
+                continue;
+            };
+            let nlink = self.read_inode(inode_id, |disk_inode| disk_inode.nlink);
+            if nlink == links {
+                continue;
+            }
+            self.problems.push(format!(
+                "inode {}: {} links counted, but {} found",
+                inode_id, nlink, links
+            ));
+            if self.repair {
+                self.modify_inode(inode_id, |disk_inode| disk_inode.nlink = links);
+            }
+        }
+    }
+    /// Check that exactly the inodes reached are marked in use
+    fn check_inode_bitmap(&mut self) {
+        let block_device = Arc::clone(&self.efs.block_device);
+        let (mut unreachable, mut unmarked) = (0, 0);
+        for inode_id in 0..self.links.len() {
+            let used = self.links[inode_id].is_some();
+            if self.efs.inode_bitmap.get(&block_device, inode_id) == used {
+                continue;
+            }
+            if used {
+                unmarked += 1;
+            } else {
+                unreachable += 1;
+            }
+            if self.repair {
+                self.efs.inode_bitmap.set(&block_device, inode_id, used);
+            }
+        }
+        if unreachable > 0 {
+            self.problems.push(format!(
+                "inode bitmap: {} inodes marked in use are unreachable",
+                unreachable
+            ));
+        }
+        if unmarked > 0 {
+            self.problems.push(format!(
+                "inode bitmap: {} inodes in use are marked free",
+                unmarked
+            ));
+        }
+    }
+    /// Check that exactly the data blocks claimed are marked in use
+    fn check_data_bitmap(&mut self) {
+        let block_device = Arc::clone(&self.efs.block_device);
+        let (mut unclaimed, mut unmarked) = (0, 0);
+        // the bits past the data area must be clear too
+        for data_block_id in 0..self.efs.data_bitmap.maximum() {
+            let used = self.claimed.get(data_block_id).copied().unwrap_or(false);
+            if self.efs.data_bitmap.get(&block_device, data_block_id) == used {
+                continue;
+            }
+            if used {
+                unmarked += 1;
+            } else {
+                unclaimed += 1;
+            }
+            if self.repair {
+                self.efs.data_bitmap.set(&block_device, data_block_id, used);
+            }
+        }
+        if unclaimed > 0 {
+            self.problems.push(format!(
+                "data bitmap: {} blocks marked in use belong to no inode",
+                unclaimed
+            ));
+        }
+        if unmarked > 0 {
+            self.problems.push(format!(
+                "data bitmap: {} blocks in use are marked free",
+                unmarked
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::exclusive_block_cache;
+    use crate::block_dev::mem::MemDevice;
+    use spin::Mutex;
+
+    const TOTAL_BLOCKS: u32 = 2048;
+
+    /// A file system holding the file "a" with some data and the directory
+    /// "d", which is unmounted when `f` returns
+    fn new_fs(device: &Arc<MemDevice>, f: impl FnOnce(&Arc<Mutex<EasyFileSystem>>)) {
+        let efs = EasyFileSystem::create(device.clone(), TOTAL_BLOCKS, 1);
+        let root = EasyFileSystem::root_inode(&efs);
+        let a = root.create("a").unwrap();
+        a.write_at(0, &[1; 3 * BLOCK_SZ]);
+        root.create_dir("d").unwrap();
+        f(&efs);
+        block_cache_sync_all();
+    }
+
+    /// Repair the file system on `device`, which a second pass must find
+    /// clean, and return the problems repaired
+    fn repair(device: &Arc<MemDevice>) -> Vec<String> {
+        let report = fsck(device.clone(), true).unwrap();
+        assert!(fsck(device.clone(), false).unwrap().is_clean());
+        report.problems
+    }
+
+    #[test]
+    fn finds_a_new_file_system_clean() {
+        let _cache = exclusive_block_cache();
+        let device = MemDevice::new(TOTAL_BLOCKS as usize);
+        new_fs(&device, |_| {});
+        let writes = device.writes();
+        let report = fsck(device.clone(), false).unwrap();
+        assert!(report.is_clean());
+        assert!(!report.replayed && !report.pending);
+        assert_eq!(device.writes(), writes);
+    }
+
+    #[test]
+    fn repairs_a_leaked_data_block() {
+        let _cache = exclusive_block_cache();
+        let device = MemDevice::new(TOTAL_BLOCKS as usize);
+        new_fs(&device, |efs| {
+            efs.lock().alloc_data().unwrap();
+        });
+        let writes = device.writes();
+        let report = fsck(device.clone(), false).unwrap();
+        assert_eq!(
+            report.problems,
+            ["data bitmap: 1 blocks marked in use belong to no inode"]
+        );
+        // nothing is written without repair
+        assert_eq!(device.writes(), writes);
+        assert_eq!(repair(&device), report.problems);
+    }
+
+    #[test]
+    fn repairs_a_wrong_link_count() {
+        let _cache = exclusive_block_cache();
+        let device = MemDevice::new(TOTAL_BLOCKS as usize);
+        new_fs(&device, |efs| {
+            let (block_id, block_offset) = efs.lock().get_disk_inode_pos(1);
+            get_block_cache(block_id as usize, device.clone())
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    disk_inode.nlink = 3
+                });
+        });
+        let problems = repair(&device);
+        assert_eq!(problems, ["inode 1: 3 links counted, but 1 found"]);
+        let efs = EasyFileSystem::open(device.clone()).unwrap();
+        assert_eq!(
+            EasyFileSystem::root_inode(&efs).find("a").unwrap().nlink(),
+            1
+        );
+    }
+
+    #[test]
+    fn leaves_a_committed_transaction_to_the_repair() {
+        let _cache = exclusive_block_cache();
+        let device = MemDevice::new(TOTAL_BLOCKS as usize);
+        let block_device: Arc<dyn BlockDevice> = device.clone();
+        new_fs(&device, |_| {});
+        // a crash right after the commit point of a transaction fixing the
+        // link count of "a", before the inode reached its home
+        let efs = EasyFileSystem::open_unreplayed(Arc::clone(&block_device)).unwrap();
+        let (block_id, block_offset) = efs.get_disk_inode_pos(1);
+        let mut fixed = [0u8; BLOCK_SZ];
+        device.read_block(block_id as usize, &mut fixed);
+        get_block_cache(block_id as usize, Arc::clone(&block_device))
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.nlink = 3
+            });
+        block_cache_sync_all();
+        efs.journal
+            .write_committed(&block_device, &[(block_id as usize, fixed)]);
+        let writes = device.writes();
+        let report = fsck(Arc::clone(&block_device), false).unwrap();
+        assert!(report.pending && !report.replayed);
+        assert_eq!(report.problems, ["inode 1: 3 links counted, but 1 found"]);
+        assert_eq!(device.writes(), writes);
+        let report = fsck(Arc::clone(&block_device), true).unwrap();
+        assert!(report.replayed && !report.pending);
+        assert!(report.is_clean());
+        assert!(!fsck(block_device, false).unwrap().pending);
+    }
+}
@@ -0,0 +1,213 @@
+//! Write-ahead journal of the operations changing the file system
+//!
+//! Every such operation runs as a transaction, whose modified blocks are kept
+//! in memory by the block cache until it commits. Committing writes copies of
+//! them to the journal, then the header naming them, which is the commit
+//! point as a single block write, then the blocks to their homes, and clears
+//! the header at last. After a crash, [`Journal::replay`] writes the blocks of
+//! a committed transaction home again, so that every transaction is either
+//! entirely on the disk or not at all.
+
+use super::{end_transaction, get_block_cache, BlockDevice, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Magic number of a committed header
+const JOURNAL_MAGIC: u32 = 0x6a726e6c;
+/// The max number of blocks of a transaction, as many as the ids a header
+/// holds after its magic, count and checksum
+pub const JOURNAL_CAPACITY: usize = BLOCK_SZ / 4 - 3;
+/// number of blocks of the journal: the header, then the copies
+pub const JOURNAL_BLOCKS: u32 = 1 + JOURNAL_CAPACITY as u32;
+
+/// A block of the journal
+type DataBlock = [u8; BLOCK_SZ];
+
+/// The journal, spanning `blocks` blocks from `start_block_id`
+pub struct Journal {
+    start_block_id: usize,
+    blocks: usize,
+}
+
+/// FNV-1a hash of the ids and contents of the blocks of a transaction
+fn checksum(blocks: &[(usize, DataBlock)]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for (block_id, data) in blocks.iter() {
+        for byte in (*block_id as u32).to_le_bytes().iter().chain(data.iter()) {
+            hash ^= *byte as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+    }
+    hash
+}
+
+/// Get the `i`th 32-bit word of a block
+fn word(block: &DataBlock, i: usize) -> u32 {
+    u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap())
+}
+
+/// Set the `i`th 32-bit word of a block
+fn set_word(block: &mut DataBlock, i: usize, value: u32) {
+    block[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl Journal {
+    /// A journal from start block id and number of blocks
+    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+        Self {
+            start_block_id,
+            blocks,
+        }
+    }
+    /// The max number of blocks of a transaction
+    fn capacity(&self) -> usize {
+        JOURNAL_CAPACITY.min(self.blocks.saturating_sub(1))
+    }
+    /// Commit the running transaction of the block cache
+    pub fn commit(&self, block_device: &Arc<dyn BlockDevice>) {
+        let blocks = end_transaction();
+        if blocks.is_empty() {
+            return;
+        }
+        // from then on, the transaction survives a crash
+        self.write_committed(block_device, &blocks);
+        for (block_id, data) in blocks.iter() {
+            block_device.write_block(*block_id, data);
+        }
+        block_device.write_block(self.start_block_id, &[0u8; BLOCK_SZ]);
+    }
+    /// Write `blocks` to the journal as a committed transaction, the header
+    /// naming them last
+    pub(crate) fn write_committed(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        blocks: &[(usize, DataBlock)],
+    ) {
+        assert!(
+            blocks.len() <= self.capacity(),
+            "transaction of {} blocks does not fit in the journal",
+            blocks.len()
+        );
+        for (i, (_, data)) in blocks.iter().enumerate() {
+            block_device.write_block(self.start_block_id + 1 + i, data);
+        }
+        let mut header = [0u8; BLOCK_SZ];
+        set_word(&mut header, 0, JOURNAL_MAGIC);
+        set_word(&mut header, 1, blocks.len() as u32);
+        set_word(&mut header, 2, checksum(blocks));
+        for (i, (block_id, _)) in blocks.iter().enumerate() {
+            set_word(&mut header, 3 + i, *block_id as u32);
+        }
+        block_device.write_block(self.start_block_id, &header);
+    }
+    /// The blocks of the transaction committed in the journal, if there is
+    /// one
+    fn committed(&self, block_device: &Arc<dyn BlockDevice>) -> Option<Vec<(usize, DataBlock)>> {
+        let mut header = [0u8; BLOCK_SZ];
+        block_device.read_block(self.start_block_id, &mut header);
+        let count = word(&header, 1) as usize;
+        if word(&header, 0) != JOURNAL_MAGIC || count == 0 || count > self.capacity() {
+            return None;
+        }
+        let mut blocks = vec![(0usize, [0u8; BLOCK_SZ]); count];
+        for (i, (block_id, data)) in blocks.iter_mut().enumerate() {
+            *block_id = word(&header, 3 + i) as usize;
+            block_device.read_block(self.start_block_id + 1 + i, data);
+        }
+        // otherwise not a header written by `commit`
+        (checksum(&blocks) == word(&header, 2)).then_some(blocks)
+    }
+    /// Whether a transaction committed in the journal waits to be replayed,
+    /// which is only read
+    pub fn is_pending(&self, block_device: &Arc<dyn BlockDevice>) -> bool {
+        self.committed(block_device).is_some()
+    }
+    /// Write the blocks of the transaction committed in the journal, if there
+    /// is one, to their homes, and clear the journal. Returns whether there
+    /// was one.
+    ///
+    /// The blocks go through the block cache, which must not be in a
+    /// transaction.
+    pub fn replay(&self, block_device: &Arc<dyn BlockDevice>) -> bool {
+        let Some(blocks) = self.committed(block_device) else {
+            return false;
+        };
+        for (block_id, data) in blocks.into_iter() {
+            let block_cache = get_block_cache(block_id, Arc::clone(block_device));
+            let mut block_cache = block_cache.lock();
+            block_cache.modify(0, |block: &mut DataBlock| *block = data);
+            block_cache.sync();
+        }
+        block_device.write_block(self.start_block_id, &[0u8; BLOCK_SZ]);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::exclusive_block_cache;
+    use crate::block_dev::mem::MemDevice;
+    use crate::begin_transaction;
+
+    const TOTAL_BLOCKS: usize = 16;
+
+    /// A journal of blocks 1 to 4, holding transactions of up to 3 blocks
+    fn new_journal() -> Journal {
+        Journal::new(1, 4)
+    }
+
+    fn block(block_device: &Arc<dyn BlockDevice>, block_id: usize) -> DataBlock {
+        let mut data = [0u8; BLOCK_SZ];
+        block_device.read_block(block_id, &mut data);
+        data
+    }
+
+    #[test]
+    fn commit_writes_the_blocks_home() {
+        let _cache = exclusive_block_cache();
+        let block_device: Arc<dyn BlockDevice> = MemDevice::new(TOTAL_BLOCKS);
+        let journal = new_journal();
+        begin_transaction();
+        get_block_cache(10, Arc::clone(&block_device))
+            .lock()
+            .modify(0, |data: &mut DataBlock| *data = [7; BLOCK_SZ]);
+        journal.commit(&block_device);
+        assert_eq!(block(&block_device, 10), [7; BLOCK_SZ]);
+        assert!(!journal.is_pending(&block_device));
+    }
+
+    #[test]
+    fn replays_a_committed_transaction() {
+        let _cache = exclusive_block_cache();
+        let device = MemDevice::new(TOTAL_BLOCKS);
+        let block_device: Arc<dyn BlockDevice> = device.clone();
+        let journal = new_journal();
+        journal.write_committed(&block_device, &[(10, [7; BLOCK_SZ]), (12, [9; BLOCK_SZ])]);
+        assert!(journal.is_pending(&block_device));
+        assert_eq!(device.writes(), [2, 3, 1]);
+        assert!(journal.replay(&block_device));
+        assert_eq!(block(&block_device, 10), [7; BLOCK_SZ]);
+        assert_eq!(block(&block_device, 12), [9; BLOCK_SZ]);
+        // the journal is cleared, so nothing is left to replay
+        assert!(!journal.is_pending(&block_device));
+        assert!(!journal.replay(&block_device));
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let _cache = exclusive_block_cache();
+        let device = MemDevice::new(TOTAL_BLOCKS);
+        let block_device: Arc<dyn BlockDevice> = device.clone();
+        let journal = new_journal();
+        journal.write_committed(&block_device, &[(10, [7; BLOCK_SZ]), (12, [9; BLOCK_SZ])]);
+        // a copy garbled after the commit
+        block_device.write_block(3, &[0; BLOCK_SZ]);
+        let writes = device.writes();
+        assert!(!journal.is_pending(&block_device));
+        assert!(!journal.replay(&block_device));
+        assert_eq!(device.writes(), writes);
+        assert_eq!(block(&block_device, 10), [0; BLOCK_SZ]);
+    }
+}
@@ -0,0 +1,581 @@
+//! On-disk structures
+//!
+//! The device is laid out as the super block, the journal, the inode bitmap,
+//! the inode area, the data bitmap, and the data area, in that order. An inode
+//! locates the data of its file with direct block ids, then through an
+//! indirect block, then through a double indirect one.
+
+use super::{get_block_cache, BlockDevice, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter, Result};
+
+/// Magic number for sanity check
+const EFS_MAGIC: u32 = 0x3b800002;
+/// The max number of direct inodes
+pub(crate) const INODE_DIRECT_COUNT: usize = 27;
+/// The max length of inode name
+pub const NAME_LENGTH_LIMIT: usize = 27;
+/// The max number of indirect1 inodes
+pub(crate) const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
+/// The max number of indirect2 inodes
+const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+/// The upper bound of direct inode index
+const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
+/// The upper bound of indirect1 inode index
+pub(crate) const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
+/// The upper bound of indirect2 inode index
+pub(crate) const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+
+/// Super block of a filesystem
+#[repr(C)]
+pub struct SuperBlock {
+    magic: u32,
+    /// total number of blocks of the filesystem
+    pub total_blocks: u32,
+    /// number of blocks of the journal
+    pub journal_blocks: u32,
+    /// number of blocks of the inode bitmap
+    pub inode_bitmap_blocks: u32,
+    /// number of blocks of the inode area
+    pub inode_area_blocks: u32,
+    /// number of blocks of the data bitmap
+    pub data_bitmap_blocks: u32,
+    /// number of blocks of the data area
+    pub data_area_blocks: u32,
+}
+
+impl Debug for SuperBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("SuperBlock")
+            .field("total_blocks", &self.total_blocks)
+            .field("journal_blocks", &self.journal_blocks)
+            .field("inode_bitmap_blocks", &self.inode_bitmap_blocks)
+            .field("inode_area_blocks", &self.inode_area_blocks)
+            .field("data_bitmap_blocks", &self.data_bitmap_blocks)
+            .field("data_area_blocks", &self.data_area_blocks)
+            .finish()
+    }
+}
+
+impl SuperBlock {
+    /// Initialize a super block
+    pub fn initialize(
+        &mut self,
+        total_blocks: u32,
+        journal_blocks: u32,
+        inode_bitmap_blocks: u32,
+        inode_area_blocks: u32,
+        data_bitmap_blocks: u32,
+        data_area_blocks: u32,
+    ) {
+        *self = Self {
+            magic: EFS_MAGIC,
+            total_blocks,
+            journal_blocks,
+            inode_bitmap_blocks,
+            inode_area_blocks,
+            data_bitmap_blocks,
+            data_area_blocks,
+        }
+    }
+    /// Check if a super block is valid using efs magic
+    pub fn is_valid(&self) -> bool {
+        self.magic == EFS_MAGIC
+    }
+}
+
+/// Type of a disk inode
+#[derive(PartialEq, Clone, Copy)]
+#[repr(u32)]
+pub enum DiskInodeType {
+    /// a regular file
+    File,
+    /// a directory, whose data is made of [`DirEntry`]s
+    Directory,
+}
+
+/// A indirect block
+pub(crate) type IndirectBlock = [u32; BLOCK_SZ / 4];
+/// A data block
+type DataBlock = [u8; BLOCK_SZ];
+
+/// A disk inode
+#[repr(C)]
+pub struct DiskInode {
+    /// size of the file in bytes
+    pub size: u32,
+    /// ids of the first data blocks
+    pub direct: [u32; INODE_DIRECT_COUNT],
+    /// id of the block holding the ids of the next data blocks
+    pub indirect1: u32,
+    /// id of the block holding the ids of indirect blocks of the last ones
+    pub indirect2: u32,
+    /// number of directory entries naming the inode
+    pub nlink: u32,
+    /// a [`DiskInodeType`], kept as a number as the disk may hold anything
+    type_: u32,
+}
+
+impl DiskInode {
+    /// Initialize a disk inode, as well as all direct inodes under it
+    /// indirect1 and indirect2 block are allocated only when they are needed.
+    /// It starts with a link, for the entry about to name it.
+    pub fn initialize(&mut self, type_: DiskInodeType) {
+        self.size = 0;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.indirect1 = 0;
+        self.indirect2 = 0;
+        self.nlink = 1;
+        self.type_ = type_ as u32;
+    }
+    /// Whether this inode is a directory
+    pub fn is_dir(&self) -> bool {
+        self.type_ == DiskInodeType::Directory as u32
+    }
+    /// Whether this inode is a file
+    pub fn is_file(&self) -> bool {
+        self.type_ == DiskInodeType::File as u32
+    }
+    /// Return block number correspond to size.
+    pub fn data_blocks(&self) -> u32 {
+        Self::_data_blocks(self.size)
+    }
+    fn _data_blocks(size: u32) -> u32 {
+        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+    }
+    /// Return number of blocks needed include indirect1/2.
+    pub fn total_blocks(size: u32) -> u32 {
+        let data_blocks = Self::_data_blocks(size) as usize;
+        let mut total = data_blocks;
+        // indirect1
+        if data_blocks > INODE_DIRECT_COUNT {
+            total += 1;
+        }
+        // indirect2
+        if data_blocks > INDIRECT1_BOUND {
+            total += 1;
+            // sub indirect1
+            total +=
+                (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+        }
+        total as u32
+    }
+    /// Get the number of data blocks that have to be allocated given the new size of data
+    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
+        assert!(new_size >= self.size);
+        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+    }
+    /// Get id of block given inner id
+    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id]
+        } else if inner_id < INDIRECT1_BOUND {
+            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect_block: &IndirectBlock| {
+                    indirect_block[inner_id - INODE_DIRECT_COUNT]
+                })
+        } else {
+            assert!(inner_id < INDIRECT2_BOUND);
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    indirect2[last / INODE_INDIRECT1_COUNT]
+                });
+            get_block_cache(indirect1 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect1: &IndirectBlock| {
+                    indirect1[last % INODE_INDIRECT1_COUNT]
+                })
+        }
+    }
+    /// Increase the size of current disk inode
+    pub fn increase_size(
+        &mut self,
+        new_size: u32,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let mut current_blocks = self.data_blocks();
+        self.size = new_size;
+        let mut total_blocks = self.data_blocks();
+        let mut new_blocks = new_blocks.into_iter();
+        // fill direct
+        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
+            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
+            current_blocks += 1;
+        }
+        // alloc indirect1
+        if total_blocks > INODE_DIRECT_COUNT as u32 {
+            if current_blocks == INODE_DIRECT_COUNT as u32 {
+                self.indirect1 = new_blocks.next().unwrap();
+            }
+            current_blocks -= INODE_DIRECT_COUNT as u32;
+            total_blocks -= INODE_DIRECT_COUNT as u32;
+        } else {
+            return;
+        }
+        // fill indirect1
+        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |indirect1: &mut IndirectBlock| {
+                while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
+                    indirect1[current_blocks as usize] = new_blocks.next().unwrap();
+                    current_blocks += 1;
+                }
+            });
+        // alloc indirect2
+        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
+            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
+                self.indirect2 = new_blocks.next().unwrap();
+            }
+            current_blocks -= INODE_INDIRECT1_COUNT as u32;
+            total_blocks -= INODE_INDIRECT1_COUNT as u32;
+        } else {
+            return;
+        }
+        // fill indirect2 from (a0, b0) -> (a1, b1)
+        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
+        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
+        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
+        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
+        // alloc low-level indirect1
+        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |indirect2: &mut IndirectBlock| {
+                while (a0 < a1) || (a0 == a1 && b0 < b1) {
+                    if b0 == 0 {
+                        indirect2[a0] = new_blocks.next().unwrap();
+                    }
+                    // fill current
+                    get_block_cache(indirect2[a0] as usize, Arc::clone(block_device))
+                        .lock()
+                        .modify(0, |indirect1: &mut IndirectBlock| {
+                            indirect1[b0] = new_blocks.next().unwrap();
+                        });
+                    // move to next
+                    b0 += 1;
+                    if b0 == INODE_INDIRECT1_COUNT {
+                        b0 = 0;
+                        a0 += 1;
+                    }
+                }
+            });
+    }
+
+    /// Clear size to zero and return blocks that should be deallocated.
+    /// Their contents are cleared once they are allocated again.
+    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        let mut data_blocks = self.data_blocks() as usize;
+        self.size = 0;
+        let mut current_blocks = 0usize;
+        // direct
+        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
+            v.push(self.direct[current_blocks]);
+            self.direct[current_blocks] = 0;
+            current_blocks += 1;
+        }
+        // indirect1 block
+        if data_blocks > INODE_DIRECT_COUNT {
+            v.push(self.indirect1);
+            data_blocks -= INODE_DIRECT_COUNT;
+            current_blocks = 0;
+        } else {
+            return v;
+        }
+        // indirect1
+        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |indirect1: &IndirectBlock| {
+                while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
+                    v.push(indirect1[current_blocks]);
+                    current_blocks += 1;
+                }
+            });
+        self.indirect1 = 0;
+        // indirect2 block
+        if data_blocks > INODE_INDIRECT1_COUNT {
+            v.push(self.indirect2);
+            data_blocks -= INODE_INDIRECT1_COUNT;
+        } else {
+            return v;
+        }
+        // indirect2
+        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
+        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
+        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
+        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |indirect2: &IndirectBlock| {
+                // full indirect1 blocks
+                for entry in indirect2.iter().take(a1) {
+                    v.push(*entry);
+                    get_block_cache(*entry as usize, Arc::clone(block_device))
+                        .lock()
+                        .read(0, |indirect1: &IndirectBlock| {
+                            for entry in indirect1.iter() {
+                                v.push(*entry);
+                            }
+                        });
+                }
+                // last indirect1 block
+                if b1 > 0 {
+                    v.push(indirect2[a1]);
+                    get_block_cache(indirect2[a1] as usize, Arc::clone(block_device))
+                        .lock()
+                        .read(0, |indirect1: &IndirectBlock| {
+                            for entry in indirect1.iter().take(b1) {
+                                v.push(*entry);
+                            }
+                        });
+                }
+            });
+        self.indirect2 = 0;
+        v
+    }
+    /// Read data from current disk inode
+    pub fn read_at(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        let mut start = offset;
+        let end = (offset + buf.len()).min(self.size as usize);
+        if start >= end {
+            return 0;
+        }
+        let mut start_block = start / BLOCK_SZ;
+        let mut read_size = 0usize;
+        loop {
+            // calculate end of current block
+            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
+            end_current_block = end_current_block.min(end);
+            // read and update read size
+            let block_read_size = end_current_block - start;
+            let dst = &mut buf[read_size..read_size + block_read_size];
+            get_block_cache(
+                self.get_block_id(start_block as u32, block_device) as usize,
+                Arc::clone(block_device),
+            )
+            .lock()
+            .read(0, |data_block: &DataBlock| {
+                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                dst.copy_from_slice(src);
+            });
+            read_size += block_read_size;
+            // move to next block
+            if end_current_block == end {
+                break;
+            }
+            start_block += 1;
+            start = end_current_block;
+        }
+        read_size
+    }
+    /// Write data into current disk inode
+    /// size must be adjusted properly beforehand
+    pub fn write_at(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        let mut start = offset;
+        let end = (offset + buf.len()).min(self.size as usize);
+        assert!(start <= end);
+        let mut start_block = start / BLOCK_SZ;
+        let mut write_size = 0usize;
+        loop {
+            // calculate end of current block
+            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
+            end_current_block = end_current_block.min(end);
+            // write and update write size
+            let block_write_size = end_current_block - start;
+            get_block_cache(
+                self.get_block_id(start_block as u32, block_device) as usize,
+                Arc::clone(block_device),
+            )
+            .lock()
+            .modify(0, |data_block: &mut DataBlock| {
+                let src = &buf[write_size..write_size + block_write_size];
+                let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
+                dst.copy_from_slice(src);
+            });
+            write_size += block_write_size;
+            // move to next block
+            if end_current_block == end {
+                break;
+            }
+            start_block += 1;
+            start = end_current_block;
+        }
+        write_size
+    }
+}
+
+/// A directory entry
+#[repr(C)]
+pub struct DirEntry {
+    name: [u8; NAME_LENGTH_LIMIT + 1],
+    inode_number: u32,
+}
+
+/// Size of a directory entry
+pub const DIRENT_SZ: usize = 32;
+
+impl DirEntry {
+    /// Create an empty directory entry
+    pub fn empty() -> Self {
+        Self {
+            name: [0u8; NAME_LENGTH_LIMIT + 1],
+            inode_number: 0,
+        }
+    }
+    /// Create a directory entry from name and inode number
+    pub fn new(name: &str, inode_number: u32) -> Self {
+        let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Self {
+            name: bytes,
+            inode_number,
+        }
+    }
+    /// Serialize into bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIRENT_SZ) }
+    }
+    /// Serialize into mutable bytes
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, DIRENT_SZ) }
+    }
+    /// Get name of the entry, which is empty if the entry is free
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(self.name_bytes()).unwrap_or_default()
+    }
+    /// Get the bytes of the name of the entry, which may not be UTF-8 on a
+    /// corrupted disk
+    pub fn name_bytes(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.name.len());
+        &self.name[..len]
+    }
+    /// Whether the entry is free, as left by an unlinked one
+    pub fn is_free(&self) -> bool {
+        self.name[0] == 0
+    }
+    /// Get inode number of the entry
+    pub fn inode_number(&self) -> u32 {
+        self.inode_number
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::exclusive_block_cache;
+    use crate::block_dev::mem::MemDevice;
+
+    /// data blocks of the file grown in the tests: all the direct ones, all
+    /// those of the indirect block, and two indirect blocks under the double
+    /// indirect one, the last one holding two
+    const DATA_BLOCKS: usize = INDIRECT1_BOUND + INODE_INDIRECT1_COUNT + 2;
+
+    fn new_file() -> DiskInode {
+        DiskInode {
+            size: 0,
+            direct: [0; INODE_DIRECT_COUNT],
+            indirect1: 0,
+            indirect2: 0,
+            nlink: 1,
+            type_: DiskInodeType::File as u32,
+        }
+    }
+
+    /// Grow `disk_inode` to `data_blocks` blocks, giving it the block ids
+    /// following those it has
+    fn grow(disk_inode: &mut DiskInode, data_blocks: usize, block_device: &Arc<dyn BlockDevice>) {
+        let new_size = (data_blocks * BLOCK_SZ) as u32;
+        let first = DiskInode::total_blocks(disk_inode.size) + 1;
+        let new_blocks = (first..first + disk_inode.blocks_num_needed(new_size)).collect();
+        disk_inode.increase_size(new_size, new_blocks, block_device);
+    }
+
+    #[test]
+    fn counts_the_indirect_blocks() {
+        assert_eq!(DiskInode::total_blocks(0), 0);
+        assert_eq!(DiskInode::total_blocks(1), 1);
+        let direct = (INODE_DIRECT_COUNT * BLOCK_SZ) as u32;
+        assert_eq!(DiskInode::total_blocks(direct), INODE_DIRECT_COUNT as u32);
+        assert_eq!(
+            DiskInode::total_blocks(direct + 1),
+            direct / BLOCK_SZ as u32 + 2
+        );
+        assert_eq!(
+            DiskInode::total_blocks((DATA_BLOCKS * BLOCK_SZ) as u32),
+            DATA_BLOCKS as u32 + 4
+        );
+    }
+
+    #[test]
+    fn maps_direct_and_indirect_blocks() {
+        let _cache = exclusive_block_cache();
+        let block_device: Arc<dyn BlockDevice> = MemDevice::new(DATA_BLOCKS + 5);
+        let mut disk_inode = new_file();
+        // grow it in steps stopping inside each level
+        for data_blocks in [10, INODE_DIRECT_COUNT + 3, INDIRECT1_BOUND + 1, DATA_BLOCKS] {
+            grow(&mut disk_inode, data_blocks, &block_device);
+        }
+        let block_id = |inner_id: usize| disk_inode.get_block_id(inner_id as u32, &block_device);
+        // the direct blocks come first
+        assert_eq!(block_id(0), 1);
+        assert_eq!(block_id(INODE_DIRECT_COUNT - 1), INODE_DIRECT_COUNT as u32);
+        // then the indirect block, and its data blocks
+        let indirect1 = INODE_DIRECT_COUNT as u32 + 1;
+        assert_eq!(disk_inode.indirect1, indirect1);
+        assert_eq!(block_id(INODE_DIRECT_COUNT), indirect1 + 1);
+        assert_eq!(
+            block_id(INDIRECT1_BOUND - 1),
+            indirect1 + INODE_INDIRECT1_COUNT as u32
+        );
+        // then the double indirect block, and each indirect block under it
+        // followed by its data blocks
+        let indirect2 = indirect1 + INODE_INDIRECT1_COUNT as u32 + 1;
+        assert_eq!(disk_inode.indirect2, indirect2);
+        assert_eq!(block_id(INDIRECT1_BOUND), indirect2 + 2);
+        assert_eq!(
+            block_id(INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1),
+            indirect2 + 1 + INODE_INDIRECT1_COUNT as u32
+        );
+        let last_indirect1 = indirect2 + 2 + INODE_INDIRECT1_COUNT as u32;
+        assert_eq!(
+            block_id(INDIRECT1_BOUND + INODE_INDIRECT1_COUNT),
+            last_indirect1 + 1
+        );
+        assert_eq!(block_id(DATA_BLOCKS - 1), last_indirect1 + 2);
+    }
+
+    #[test]
+    fn clear_size_gives_back_every_block() {
+        let _cache = exclusive_block_cache();
+        let block_device: Arc<dyn BlockDevice> = MemDevice::new(DATA_BLOCKS + 5);
+        for data_blocks in [3, INODE_DIRECT_COUNT + 1, INDIRECT1_BOUND, DATA_BLOCKS] {
+            let mut disk_inode = new_file();
+            grow(&mut disk_inode, data_blocks, &block_device);
+            let total_blocks = DiskInode::total_blocks(disk_inode.size);
+            let mut blocks = disk_inode.clear_size(&block_device);
+            blocks.sort_unstable();
+            assert_eq!(blocks, (1..=total_blocks).collect::<Vec<_>>());
+            assert_eq!(disk_inode.size, 0);
+            assert_eq!(disk_inode.direct, [0; INODE_DIRECT_COUNT]);
+            assert_eq!((disk_inode.indirect1, disk_inode.indirect2), (0, 0));
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! An easy file system, usable both by the kernel and on the host
+//!
+//! The file system only sees a [`BlockDevice`], read and written a block at a
+//! time, and goes through the block cache for every access to it. On top of
+//! the [`EasyFileSystem`] and its on-disk layout, an [`Inode`] offers the
+//! operations on files and directories.
+//!
+//! Each operation changing the file system is a transaction, committed through
+//! the [`Journal`], so that a crash never leaves the file system inconsistent.
+//! [`fsck()`] checks that it is, and repairs it otherwise.
+
+#![no_std]
+#![deny(missing_docs)]
+
+extern crate alloc;
+
+mod bitmap;
+mod block_cache;
+mod block_dev;
+mod efs;
+mod fsck;
+mod journal;
+mod layout;
+mod vfs;
+
+/// size of a block
+pub const BLOCK_SZ: usize = 512;
+
+pub use bitmap::Bitmap;
+use block_cache::{begin_transaction, end_transaction};
+pub use block_cache::{block_cache_sync_all, get_block_cache, BlockCache, BLOCK_CACHE_SIZE};
+pub use block_dev::BlockDevice;
+pub use efs::EasyFileSystem;
+pub use fsck::{fsck, FsckReport};
+pub use journal::{Journal, JOURNAL_BLOCKS, JOURNAL_CAPACITY};
+pub use layout::*;
+pub use vfs::Inode;
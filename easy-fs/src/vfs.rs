@@ -0,0 +1,432 @@
+//! Inodes as seen by the users of the file system
+//!
+//! Every operation locks the whole file system, so that it sees the disk
+//! inodes and the bitmaps in a consistent state. Those changing it run as
+//! transactions, committed before the file system is unlocked.
+
+use super::{
+    get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType, EasyFileSystem, BLOCK_SZ,
+    DIRENT_SZ, NAME_LENGTH_LIMIT,
+};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard};
+
+/// most data blocks written by a single transaction of [`Inode::write_at`],
+/// which leaves room in the journal for the blocks locating them
+const WRITE_BLOCKS_PER_TRANSACTION: usize = 16;
+
+/// Virtual filesystem layer over easy-fs
+pub struct Inode {
+    inode_id: u32,
+    block_id: usize,
+    block_offset: usize,
+    fs: Arc<Mutex<EasyFileSystem>>,
+    block_device: Arc<dyn BlockDevice>,
+}
+
+impl Inode {
+    /// Create a vfs inode of inode `inode_id`, at `block_offset` of block
+    /// `block_id`
+    pub fn new(
+        inode_id: u32,
+        block_id: u32,
+        block_offset: usize,
+        fs: Arc<Mutex<EasyFileSystem>>,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Self {
+        Self {
+            inode_id,
+            block_id: block_id as usize,
+            block_offset,
+            fs,
+            block_device,
+        }
+    }
+    /// Call a function over a disk inode to read it
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
+        get_block_cache(self.block_id, Arc::clone(&self.block_device))
+            .lock()
+            .read(self.block_offset, f)
+    }
+    /// Call a function over a disk inode to modify it
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+        get_block_cache(self.block_id, Arc::clone(&self.block_device))
+            .lock()
+            .modify(self.block_offset, f)
+    }
+    /// Run `f` over the locked file system as a transaction
+    fn transaction<V>(&self, f: impl FnOnce(&mut MutexGuard<EasyFileSystem>) -> V) -> V {
+        let mut fs = self.fs.lock();
+        fs.begin();
+        let ret = f(&mut fs);
+        fs.commit();
+        ret
+    }
+    /// Number of the inode
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+    /// Number of directory entries naming the inode
+    pub fn nlink(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.nlink)
+    }
+    /// Whether the inode is a directory
+    pub fn is_dir(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+    /// Size of the file in bytes
+    pub fn size(&self) -> usize {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.size as usize)
+    }
+    /// Read the directory entries of a disk inode, free ones included
+    fn dirents(&self, disk_inode: &DiskInode) -> Vec<DirEntry> {
+        // assert it is a directory
+        assert!(disk_inode.is_dir());
+        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+        let mut v = Vec::new();
+        for i in 0..file_count {
+            let mut dirent = DirEntry::empty();
+            assert_eq!(
+                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
+                DIRENT_SZ,
+            );
+            v.push(dirent);
+        }
+        v
+    }
+    /// Find the index of the entry named `name` in a disk inode, and the inode
+    /// it names
+    fn find_dirent(&self, name: &str, disk_inode: &DiskInode) -> Option<(usize, u32)> {
+        self.dirents(disk_inode)
+            .iter()
+            .enumerate()
+            .find(|(_, dirent)| !dirent.is_free() && dirent.name() == name)
+            .map(|(i, dirent)| (i, dirent.inode_number()))
+    }
+    /// Find inode under a disk inode by name
+    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
+        self.find_dirent(name, disk_inode)
+            .map(|(_, inode_id)| inode_id)
+    }
+    /// Get the vfs inode of inode `inode_id`
+    fn inode_of_id(&self, fs: &MutexGuard<EasyFileSystem>, inode_id: u32) -> Arc<Inode> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Arc::new(Self::new(
+            inode_id,
+            block_id,
+            block_offset,
+            self.fs.clone(),
+            self.block_device.clone(),
+        ))
+    }
+    /// Find the entry `name` of this directory, or None if there is no such
+    /// entry or this is not a directory
+    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
+        let fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return None;
+            }
+            self.find_inode_id(name, disk_inode)
+        })
+        .map(|inode_id| self.inode_of_id(&fs, inode_id))
+    }
+    /// Follow `path` from this directory, its components being separated by
+    /// `/`. Empty components are skipped, so an empty path is this inode.
+    pub fn find_path(self: &Arc<Self>, path: &str) -> Option<Arc<Inode>> {
+        let mut inode = self.clone();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = inode.find(name)?;
+        }
+        Some(inode)
+    }
+    /// Increase the size of a disk inode, or leave it as is and return false
+    /// if the disk is full
+    fn increase_size(
+        &self,
+        new_size: u32,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> bool {
+        if new_size < disk_inode.size {
+            return true;
+        }
+        let blocks_needed = disk_inode.blocks_num_needed(new_size);
+        let mut v: Vec<u32> = Vec::new();
+        for _ in 0..blocks_needed {
+            match fs.alloc_data() {
+                Some(block_id) => v.push(block_id),
+                None => {
+                    v.into_iter().for_each(|block_id| fs.dealloc_data(block_id));
+                    return false;
+                }
+            }
+        }
+        disk_inode.increase_size(new_size, v, &self.block_device);
+        true
+    }
+    /// Add the entry `name` for inode `inode_id` to a directory, in the first
+    /// free entry or after the last one. Returns false if the disk is full.
+    fn add_dirent(
+        &self,
+        name: &str,
+        inode_id: u32,
+        dir_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> bool {
+        let dirents = self.dirents(dir_inode);
+        let index = match dirents.iter().position(|dirent| dirent.is_free()) {
+            Some(index) => index,
+            None => {
+                let new_size = (dirents.len() + 1) * DIRENT_SZ;
+                if !self.increase_size(new_size as u32, dir_inode, fs) {
+                    return false;
+                }
+                dirents.len()
+            }
+        };
+        let dirent = DirEntry::new(name, inode_id);
+        dir_inode.write_at(index * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+        true
+    }
+    /// Whether `name` can name an entry
+    fn valid_name(name: &str) -> bool {
+        !name.is_empty() && name.len() <= NAME_LENGTH_LIMIT && !name.contains('/')
+    }
+    /// Create an inode of `type_` named `name` in this directory. Returns
+    /// None if it exists already, the name is invalid, this is not a
+    /// directory, or the disk is full.
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
+        if !Self::valid_name(name) {
+            return None;
+        }
+        self.transaction(|fs| {
+            // is it a directory without such an entry yet?
+            let free = self.read_disk_inode(|dir_inode| {
+                dir_inode.is_dir() && self.find_inode_id(name, dir_inode).is_none()
+            });
+            if !free {
+                return None;
+            }
+            // create a new file
+            let new_inode_id = fs.alloc_inode()?;
+            // initialize inode
+            let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
+            get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
+                    new_inode.initialize(type_);
+                });
+            let added = self
+                .modify_disk_inode(|dir_inode| self.add_dirent(name, new_inode_id, dir_inode, fs));
+            if !added {
+                fs.inode_bitmap
+                    .dealloc(&self.block_device, new_inode_id as usize);
+                return None;
+            }
+            Some(self.inode_of_id(fs, new_inode_id))
+        })
+    }
+    /// Create the file `name` in this directory
+    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File)
+    }
+    /// Create the directory `name` in this directory
+    pub fn create_dir(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+    /// Add the entry `name` to this directory for the file `target`, which
+    /// gets one more link. Returns false if the entry exists already, the name
+    /// is invalid, this is not a directory, `target` is not a file, or the
+    /// disk is full.
+    pub fn link(&self, name: &str, target: &Inode) -> bool {
+        if !Self::valid_name(name) {
+            return false;
+        }
+        self.transaction(|fs| {
+            let free = self.read_disk_inode(|dir_inode| {
+                dir_inode.is_dir() && self.find_inode_id(name, dir_inode).is_none()
+            });
+            if !free || !target.read_disk_inode(|disk_inode| disk_inode.is_file()) {
+                return false;
+            }
+            let added = self.modify_disk_inode(|dir_inode| {
+                self.add_dirent(name, target.inode_id, dir_inode, fs)
+            });
+            if added {
+                target.modify_disk_inode(|disk_inode| disk_inode.nlink += 1);
+            }
+            added
+        })
+    }
+    /// Remove the entry `name` from this directory. The inode it names is
+    /// freed with its data once it has no link left. Returns false if there
+    /// is no such entry, or it names a directory that is not empty.
+    pub fn unlink(&self, name: &str) -> bool {
+        self.transaction(|fs| {
+            let Some((index, inode_id)) = self.read_disk_inode(|dir_inode| {
+                if !dir_inode.is_dir() {
+                    return None;
+                }
+                self.find_dirent(name, dir_inode)
+            }) else {
+                return false;
+            };
+            let target = self.inode_of_id(fs, inode_id);
+            let empty = target.read_disk_inode(|disk_inode| {
+                !disk_inode.is_dir() || self.dirents(disk_inode).iter().all(|d| d.is_free())
+            });
+            if !empty {
+                return false;
+            }
+            self.modify_disk_inode(|dir_inode| {
+                let dirent = DirEntry::empty();
+                dir_inode.write_at(index * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+            });
+            target.modify_disk_inode(|disk_inode| {
+                disk_inode.nlink -= 1;
+                if disk_inode.nlink > 0 {
+                    return;
+                }
+                for data_block in disk_inode.clear_size(&self.block_device) {
+                    fs.dealloc_data(data_block);
+                }
+                fs.inode_bitmap
+                    .dealloc(&self.block_device, inode_id as usize);
+            });
+            true
+        })
+    }
+    /// List the names of the entries of this directory
+    pub fn ls(&self) -> Vec<String> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return Vec::new();
+            }
+            self.dirents(disk_inode)
+                .iter()
+                .filter(|dirent| !dirent.is_free())
+                .map(|dirent| String::from(dirent.name()))
+                .collect()
+        })
+    }
+    /// Read data from current inode
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+    }
+    /// Write data to current inode, growing it as needed. Returns how many
+    /// bytes were written, which is fewer than asked only if the disk is full.
+    ///
+    /// The data is written by several transactions if it spans many blocks,
+    /// so that a crash may leave only the start of it written.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        const CHUNK_SIZE: usize = WRITE_BLOCKS_PER_TRANSACTION * BLOCK_SZ;
+        // grow the file over a hole before `offset` first
+        while self.transaction(|fs| {
+            self.modify_disk_inode(|disk_inode| {
+                let size = disk_inode.size as usize;
+                size < offset
+                    && self.increase_size(offset.min(size + CHUNK_SIZE) as u32, disk_inode, fs)
+            })
+        }) {}
+        let mut written = 0;
+        for chunk in buf.chunks(CHUNK_SIZE) {
+            let len = self.transaction(|fs| self.write_chunk(offset + written, chunk, fs));
+            written += len;
+            if len < chunk.len() {
+                break;
+            }
+        }
+        written
+    }
+    /// Write `buf` at `offset` of current inode in the running transaction
+    fn write_chunk(&self, offset: usize, buf: &[u8], fs: &mut MutexGuard<EasyFileSystem>) -> usize {
+        self.modify_disk_inode(|disk_inode| {
+            let end = (offset + buf.len()) as u32;
+            if !self.increase_size(end, disk_inode, fs) {
+                // write what fits in the blocks the file has already
+                let room = disk_inode.data_blocks() as usize * BLOCK_SZ;
+                if room <= offset {
+                    return 0;
+                }
+                let end = room.min(offset + buf.len()) as u32;
+                if end > disk_inode.size {
+                    disk_inode.size = end;
+                }
+                return disk_inode.write_at(
+                    offset,
+                    &buf[..end as usize - offset],
+                    &self.block_device,
+                );
+            }
+            disk_inode.write_at(offset, buf, &self.block_device)
+        })
+    }
+    /// Clear the data in current inode
+    pub fn clear(&self) {
+        self.transaction(|fs| {
+            self.modify_disk_inode(|disk_inode| {
+                let size = disk_inode.size;
+                let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
+                assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
+                for data_block in data_blocks_dealloc.into_iter() {
+                    fs.dealloc_data(data_block);
+                }
+            });
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::exclusive_block_cache;
+    use crate::block_dev::mem::MemDevice;
+
+    const TOTAL_BLOCKS: u32 = 2048;
+
+    #[test]
+    fn reuses_free_dirents() {
+        let _cache = exclusive_block_cache();
+        let efs = EasyFileSystem::create(MemDevice::new(TOTAL_BLOCKS as usize), TOTAL_BLOCKS, 1);
+        let root = EasyFileSystem::root_inode(&efs);
+        root.create("a").unwrap();
+        root.create("b").unwrap();
+        assert!(root.unlink("a"));
+        assert_eq!(root.ls(), ["b"]);
+        let c = root.create("c").unwrap();
+        // "c" takes the entry "a" left
+        assert_eq!(root.size(), 2 * DIRENT_SZ);
+        assert_eq!(root.ls(), ["c", "b"]);
+        assert_eq!(root.find("c").unwrap().inode_id(), c.inode_id());
+        root.create("d").unwrap();
+        assert_eq!(root.size(), 3 * DIRENT_SZ);
+    }
+
+    #[test]
+    fn clear_frees_the_blocks_of_a_file() {
+        let _cache = exclusive_block_cache();
+        let efs = EasyFileSystem::create(MemDevice::new(TOTAL_BLOCKS as usize), TOTAL_BLOCKS, 1);
+        let root = EasyFileSystem::root_inode(&efs);
+        let file = root.create("file").unwrap();
+        let data = alloc::vec![0x5a; 40 * BLOCK_SZ];
+        assert_eq!(file.write_at(0, &data), data.len());
+        let first = efs.lock().alloc_data().unwrap();
+        efs.lock().dealloc_data(first);
+        file.clear();
+        assert_eq!(file.size(), 0);
+        // the blocks of the file are the first free ones again
+        let mut fs = efs.lock();
+        let blocks: Vec<_> = (0..DiskInode::total_blocks(data.len() as u32))
+            .map(|_| fs.alloc_data().unwrap())
+            .collect();
+        assert!(blocks.iter().all(|block_id| *block_id < first));
+    }
+}
@@ -1,23 +1,35 @@
+use std::env;
 use std::io::{Result, Write};
 use std::fs::{File, read_dir};
 
 fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // otherwise, the apps are packed into the disk image by easy-fs-fuse
+    if env::var_os("CARGO_FEATURE_EMBEDDED_APPS").is_none() {
+        return;
+    }
     println!("cargo:rerun-if-changed=../ci-user/user/src/");
-    println!("cargo:rerun-if-changed={}", TARGET_PATH);
+    for path in TARGET_PATHS {
+        println!("cargo:rerun-if-changed={}", path);
+    }
     insert_app_data().unwrap();
 }
 
-static TARGET_PATH: &str = "../ci-user/user/build/bin/";
+/// the test apps, and the shell started as the initial process
+static TARGET_PATHS: [&str; 2] = ["../ci-user/user/build/elf/", "../shell/build/elf/"];
 
 fn insert_app_data() -> Result<()> {
     let mut f = File::create("src/link_app.S").unwrap();
-    let mut apps: Vec<_> = read_dir("../ci-user/user/build/bin")
-        .unwrap()
-        .into_iter()
-        .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
-            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
+    let mut apps: Vec<_> = TARGET_PATHS
+        .iter()
+        // the shell may not be built
+        .filter_map(|path| Some((path, read_dir(path).ok()?)))
+        .flat_map(|(path, dir)| {
+            dir.map(move |dir_entry| {
+                let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
+                name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
+                (name_with_ext, path)
+            })
         })
         .collect();
     apps.sort();
@@ -37,11 +49,11 @@ _num_app:
     writeln!(f, r#"
     .global _app_names
 _app_names:"#)?;
-    for app in apps.iter() {
+    for (app, _) in apps.iter() {
         writeln!(f, r#"    .string "{}""#, app)?;
     }
 
-    for (idx, app) in apps.iter().enumerate() {
+    for (idx, (app, path)) in apps.iter().enumerate() {
         println!("app_{}: {}", idx, app);
         writeln!(f, r#"
     .section .data
@@ -49,8 +61,8 @@ _app_names:"#)?;
     .global app_{0}_end
     .align 3
 app_{0}_start:
-    .incbin "{2}{1}.bin"
-app_{0}_end:"#, idx, app, TARGET_PATH)?;
+    .incbin "{2}{1}.elf"
+app_{0}_end:"#, idx, app, path)?;
     }
     Ok(())
 }
@@ -6,19 +6,46 @@
 pub const USER_STACK_SIZE: usize = 4096;
 /// kernel stack size
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
+/// size of a page frame
+pub const PAGE_SIZE: usize = 0x1000;
 /// kernel heap size
 pub const KERNEL_HEAP_SIZE: usize = 0x20000;
 /// the max number of apps
 pub const MAX_APP_NUM: usize = 16;
+/// the max number of threads of all processes, each owning a kernel and a user stack
+pub const MAX_THREAD_NUM: usize = 32;
+/// the max number of harts, each of which owns a boot stack in `entry.asm`, and
+/// the harts with higher ids are parked there
+pub const MAX_HARTS: usize = 4;
+/// boot stack size of each hart
+pub const BOOT_STACK_SIZE: usize = 4096 * 16;
+/// the max number of file descriptors of a process
+pub const MAX_FD_NUM: usize = 1024;
 /// base_addr(changed) of app
 pub const APP_BASE_ADDRESS: usize = 0x80400000;
 /// size limit of app
 pub const APP_SIZE_LIMIT: usize = 0x20000;
+/// room taken at most by the arguments and environment of an app on its user stack
+pub const MAX_ARG_SIZE: usize = 1024;
+/// directory on the disk holding the apps, unless they are embedded in the kernel
+pub const APP_DIR: &str = "bin";
+/// name of the app started as the initial process, the shell in `shell/`
+pub const INIT_APP: &str = "user_shell";
 
 /// the max number of syscall
 pub const MAX_SYSCALL_NUM: usize = 500;
 /// clock frequency
 pub const CLOCK_FREQ: usize = 12500000;
+/// base address of the PLIC of QEMU virt
+pub const PLIC_BASE: usize = 0x0c00_0000;
+/// base address of the NS16550A UART of QEMU virt
+pub const UART_BASE: usize = 0x1000_0000;
+/// interrupt number of the UART at the PLIC
+pub const UART_IRQ: usize = 10;
+/// base address of the first virtio-mmio slot of QEMU virt, where the disk is
+pub const VIRTIO0: usize = 0x1000_1000;
+/// interrupt number of the first virtio-mmio slot at the PLIC
+pub const VIRTIO0_IRQ: usize = 1;
 /// the physical memory end
 pub const MEMORY_END: usize = 0x88000000;
 /// syscall number
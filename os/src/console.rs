@@ -1,20 +1,120 @@
-//! SBI console driver, for text output
-use crate::sbi::console_putchar;
+//! Console, on SBI or on the UART
+//!
+//! Output is gathered in a buffer and flushed to the backend. On SBI, it goes
+//! through the debug console extension in one call, or one byte at a time on
+//! older SBI implementations. The console takes raw bytes, so whatever
+//! userspace writes goes out as is.
+//!
+//! The console starts on SBI, and switches to the UART once its driver is up,
+//! which also makes input interrupt-driven. Input is taken by the terminal in
+//! `fs::tty`, which is what userspace reads.
+use crate::drivers::UART;
+use crate::sbi::{console_getchar, console_putchar, console_write};
+use crate::sync::SpinLock;
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-struct Stdout;
+/// The device behind the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleBackend {
+    /// the console of the SBI implementation
+    Sbi,
+    /// our own UART driver
+    Uart,
+}
+
+/// whether the backend is [`ConsoleBackend::Uart`]
+static USE_UART: AtomicBool = AtomicBool::new(false);
+
+/// Switch the console to `backend`
+pub fn set_backend(backend: ConsoleBackend) {
+    USE_UART.store(backend == ConsoleBackend::Uart, Ordering::Release);
+}
+
+/// Get the device behind the console
+pub fn backend() -> ConsoleBackend {
+    if USE_UART.load(Ordering::Acquire) {
+        ConsoleBackend::Uart
+    } else {
+        ConsoleBackend::Sbi
+    }
+}
+
+/// size of the output buffer
+const BUFFER_SIZE: usize = 256;
+
+struct Stdout {
+    buffer: [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl Stdout {
+    /// Put `bytes` into the buffer, flushing it whenever it is full
+    fn write_bytes(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let len = bytes.len().min(BUFFER_SIZE - self.len);
+            self.buffer[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+            self.len += len;
+            bytes = &bytes[len..];
+            if self.len == BUFFER_SIZE {
+                self.flush();
+            }
+        }
+    }
+    /// Send the buffer to the console
+    fn flush(&mut self) {
+        let bytes = &self.buffer[..self.len];
+        if backend() == ConsoleBackend::Uart {
+            UART.write_bytes(bytes);
+        } else if !console_write(bytes) {
+            bytes
+                .iter()
+                .for_each(|byte| console_putchar(*byte as usize));
+        }
+        self.len = 0;
+    }
+}
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            console_putchar(c as usize);
-        }
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
 
+/// serializes output from all harts, so that lines do not interleave
+static STDOUT: SpinLock<Stdout> = SpinLock::new(Stdout {
+    buffer: [0; BUFFER_SIZE],
+    len: 0,
+});
+
 pub fn print(args: fmt::Arguments) {
-    Stdout.write_fmt(args).unwrap();
+    let mut stdout = STDOUT.lock();
+    stdout.write_fmt(args).unwrap();
+    stdout.flush();
+}
+
+/// Write raw bytes to the console
+pub fn write_bytes(bytes: &[u8]) {
+    let mut stdout = STDOUT.lock();
+    stdout.write_bytes(bytes);
+    stdout.flush();
+}
+
+/// Take the bytes typed so far into `buf`, returning how many
+pub fn read_bytes(buf: &mut [u8]) -> usize {
+    if backend() == ConsoleBackend::Uart {
+        return UART.read_bytes(buf);
+    }
+    let mut len = 0;
+    while len < buf.len() {
+        let Some(c) = console_getchar() else {
+            break;
+        };
+        buf[len] = c;
+        len += 1;
+    }
+    len
 }
 
 /// Print! to the host console using the format string and arguments.
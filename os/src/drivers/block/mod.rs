@@ -0,0 +1,18 @@
+//! Block devices
+//!
+//! A block device is read and written a whole block at a time. The disk of
+//! QEMU virt is a [`VirtIOBlock`] in the first virtio-mmio slot, present only
+//! if QEMU is given a drive.
+
+mod virtio_blk;
+
+use crate::config::VIRTIO0;
+use alloc::sync::Arc;
+pub use easy_fs::{BlockDevice, BLOCK_SZ};
+use lazy_static::*;
+pub use virtio_blk::VirtIOBlock;
+
+lazy_static! {
+    /// The disk, if there is one
+    pub static ref BLOCK_DEVICE: Option<Arc<VirtIOBlock>> = VirtIOBlock::new(VIRTIO0).map(Arc::new);
+}
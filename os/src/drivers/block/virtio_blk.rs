@@ -0,0 +1,186 @@
+//! virtio block device
+//!
+//! A request is a chain of three buffers: a header telling what to do, the
+//! data, and a status byte the device writes once it is done. Until the
+//! interrupt is registered, and whenever there is no task to put to sleep,
+//! the requester polls the used ring. Otherwise it sleeps until the interrupt
+//! handler finds its request completed.
+
+use super::{BlockDevice, BLOCK_SZ};
+use crate::drivers::virtio::{VirtQueue, VirtioMmio, QUEUE_SIZE};
+use crate::sync::SpinLock;
+use crate::task::{block_current_and_run_next, current_task, wakeup_task, TaskControlBlock};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// device id of a block device
+const VIRTIO_ID_BLOCK: u32 = 2;
+/// request type: read from the device
+const VIRTIO_BLK_T_IN: u32 = 0;
+/// request type: write to the device
+const VIRTIO_BLK_T_OUT: u32 = 1;
+/// request status: success
+const VIRTIO_BLK_S_OK: u8 = 0;
+/// offset of the capacity in the configuration space, in 512-byte sectors
+const CONFIG_CAPACITY: usize = 0;
+
+/// The header of a request
+#[repr(C)]
+struct BlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// A virtio block device
+pub struct VirtIOBlock {
+    transport: VirtioMmio,
+    /// number of blocks of the device
+    capacity: usize,
+    queue: SpinLock<BlkQueue>,
+    /// whether the interrupt handler is registered, so that requesters can sleep
+    irq_enabled: AtomicBool,
+}
+
+/// The request queue, and who is waiting on it
+struct BlkQueue {
+    queue: VirtQueue,
+    /// whether the request of each chain head is completed
+    done: [bool; QUEUE_SIZE],
+    /// tasks sleeping on the request of each chain head
+    waiters: [Option<Arc<TaskControlBlock>>; QUEUE_SIZE],
+}
+
+impl BlkQueue {
+    /// Mark the requests given back by the device completed, returning the
+    /// tasks to wake up
+    fn collect_used(&mut self) -> Vec<Arc<TaskControlBlock>> {
+        let mut waiters = Vec::new();
+        while let Some(head) = self.queue.pop_used() {
+            self.done[head as usize] = true;
+            waiters.extend(self.waiters[head as usize].take());
+        }
+        waiters
+    }
+}
+
+impl VirtIOBlock {
+    /// Set up the block device at `base`, in polling mode. Returns None if
+    /// there is none.
+    pub fn new(base: usize) -> Option<Self> {
+        let transport = VirtioMmio::new(base);
+        if !transport.probe(VIRTIO_ID_BLOCK) || !transport.begin_init(0) {
+            return None;
+        }
+        let queue = VirtQueue::new(&transport, 0)?;
+        transport.finish_init();
+        let capacity = transport.config_read_u64(CONFIG_CAPACITY) as usize * 512 / BLOCK_SZ;
+        info!("[kernel] virtio-blk: {} blocks", capacity);
+        Some(Self {
+            transport,
+            capacity,
+            queue: SpinLock::new(BlkQueue {
+                queue,
+                done: [false; QUEUE_SIZE],
+                waiters: core::array::from_fn(|_| None),
+            }),
+            irq_enabled: AtomicBool::new(false),
+        })
+    }
+    /// Number of blocks of the device
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Let requesters sleep until the interrupt, once [`VirtIOBlock::handle_irq`]
+    /// is registered as its handler
+    pub fn enable_irq(&self) {
+        self.irq_enabled.store(true, Ordering::Release);
+    }
+    /// Handle the interrupt of the device, waking up the requesters whose
+    /// requests are completed
+    pub fn handle_irq(&self) {
+        self.transport.ack_interrupt();
+        let waiters = self.queue.lock().collect_used();
+        waiters.into_iter().for_each(wakeup_task);
+    }
+    /// Transfer block `block_id` from or to the `BLOCK_SZ` bytes at `buf`
+    fn request(&self, req_type: u32, block_id: usize, buf: usize) {
+        assert!(
+            block_id < self.capacity,
+            "virtio-blk: block {} out of range",
+            block_id
+        );
+        let header = BlkReqHeader {
+            req_type,
+            reserved: 0,
+            sector: (block_id * BLOCK_SZ / 512) as u64,
+        };
+        let mut status = u8::MAX;
+        let header_buf = (
+            &header as *const BlkReqHeader as usize,
+            core::mem::size_of::<BlkReqHeader>(),
+        );
+        let data_buf = (buf, BLOCK_SZ);
+        let status_buf = (&mut status as *mut u8 as usize, 1);
+        let (inputs, outputs) = if req_type == VIRTIO_BLK_T_IN {
+            ([header_buf].to_vec(), [data_buf, status_buf].to_vec())
+        } else {
+            ([header_buf, data_buf].to_vec(), [status_buf].to_vec())
+        };
+        // we do not return before the request is completed, so the buffers,
+        // including those on our stack, outlive it
+        let head = loop {
+            let mut inner = self.queue.lock();
+            if let Some(head) = unsafe { inner.queue.add(&inputs, &outputs) } {
+                self.transport.notify(inner.queue.index());
+                break head;
+            }
+            // all descriptors are taken, wait for other requests to complete
+            let waiters = inner.collect_used();
+            drop(inner);
+            waiters.into_iter().for_each(wakeup_task);
+            core::hint::spin_loop();
+        };
+        loop {
+            let mut inner = self.queue.lock();
+            let waiters = inner.collect_used();
+            if inner.done[head as usize] {
+                inner.done[head as usize] = false;
+                inner.queue.recycle(head);
+                drop(inner);
+                waiters.into_iter().for_each(wakeup_task);
+                break;
+            }
+            match current_task().filter(|_| self.irq_enabled.load(Ordering::Acquire)) {
+                Some(task) => {
+                    inner.waiters[head as usize] = Some(task);
+                    waiters.into_iter().for_each(wakeup_task);
+                    block_current_and_run_next(inner);
+                }
+                None => {
+                    drop(inner);
+                    waiters.into_iter().for_each(wakeup_task);
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        let status = unsafe { (&status as *const u8).read_volatile() };
+        assert_eq!(
+            status, VIRTIO_BLK_S_OK,
+            "virtio-blk: request on block {} failed",
+            block_id
+        );
+    }
+}
+
+impl BlockDevice for VirtIOBlock {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        assert_eq!(buf.len(), BLOCK_SZ);
+        self.request(VIRTIO_BLK_T_IN, block_id, buf.as_mut_ptr() as usize);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        assert_eq!(buf.len(), BLOCK_SZ);
+        self.request(VIRTIO_BLK_T_OUT, block_id, buf.as_ptr() as usize);
+    }
+}
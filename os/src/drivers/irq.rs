@@ -0,0 +1,68 @@
+//! Dispatch of external interrupts to the drivers
+//!
+//! A driver registers a handler for the interrupt number of its device at the
+//! PLIC. The interrupt is then enabled on every hart, both those already up
+//! and those coming up later, and any of them may end up handling it.
+
+use super::PLIC;
+use crate::sync::SpinLock;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+/// the number of interrupt sources of the PLIC, source 0 meaning none
+const MAX_IRQ: usize = 1024;
+
+/// A handler of the interrupts of a device
+pub type IrqHandler = Arc<dyn Fn() + Send + Sync>;
+
+/// Registered handlers, and the harts they are enabled on
+struct IrqTable {
+    handlers: BTreeMap<usize, IrqHandler>,
+    /// bit `i` tells whether hart `i` takes external interrupts
+    online_harts: usize,
+}
+
+static IRQ_TABLE: SpinLock<IrqTable> = SpinLock::new(IrqTable {
+    handlers: BTreeMap::new(),
+    online_harts: 0,
+});
+
+/// Run `handler` on every interrupt `irq`, which gets `priority` at the PLIC.
+/// Returns false if `irq` is not valid or already has a handler.
+pub fn register_irq(irq: usize, priority: u32, handler: IrqHandler) -> bool {
+    if irq == 0 || irq >= MAX_IRQ || priority == 0 {
+        return false;
+    }
+    let mut table = IRQ_TABLE.lock();
+    if table.handlers.contains_key(&irq) {
+        return false;
+    }
+    table.handlers.insert(irq, handler);
+    PLIC.set_priority(irq, priority);
+    for hart in (0..usize::BITS as usize).filter(|hart| table.online_harts & 1 << hart != 0) {
+        PLIC.enable(hart, irq);
+    }
+    true
+}
+
+/// Let the registered interrupts, and those registered later, reach `hart`
+pub fn init_hart(hart: usize) {
+    let mut table = IRQ_TABLE.lock();
+    table.online_harts |= 1 << hart;
+    PLIC.set_threshold(hart, 0);
+    for irq in table.handlers.keys() {
+        PLIC.enable(hart, *irq);
+    }
+}
+
+/// Handle the pending interrupts of the devices on `hart`
+pub fn handle_external_interrupt(hart: usize) {
+    while let Some(irq) = PLIC.claim(hart) {
+        let handler = IRQ_TABLE.lock().handlers.get(&irq).cloned();
+        match handler {
+            Some(handler) => handler(),
+            None => warn!("[kernel] unexpected irq {}", irq),
+        }
+        PLIC.complete(hart, irq);
+    }
+}
@@ -0,0 +1,53 @@
+//! Device drivers
+//!
+//! Devices of QEMU virt are memory-mapped, and without paging the kernel
+//! reaches their registers at their physical addresses. Their interrupts come
+//! through the [`plic`] as supervisor external interrupts, and [`irq`] hands
+//! them to the handlers the drivers registered.
+
+pub mod block;
+pub mod irq;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
+
+use crate::config::{PLIC_BASE, UART_BASE, UART_IRQ, VIRTIO0_IRQ};
+use crate::console::{set_backend, ConsoleBackend};
+use crate::fs::TTY;
+use alloc::sync::Arc;
+use block::BLOCK_DEVICE;
+pub use irq::{handle_external_interrupt, init_hart, register_irq, IrqHandler};
+use plic::Plic;
+use uart::Uart;
+
+/// The PLIC of the machine
+pub static PLIC: Plic = Plic::new(PLIC_BASE);
+/// The serial port of the machine
+pub static UART: Uart = Uart::new(UART_BASE);
+
+/// Initialize the devices, and the interrupts of the boot hart
+pub fn init(hart: usize) {
+    init_hart(hart);
+    UART.init();
+    // the terminal takes the input right away, so that Ctrl-C works even
+    // when nobody reads
+    assert!(register_irq(
+        UART_IRQ,
+        1,
+        Arc::new(|| {
+            UART.handle_irq();
+            TTY.receive_input();
+        })
+    ));
+    set_backend(ConsoleBackend::Uart);
+    if let Some(block_device) = BLOCK_DEVICE.as_ref() {
+        let handler_device = block_device.clone();
+        if register_irq(
+            VIRTIO0_IRQ,
+            1,
+            Arc::new(move || handler_device.handle_irq()),
+        ) {
+            block_device.enable_irq();
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Platform-Level Interrupt Controller
+//!
+//! The PLIC routes the interrupts of devices to the harts. Each hart has one
+//! context per privilege mode, and the kernel uses the supervisor ones.
+
+/// offset of the enable bits of context 0
+const ENABLE_BASE: usize = 0x2000;
+/// distance between the enable bits of two contexts
+const ENABLE_STRIDE: usize = 0x80;
+/// offset of the priority threshold of context 0
+const CONTEXT_BASE: usize = 0x20_0000;
+/// distance between the thresholds of two contexts
+const CONTEXT_STRIDE: usize = 0x1000;
+/// offset of the claim/complete register from the threshold
+const CLAIM_OFFSET: usize = 4;
+
+/// A PLIC
+pub struct Plic {
+    /// base address of the registers
+    base: usize,
+}
+
+impl Plic {
+    /// Create the driver of the PLIC at `base`
+    pub const fn new(base: usize) -> Self {
+        Self { base }
+    }
+    /// The supervisor context of `hart`, on QEMU virt where every hart has
+    /// a machine context followed by a supervisor one
+    fn context(hart: usize) -> usize {
+        2 * hart + 1
+    }
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+    /// Set the priority of `irq`, 0 meaning it never fires
+    pub fn set_priority(&self, irq: usize, priority: u32) {
+        unsafe { self.reg(4 * irq).write_volatile(priority) }
+    }
+    /// Let `irq` interrupt `hart` in supervisor mode
+    pub fn enable(&self, hart: usize, irq: usize) {
+        let reg = self.reg(ENABLE_BASE + Self::context(hart) * ENABLE_STRIDE + 4 * (irq / 32));
+        unsafe { reg.write_volatile(reg.read_volatile() | 1 << (irq % 32)) }
+    }
+    /// Only let interrupts of a priority above `threshold` reach `hart`
+    pub fn set_threshold(&self, hart: usize, threshold: u32) {
+        let reg = self.reg(CONTEXT_BASE + Self::context(hart) * CONTEXT_STRIDE);
+        unsafe { reg.write_volatile(threshold) }
+    }
+    /// Take the pending interrupt of the highest priority for `hart`, if any
+    pub fn claim(&self, hart: usize) -> Option<usize> {
+        let reg = self.reg(CONTEXT_BASE + Self::context(hart) * CONTEXT_STRIDE + CLAIM_OFFSET);
+        match unsafe { reg.read_volatile() } {
+            0 => None,
+            irq => Some(irq as usize),
+        }
+    }
+    /// Tell the PLIC that `hart` is done with `irq`
+    pub fn complete(&self, hart: usize, irq: usize) {
+        let reg = self.reg(CONTEXT_BASE + Self::context(hart) * CONTEXT_STRIDE + CLAIM_OFFSET);
+        unsafe { reg.write_volatile(irq as u32) }
+    }
+}
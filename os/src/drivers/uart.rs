@@ -0,0 +1,185 @@
+//! NS16550A UART, the serial port of QEMU virt
+//!
+//! Both directions go through ring buffers. Received bytes are taken in by
+//! the interrupt handler, and bytes to send are fed to the device whenever it
+//! can take more, either right away or on its "transmitter empty" interrupt.
+
+use crate::sync::SpinLock;
+
+/// receive buffer register (read)
+const RBR: usize = 0;
+/// transmit holding register (write)
+const THR: usize = 0;
+/// interrupt enable register
+const IER: usize = 1;
+/// FIFO control register (write)
+const FCR: usize = 2;
+/// line control register
+const LCR: usize = 3;
+/// modem control register
+const MCR: usize = 4;
+/// line status register
+const LSR: usize = 5;
+
+/// IER: interrupt when a byte is received
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+/// IER: interrupt when the transmitter can take more
+const IER_TX_EMPTY: u8 = 1 << 1;
+/// FCR: enable and clear both FIFOs
+const FCR_FIFO_ENABLE_CLEAR: u8 = 0b111;
+/// LCR: 8 data bits, no parity, one stop bit
+const LCR_8N1: u8 = 0b11;
+/// MCR: DTR, RTS, and OUT2 which lets the interrupts out
+const MCR_DTR_RTS_OUT2: u8 = 0b1011;
+/// LSR: a received byte is ready
+const LSR_DATA_READY: u8 = 1 << 0;
+/// LSR: the transmitter can take a byte
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+/// size of the receive buffer
+const RX_BUFFER_SIZE: usize = 256;
+/// size of the transmit buffer
+const TX_BUFFER_SIZE: usize = 1024;
+
+/// A fixed-size byte queue, which never allocates in interrupt context
+struct RingBuffer<const N: usize> {
+    arr: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            arr: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+    /// Append `byte`, returning false if there is no room
+    fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.arr[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.arr[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// A 16550-compatible UART
+pub struct Uart {
+    /// base address of the registers
+    base: usize,
+    inner: SpinLock<UartInner>,
+}
+
+struct UartInner {
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    /// current value of IER
+    ier: u8,
+}
+
+impl Uart {
+    /// Create the driver of the UART at `base`
+    pub const fn new(base: usize) -> Self {
+        Self {
+            base,
+            inner: SpinLock::new(UartInner {
+                rx: RingBuffer::new(),
+                tx: RingBuffer::new(),
+                ier: 0,
+            }),
+        }
+    }
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { ((self.base + reg) as *const u8).read_volatile() }
+    }
+    fn write_reg(&self, reg: usize, value: u8) {
+        unsafe { ((self.base + reg) as *mut u8).write_volatile(value) }
+    }
+    fn set_ier(&self, inner: &mut UartInner, ier: u8) {
+        if inner.ier != ier {
+            inner.ier = ier;
+            self.write_reg(IER, ier);
+        }
+    }
+    /// Set up the line and turn on the receive interrupt
+    pub fn init(&self) {
+        let mut inner = self.inner.lock();
+        self.write_reg(IER, 0);
+        self.write_reg(LCR, LCR_8N1);
+        self.write_reg(FCR, FCR_FIFO_ENABLE_CLEAR);
+        self.write_reg(MCR, MCR_DTR_RTS_OUT2);
+        inner.ier = 0;
+        self.set_ier(&mut inner, IER_RX_AVAILABLE);
+    }
+    /// Feed the transmitter from the transmit buffer for as long as it takes
+    /// bytes, and ask for an interrupt if some are left
+    fn start_tx(&self, inner: &mut UartInner) {
+        while !inner.tx.is_empty() && self.read_reg(LSR) & LSR_TX_EMPTY != 0 {
+            self.write_reg(THR, inner.tx.pop().unwrap());
+        }
+        let ier = if inner.tx.is_empty() {
+            inner.ier & !IER_TX_EMPTY
+        } else {
+            inner.ier | IER_TX_EMPTY
+        };
+        self.set_ier(inner, ier);
+    }
+    /// Queue `bytes` to be sent. When the transmit buffer is full, wait for
+    /// the device to take bytes out of it instead of dropping them.
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        let mut inner = self.inner.lock();
+        for byte in bytes {
+            while inner.tx.is_full() {
+                if self.read_reg(LSR) & LSR_TX_EMPTY != 0 {
+                    let byte = inner.tx.pop().unwrap();
+                    self.write_reg(THR, byte);
+                }
+            }
+            inner.tx.push(*byte);
+        }
+        self.start_tx(&mut inner);
+    }
+    /// Take the bytes received so far into `buf`, returning how many
+    pub fn read_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut inner = self.inner.lock();
+        let mut len = 0;
+        while len < buf.len() {
+            let Some(byte) = inner.rx.pop() else {
+                break;
+            };
+            buf[len] = byte;
+            len += 1;
+        }
+        len
+    }
+    /// Handle an interrupt of the UART: take in what it received, and feed it
+    /// what is left to send
+    pub fn handle_irq(&self) {
+        let mut inner = self.inner.lock();
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let byte = self.read_reg(RBR);
+            // input nobody reads for a while is dropped
+            inner.rx.push(byte);
+        }
+        self.start_tx(&mut inner);
+    }
+}
@@ -0,0 +1,360 @@
+//! virtio over MMIO, the transport of the virtio devices of QEMU virt
+//!
+//! Both the legacy interface (version 1), which QEMU offers by default, and
+//! the modern one (version 2) are supported. A driver exchanges requests with
+//! its device through [`VirtQueue`]s: it puts chains of buffer descriptors in
+//! the available ring, and the device gives them back in the used ring once
+//! it is done with them.
+//!
+//! Without paging, the buffers are handed to the device at the addresses the
+//! kernel sees them at.
+
+use crate::config::PAGE_SIZE;
+use crate::mm::{frame_alloc_contiguous, FrameTracker};
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+/// "virt" in little endian
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
+/// magic value
+const MAGIC: usize = 0x000;
+/// version of the interface
+const VERSION: usize = 0x004;
+/// type of the device, 0 if the slot is empty
+const DEVICE_ID: usize = 0x008;
+/// features offered by the device, 32 of them at a time
+const DEVICE_FEATURES: usize = 0x010;
+/// which 32 features `DEVICE_FEATURES` shows
+const DEVICE_FEATURES_SEL: usize = 0x014;
+/// features accepted by the driver, 32 of them at a time
+const DRIVER_FEATURES: usize = 0x020;
+/// which 32 features `DRIVER_FEATURES` sets
+const DRIVER_FEATURES_SEL: usize = 0x024;
+/// size of a page for `QUEUE_PFN` (legacy)
+const GUEST_PAGE_SIZE: usize = 0x028;
+/// queue the other queue registers apply to
+const QUEUE_SEL: usize = 0x030;
+/// largest size of the queue
+const QUEUE_NUM_MAX: usize = 0x034;
+/// size of the queue
+const QUEUE_NUM: usize = 0x038;
+/// alignment of the used ring (legacy)
+const QUEUE_ALIGN: usize = 0x03c;
+/// page number of the queue, 0 to stop using it (legacy)
+const QUEUE_PFN: usize = 0x040;
+/// whether the queue is ready (modern)
+const QUEUE_READY: usize = 0x044;
+/// tell the device a queue has new requests
+const QUEUE_NOTIFY: usize = 0x050;
+/// why the device raised an interrupt
+const INTERRUPT_STATUS: usize = 0x060;
+/// acknowledge the interrupt
+const INTERRUPT_ACK: usize = 0x064;
+/// status of the device
+const STATUS: usize = 0x070;
+/// address of the descriptor table (modern)
+const QUEUE_DESC_LOW: usize = 0x080;
+/// address of the available ring (modern)
+const QUEUE_DRIVER_LOW: usize = 0x090;
+/// address of the used ring (modern)
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+/// start of the configuration space of the device
+const CONFIG: usize = 0x100;
+
+/// STATUS: the driver has noticed the device
+const STATUS_ACKNOWLEDGE: u32 = 1;
+/// STATUS: the driver knows how to drive the device
+const STATUS_DRIVER: u32 = 2;
+/// STATUS: the driver is ready
+const STATUS_DRIVER_OK: u32 = 4;
+/// STATUS: feature negotiation is done
+const STATUS_FEATURES_OK: u32 = 8;
+
+/// the device follows the modern interface
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// number of descriptors of a [`VirtQueue`]
+pub const QUEUE_SIZE: usize = 16;
+
+/// Descriptor flag: the chain goes on at `next`
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// Descriptor flag: the buffer is written by the device
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// The registers of a virtio-mmio device
+pub struct VirtioMmio {
+    /// base address of the registers
+    base: usize,
+}
+
+impl VirtioMmio {
+    /// Create the transport of the device at `base`
+    pub const fn new(base: usize) -> Self {
+        Self { base }
+    }
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+    /// Whether there is a device of type `device_id` we can drive
+    pub fn probe(&self, device_id: u32) -> bool {
+        self.read(MAGIC) == MAGIC_VALUE
+            && matches!(self.read(VERSION), 1 | 2)
+            && self.read(DEVICE_ID) == device_id
+    }
+    fn is_legacy(&self) -> bool {
+        self.read(VERSION) == 1
+    }
+    /// Reset the device and accept those of `features` it offers. Returns
+    /// false if the device does not take them.
+    pub fn begin_init(&self, features: u64) -> bool {
+        self.write(STATUS, 0);
+        self.write(STATUS, STATUS_ACKNOWLEDGE);
+        self.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let mut device_features = 0;
+        for sel in 0..2 {
+            self.write(DEVICE_FEATURES_SEL, sel);
+            device_features |= (self.read(DEVICE_FEATURES) as u64) << (32 * sel);
+        }
+        let mut driver_features = device_features & features;
+        if !self.is_legacy() {
+            driver_features |= VIRTIO_F_VERSION_1;
+        }
+        for sel in 0..2 {
+            self.write(DRIVER_FEATURES_SEL, sel);
+            self.write(DRIVER_FEATURES, (driver_features >> (32 * sel)) as u32);
+        }
+        if self.is_legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            return true;
+        }
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        self.write(STATUS, status);
+        self.read(STATUS) & STATUS_FEATURES_OK != 0
+    }
+    /// Tell the device the driver is ready, once its queues are set up
+    pub fn finish_init(&self) {
+        self.write(STATUS, self.read(STATUS) | STATUS_DRIVER_OK);
+    }
+    /// Read the 64-bit field at `offset` of the configuration space
+    pub fn config_read_u64(&self, offset: usize) -> u64 {
+        let low = self.read(CONFIG + offset) as u64;
+        let high = self.read(CONFIG + offset + 4) as u64;
+        low | high << 32
+    }
+    /// Tell the device queue `queue` has new requests
+    pub fn notify(&self, queue: u32) {
+        self.write(QUEUE_NOTIFY, queue);
+    }
+    /// Acknowledge the interrupt of the device, returning its causes
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        status
+    }
+    /// Hand queue `queue` of `size` descriptors to the device
+    fn setup_queue(&self, queue: u32, size: usize, desc: usize, avail: usize, used: usize) {
+        self.write(QUEUE_SEL, queue);
+        self.write(QUEUE_NUM, size as u32);
+        if self.is_legacy() {
+            // the device finds the rings right after the descriptors, and
+            // the used ring on the next page
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (desc / PAGE_SIZE) as u32);
+        } else {
+            for (offset, addr) in [
+                (QUEUE_DESC_LOW, desc),
+                (QUEUE_DRIVER_LOW, avail),
+                (QUEUE_DEVICE_LOW, used),
+            ] {
+                self.write(offset, addr as u32);
+                self.write(offset + 4, (addr >> 32) as u32);
+            }
+            self.write(QUEUE_READY, 1);
+        }
+    }
+    /// Largest size of queue `queue`, 0 if there is no such queue
+    fn queue_max(&self, queue: u32) -> usize {
+        self.write(QUEUE_SEL, queue);
+        self.read(QUEUE_NUM_MAX) as usize
+    }
+}
+
+/// A buffer descriptor
+#[repr(C, align(16))]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// The ring of chains handed to the device
+#[repr(C, align(2))]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+/// A chain given back by the device
+#[repr(C)]
+struct UsedElem {
+    /// head of the chain
+    id: u32,
+    /// bytes written by the device
+    len: u32,
+}
+
+/// The ring of chains given back by the device
+#[repr(C, align(4))]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+/// A virtqueue, living in frames the device accesses directly
+pub struct VirtQueue {
+    /// the frames of the descriptors and the rings
+    _frames: Vec<FrameTracker>,
+    /// index of the queue in the device
+    index: u32,
+    desc: *mut Descriptor,
+    avail: *mut AvailRing,
+    used: *mut UsedRing,
+    /// first descriptor of the free list
+    free_head: u16,
+    /// number of free descriptors
+    num_free: usize,
+    /// next index of the available ring the driver writes
+    avail_idx: u16,
+    /// next index of the used ring the driver reads
+    last_used_idx: u16,
+}
+
+// the rings are only touched through `&mut self`
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Set up queue `index` of the device behind `transport`. Returns None if
+    /// the device has no such queue, or no memory is left.
+    pub fn new(transport: &VirtioMmio, index: u32) -> Option<Self> {
+        if transport.queue_max(index) < QUEUE_SIZE {
+            return None;
+        }
+        // the layout of the legacy interface, which the modern one accepts too
+        let desc_size = core::mem::size_of::<Descriptor>() * QUEUE_SIZE;
+        let avail_size = core::mem::size_of::<AvailRing>();
+        let used_offset = (desc_size + avail_size).next_multiple_of(PAGE_SIZE);
+        let size = used_offset + core::mem::size_of::<UsedRing>();
+        let frames = frame_alloc_contiguous(size.div_ceil(PAGE_SIZE))?;
+        let base = frames[0].addr();
+        let queue = Self {
+            _frames: frames,
+            index,
+            desc: base as *mut Descriptor,
+            avail: (base + desc_size) as *mut AvailRing,
+            used: (base + used_offset) as *mut UsedRing,
+            free_head: 0,
+            num_free: QUEUE_SIZE,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..QUEUE_SIZE - 1 {
+            unsafe {
+                (*queue.desc.add(i)).next = (i + 1) as u16;
+            }
+        }
+        transport.setup_queue(
+            index,
+            QUEUE_SIZE,
+            queue.desc as usize,
+            queue.avail as usize,
+            queue.used as usize,
+        );
+        Some(queue)
+    }
+    /// Index of the queue in the device
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+    /// Hand the device a chain of the buffers `inputs` it reads, followed by
+    /// the buffers `outputs` it writes, each given as its address and length.
+    /// Returns the head of the chain, or None if there are not enough free
+    /// descriptors.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must stay valid until the chain is given back.
+    pub unsafe fn add(
+        &mut self,
+        inputs: &[(usize, usize)],
+        outputs: &[(usize, usize)],
+    ) -> Option<u16> {
+        let count = inputs.len() + outputs.len();
+        if count == 0 || count > self.num_free {
+            return None;
+        }
+        let head = self.free_head;
+        let buffers = inputs
+            .iter()
+            .map(|buf| (buf, 0))
+            .chain(outputs.iter().map(|buf| (buf, VIRTQ_DESC_F_WRITE)));
+        for (i, (&(addr, len), flags)) in buffers.enumerate() {
+            let desc = &mut *self.desc.add(self.free_head as usize);
+            desc.addr = addr as u64;
+            desc.len = len as u32;
+            desc.flags = flags;
+            if i + 1 < count {
+                desc.flags |= VIRTQ_DESC_F_NEXT;
+            }
+            self.free_head = desc.next;
+        }
+        self.num_free -= count;
+        let avail = &mut *self.avail;
+        avail.ring[self.avail_idx as usize % QUEUE_SIZE] = head;
+        // the device must see the chain before the new index
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        (&mut avail.idx as *mut u16).write_volatile(self.avail_idx);
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+    /// Take the head of a chain the device has given back, if there is one.
+    /// Its descriptors stay in use until [`VirtQueue::recycle`].
+    pub fn pop_used(&mut self) -> Option<u16> {
+        fence(Ordering::SeqCst);
+        let used = unsafe { &*self.used };
+        let used_idx = unsafe { (&used.idx as *const u16).read_volatile() };
+        if used_idx == self.last_used_idx {
+            return None;
+        }
+        let elem = &used.ring[self.last_used_idx as usize % QUEUE_SIZE];
+        let head = unsafe { (&elem.id as *const u32).read_volatile() } as u16;
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        Some(head)
+    }
+    /// Put the descriptors of the chain at `head` back on the free list
+    pub fn recycle(&mut self, head: u16) {
+        let mut last = head as usize;
+        self.num_free += 1;
+        loop {
+            let desc = unsafe { &mut *self.desc.add(last) };
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            last = desc.next as usize;
+            self.num_free += 1;
+        }
+        unsafe {
+            (*self.desc.add(last)).next = self.free_head;
+        }
+        self.free_head = head;
+    }
+}
@@ -1,12 +1,27 @@
     .section .text.entry
     .globl _start
 _start:
-    la sp, boot_stack_top
+    # a0 = hartid, a1 = opaque, as passed by SBI on boot and by `hart_start`
+    # the kernel has neither a boot stack nor a processor for the harts beyond MAX_HARTS
+    li t0, {MAX_HARTS}
+    bgeu a0, t0, park
+    # keep hartid in tp, so that the kernel always knows where it is running
+    mv tp, a0
+    # every hart gets its own boot stack: sp = boot_stack_lower_bound + (hartid + 1) * BOOT_STACK_SIZE
+    addi t0, a0, 1
+    li t1, {BOOT_STACK_SIZE}
+    mul t0, t0, t1
+    la sp, boot_stack_lower_bound
+    add sp, sp, t0
     call rust_main
+park:
+    wfi
+    j park
 
     .section .bss.stack
     .globl boot_stack_lower_bound
 boot_stack_lower_bound:
-    .space 4096 * 16
+    # BOOT_STACK_SIZE for each of the MAX_HARTS harts
+    .space {BOOT_STACK_SIZE} * {MAX_HARTS}
     .globl boot_stack_top
-boot_stack_top:
\ No newline at end of file
+boot_stack_top:
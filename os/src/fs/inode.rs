@@ -0,0 +1,216 @@
+//! Files on the disk
+//!
+//! The disk holds an easy-fs file system, opened on first use. Its operations
+//! take the spin locks of easy-fs and may then sleep on the disk, so only one
+//! task at a time is let in, through `FS_LOCK`, and the others sleep on it
+//! instead of spinning. Before the first task runs, the boot hart is alone to
+//! use the disk and reads it without the lock, as it cannot sleep.
+//!
+//! Every operation changing the file system reaches the disk through the
+//! journal of easy-fs before it returns, so that a crash never leaves the
+//! disk inconsistent.
+
+use super::File;
+use crate::drivers::block::{BlockDevice, BLOCK_DEVICE};
+use crate::sync::{Mutex, MutexGuard, SpinLock};
+use crate::task::current_task;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::*;
+use easy_fs::{block_cache_sync_all, EasyFileSystem, Inode};
+use lazy_static::*;
+
+/// A file on the disk opened by a process
+pub struct OSInode {
+    readable: bool,
+    writable: bool,
+    inode: Arc<Inode>,
+    /// where the next read or write starts
+    offset: Mutex<usize>,
+}
+
+/// serializes the accesses to easy-fs
+static FS_LOCK: Mutex<()> = Mutex::new(());
+
+/// number of the `OSInode`s open on each inode, by inode number
+static OPEN_INODES: SpinLock<BTreeMap<u32, usize>> = SpinLock::new(BTreeMap::new());
+
+/// Lock `FS_LOCK`, unless no task runs yet
+fn lock_fs() -> Option<MutexGuard<'static, ()>> {
+    current_task().map(|_| FS_LOCK.lock())
+}
+
+lazy_static! {
+    /// The root directory of the disk, if the disk holds a file system
+    static ref ROOT_INODE: Option<Arc<Inode>> = {
+        let block_device: Arc<dyn BlockDevice> = BLOCK_DEVICE.clone()?;
+        let efs = EasyFileSystem::open(block_device)?;
+        Some(Arc::new(EasyFileSystem::root_inode(&efs)))
+    };
+}
+
+bitflags! {
+    /// Flags of `sys_open`, which opens the file read only if there is neither
+    /// `WRONLY` nor `RDWR`
+    pub struct OpenFlags: u32 {
+        /// write only
+        const WRONLY = 1 << 0;
+        /// read and write
+        const RDWR = 1 << 1;
+        /// create the file if it does not exist, or clear it if it does
+        const CREATE = 1 << 9;
+        /// clear the file
+        const TRUNC = 1 << 10;
+        /// close the descriptor on `sys_exec`
+        const CLOEXEC = 1 << 19;
+    }
+}
+
+impl OpenFlags {
+    /// Whether the file is opened for reading and for writing
+    pub fn read_write(&self) -> (bool, bool) {
+        if self.contains(Self::WRONLY) {
+            (false, true)
+        } else if self.contains(Self::RDWR) {
+            (true, true)
+        } else {
+            (true, false)
+        }
+    }
+}
+
+impl OSInode {
+    /// Open `inode` with an offset of 0
+    pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
+        *OPEN_INODES.lock().entry(inode.inode_id()).or_insert(0) += 1;
+        Self {
+            readable,
+            writable,
+            inode,
+            offset: Mutex::new(0),
+        }
+    }
+    /// Read from `offset` into `buf`, leaving the offset of the file as is
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let _fs = lock_fs();
+        self.inode.read_at(offset, buf)
+    }
+}
+
+/// Open the file at `path` from the root directory with `flags`. Returns None
+/// if there is no such file and it cannot be created, it is a directory to be
+/// written, or there is no file system.
+pub fn open_file(path: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+    let _fs = lock_fs();
+    let root_inode = ROOT_INODE.as_ref()?;
+    let (readable, writable) = flags.read_write();
+    let inode = match root_inode.find_path(path) {
+        Some(inode) => {
+            if flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNC) && !inode.is_dir() {
+                inode.clear();
+            }
+            inode
+        }
+        None if flags.contains(OpenFlags::CREATE) => {
+            let (dir, name) = split_path(path);
+            root_inode.find_path(dir)?.create(name)?
+        }
+        None => return None,
+    };
+    if writable && inode.is_dir() {
+        return None;
+    }
+    Some(Arc::new(OSInode::new(readable, writable, inode)))
+}
+
+/// Split `path` into the path of its directory and its name
+fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Remove the entry at `path`. Returns false if there is no such entry, it is
+/// a directory that is not empty, or the last link to a file still open.
+pub fn unlink_file(path: &str) -> bool {
+    let _fs = lock_fs();
+    let Some(root_inode) = ROOT_INODE.as_ref() else {
+        return false;
+    };
+    let (dir, name) = split_path(path);
+    let (Some(dir), Some(inode)) = (root_inode.find_path(dir), root_inode.find_path(path)) else {
+        return false;
+    };
+    // an open file keeps its data, which would be freed along with the link
+    if inode.nlink() == 1 && OPEN_INODES.lock().contains_key(&inode.inode_id()) {
+        return false;
+    }
+    dir.unlink(name)
+}
+
+/// Add the entry `new_path` for the file at `old_path`. Returns false if there
+/// is no such file, or the entry cannot be added.
+pub fn link_file(old_path: &str, new_path: &str) -> bool {
+    let _fs = lock_fs();
+    let Some(root_inode) = ROOT_INODE.as_ref() else {
+        return false;
+    };
+    let (dir, name) = split_path(new_path);
+    let (Some(dir), Some(inode)) = (root_inode.find_path(dir), root_inode.find_path(old_path))
+    else {
+        return false;
+    };
+    dir.link(name, &inode)
+}
+
+/// List the names of the entries of the directory at `path`, or None if there
+/// is no such directory
+pub fn list_dir(path: &str) -> Option<Vec<String>> {
+    let _fs = lock_fs();
+    let dir = ROOT_INODE.as_ref()?.find_path(path)?;
+    if !dir.is_dir() {
+        return None;
+    }
+    Some(dir.ls())
+}
+
+/// Write every modified block of the disk back
+pub fn sync_all() {
+    let _fs = lock_fs();
+    block_cache_sync_all();
+}
+
+impl File for OSInode {
+    fn readable(&self) -> bool {
+        self.readable
+    }
+    fn writable(&self) -> bool {
+        self.writable
+    }
+    fn read(&self, buf: &mut [u8]) -> isize {
+        let mut offset = self.offset.lock();
+        let _fs = lock_fs();
+        let len = self.inode.read_at(*offset, buf);
+        *offset += len;
+        len as isize
+    }
+    fn write(&self, buf: &[u8]) -> isize {
+        let mut offset = self.offset.lock();
+        let _fs = lock_fs();
+        let len = self.inode.write_at(*offset, buf);
+        *offset += len;
+        len as isize
+    }
+}
+
+impl Drop for OSInode {
+    fn drop(&mut self) {
+        let mut open_inodes = OPEN_INODES.lock();
+        let inode_id = self.inode.inode_id();
+        let count = open_inodes.get_mut(&inode_id).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            open_inodes.remove(&inode_id);
+        }
+    }
+}
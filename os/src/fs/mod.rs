@@ -0,0 +1,61 @@
+//! File system in rCore
+//!
+//! Everything a process reads or writes through a file descriptor is a
+//! [`File`]: the terminal in [`tty`], which every process gets as its
+//! descriptors 0, 1 and 2, the ends of the pipes in [`pipe`], and the files
+//! of the easy-fs file system on the disk in [`inode`].
+
+mod inode;
+mod pipe;
+mod tty;
+
+use alloc::sync::Arc;
+
+/// Returned by a read interrupted by a signal
+pub const EINTR: isize = -4;
+/// Returned by a write to a pipe whose read ends are all closed
+pub const EPIPE: isize = -32;
+
+/// A file a process can read or write through a descriptor
+pub trait File: Send + Sync {
+    /// Whether the file can be read from
+    fn readable(&self) -> bool;
+    /// Whether the file can be written to
+    fn writable(&self) -> bool;
+    /// Read into `buf`, returning the number of bytes read, which is 0 only at
+    /// the end of the file, or a negative error code. May block until some
+    /// data is there.
+    fn read(&self, buf: &mut [u8]) -> isize;
+    /// Write `buf`, returning the number of bytes written, or a negative
+    /// error code if nothing could be
+    fn write(&self, buf: &[u8]) -> isize;
+    /// Handle device-specific `request` with argument `arg`, returning -1 if
+    /// the file does not support it
+    fn ioctl(&self, _request: usize, _arg: usize) -> isize {
+        -1
+    }
+}
+
+/// An entry of the fd table of a process. Duplicated descriptors share the
+/// same file, and so its offset.
+#[derive(Clone)]
+pub struct FileDescriptor {
+    /// The open file
+    pub file: Arc<dyn File>,
+    /// Whether the descriptor is closed by `sys_exec`
+    pub cloexec: bool,
+}
+
+impl FileDescriptor {
+    /// Create a descriptor of `file`, kept open across `sys_exec`
+    pub fn new(file: Arc<dyn File>) -> Self {
+        Self {
+            file,
+            cloexec: false,
+        }
+    }
+}
+
+pub use inode::{link_file, list_dir, open_file, sync_all, unlink_file, OSInode, OpenFlags};
+pub use pipe::{make_pipe, Pipe};
+pub use tty::{Termios, Tty, TTY};
@@ -0,0 +1,204 @@
+//! Pipes, a ring buffer with a read end and a write end
+//!
+//! A reader sleeps while the buffer is empty and a writer while it is full.
+//! Once all write ends are closed, readers drain the buffer and then get the
+//! end of file. Once all read ends are closed, a writer gets SIGPIPE, and its
+//! write fails with [`EPIPE`] unless some bytes were written before.
+//!
+//! A signal wakes up a sleeping reader or writer, whose call then fails with
+//! [`EINTR`], or for a writer returns the bytes written so far if any.
+
+use super::{File, EINTR, EPIPE};
+use crate::sync::SpinLock;
+use crate::task::{
+    block_current_interruptible_and_run_next, current_process_ended, current_signal_pending,
+    current_task, send_signal, wakeup_task, SignalFlags, TaskControlBlock,
+};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// One end of a pipe
+pub struct Pipe {
+    readable: bool,
+    writable: bool,
+    buffer: Arc<SpinLock<PipeRingBuffer>>,
+}
+
+impl Pipe {
+    /// Create the read end of `buffer`
+    fn read_end_with_buffer(buffer: Arc<SpinLock<PipeRingBuffer>>) -> Self {
+        buffer.lock().readers += 1;
+        Self {
+            readable: true,
+            writable: false,
+            buffer,
+        }
+    }
+    /// Create the write end of `buffer`
+    fn write_end_with_buffer(buffer: Arc<SpinLock<PipeRingBuffer>>) -> Self {
+        buffer.lock().writers += 1;
+        Self {
+            readable: false,
+            writable: true,
+            buffer,
+        }
+    }
+}
+
+const RING_BUFFER_SIZE: usize = 512;
+
+/// The buffer shared by both ends of a pipe
+struct PipeRingBuffer {
+    arr: [u8; RING_BUFFER_SIZE],
+    head: usize,
+    tail: usize,
+    /// number of bytes in the buffer, telling a full buffer from an empty one
+    len: usize,
+    /// number of open read ends
+    readers: usize,
+    /// number of open write ends
+    writers: usize,
+    /// tasks waiting for data
+    read_waiters: VecDeque<Arc<TaskControlBlock>>,
+    /// tasks waiting for room
+    write_waiters: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl PipeRingBuffer {
+    fn new() -> Self {
+        Self {
+            arr: [0; RING_BUFFER_SIZE],
+            head: 0,
+            tail: 0,
+            len: 0,
+            readers: 0,
+            writers: 0,
+            read_waiters: VecDeque::new(),
+            write_waiters: VecDeque::new(),
+        }
+    }
+    /// Take as many bytes as possible into `buf`, returning how many
+    fn read_bytes(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.len);
+        for byte in buf[..len].iter_mut() {
+            *byte = self.arr[self.head];
+            self.head = (self.head + 1) % RING_BUFFER_SIZE;
+        }
+        self.len -= len;
+        len
+    }
+    /// Put as many bytes of `buf` as possible, returning how many
+    fn write_bytes(&mut self, buf: &[u8]) -> usize {
+        let len = buf.len().min(RING_BUFFER_SIZE - self.len);
+        for byte in buf[..len].iter() {
+            self.arr[self.tail] = *byte;
+            self.tail = (self.tail + 1) % RING_BUFFER_SIZE;
+        }
+        self.len += len;
+        len
+    }
+}
+
+/// Create a pipe, returning its read end and its write end
+pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
+    let buffer = Arc::new(SpinLock::new(PipeRingBuffer::new()));
+    let read_end = Arc::new(Pipe::read_end_with_buffer(buffer.clone()));
+    let write_end = Arc::new(Pipe::write_end_with_buffer(buffer));
+    (read_end, write_end)
+}
+
+impl File for Pipe {
+    fn readable(&self) -> bool {
+        self.readable
+    }
+    fn writable(&self) -> bool {
+        self.writable
+    }
+    fn read(&self, buf: &mut [u8]) -> isize {
+        assert!(self.readable);
+        if buf.is_empty() {
+            return 0;
+        }
+        let task = current_task().unwrap();
+        loop {
+            let mut ring_buffer = self.buffer.lock();
+            let len = ring_buffer.read_bytes(buf);
+            if len > 0 {
+                let waiters = core::mem::take(&mut ring_buffer.write_waiters);
+                drop(ring_buffer);
+                waiters.into_iter().for_each(wakeup_task);
+                return len as isize;
+            }
+            if ring_buffer.writers == 0 {
+                return 0;
+            }
+            if current_process_ended() || current_signal_pending() {
+                // woken up for the signal or to exit, not for data
+                ring_buffer.read_waiters.retain(|t| !Arc::ptr_eq(t, &task));
+                return EINTR;
+            }
+            ring_buffer.read_waiters.push_back(task.clone());
+            block_current_interruptible_and_run_next(ring_buffer);
+        }
+    }
+    fn write(&self, buf: &[u8]) -> isize {
+        assert!(self.writable);
+        let task = current_task().unwrap();
+        let mut written = 0;
+        loop {
+            let mut ring_buffer = self.buffer.lock();
+            if ring_buffer.readers == 0 {
+                drop(ring_buffer);
+                send_signal(&task, SignalFlags::SIGPIPE);
+                return if written == 0 {
+                    EPIPE
+                } else {
+                    written as isize
+                };
+            }
+            written += ring_buffer.write_bytes(&buf[written..]);
+            let waiters = core::mem::take(&mut ring_buffer.read_waiters);
+            if written == buf.len() {
+                drop(ring_buffer);
+                waiters.into_iter().for_each(wakeup_task);
+                return written as isize;
+            }
+            if current_process_ended() || current_signal_pending() {
+                ring_buffer.write_waiters.retain(|t| !Arc::ptr_eq(t, &task));
+                drop(ring_buffer);
+                waiters.into_iter().for_each(wakeup_task);
+                return if written == 0 {
+                    EINTR
+                } else {
+                    written as isize
+                };
+            }
+            ring_buffer.write_waiters.push_back(task.clone());
+            // the readers cannot miss the data, as they need the lock we still
+            // hold to go to sleep
+            waiters.into_iter().for_each(wakeup_task);
+            block_current_interruptible_and_run_next(ring_buffer);
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        let mut ring_buffer = self.buffer.lock();
+        let mut waiters = VecDeque::new();
+        if self.readable {
+            ring_buffer.readers -= 1;
+            if ring_buffer.readers == 0 {
+                waiters = core::mem::take(&mut ring_buffer.write_waiters);
+            }
+        }
+        if self.writable {
+            ring_buffer.writers -= 1;
+            if ring_buffer.writers == 0 {
+                waiters = core::mem::take(&mut ring_buffer.read_waiters);
+            }
+        }
+        drop(ring_buffer);
+        waiters.into_iter().for_each(wakeup_task);
+    }
+}
@@ -0,0 +1,281 @@
+//! The terminal on the console, with a line discipline
+//!
+//! Every process gets the terminal as its descriptors 0, 1 and 2. Input goes
+//! through the line discipline before it reaches readers:
+//!
+//! - In canonical mode, input is echoed and edited a line at a time, with
+//!   backspace erasing the last byte. A line is only readable once ended by
+//!   Enter, or by Ctrl-D, which on an empty line makes a read return 0.
+//! - In raw mode, input is passed on as is and without echo.
+//!
+//! Unless turned off along with canonical mode, Ctrl-C sends SIGINT to the
+//! foreground process, which is the one set by `TIOCSPGRP`, or else the last
+//! one that read from the terminal.
+//!
+//! On the UART, input is taken by the line discipline as soon as it arrives,
+//! so Ctrl-C works even when nobody reads. On SBI, it is only polled by
+//! readers.
+
+use super::{File, EINTR};
+use crate::console::{self, backend, ConsoleBackend};
+use crate::sync::SpinLock;
+use crate::task::{
+    block_current_interruptible_and_run_next, current_process, current_process_ended,
+    current_signal_pending, current_task, pid2process, send_signal, suspend_current_and_run_next,
+    wakeup_task, SignalFlags, TaskControlBlock,
+};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+/// `Termios::lflag`: Ctrl-C sends SIGINT
+pub const ISIG: u32 = 0o1;
+/// `Termios::lflag`: canonical mode
+pub const ICANON: u32 = 0o2;
+/// `Termios::lflag`: echo input
+pub const ECHO: u32 = 0o10;
+
+/// ioctl to get the [`Termios`] of the terminal
+pub const TCGETS: usize = 0x5401;
+/// ioctl to set the [`Termios`] of the terminal
+pub const TCSETS: usize = 0x5402;
+/// ioctl to get the pid of the foreground process
+pub const TIOCGPGRP: usize = 0x540f;
+/// ioctl to set the pid of the foreground process
+pub const TIOCSPGRP: usize = 0x5410;
+
+/// Settings of the terminal, as exchanged with `TCGETS` and `TCSETS`. Only
+/// the local modes are supported.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Termios {
+    /// local modes, made of [`ISIG`], [`ICANON`] and [`ECHO`]
+    pub lflag: u32,
+}
+
+/// Ctrl-C
+const INTR: u8 = 0x03;
+/// Ctrl-D
+const EOF: u8 = 0x04;
+/// Backspace
+const BS: u8 = 0x08;
+/// what most terminals send for Backspace
+const DEL: u8 = 0x7f;
+
+/// The terminal
+pub struct Tty {
+    inner: SpinLock<TtyInner>,
+}
+
+struct TtyInner {
+    /// local modes
+    lflag: u32,
+    /// line being edited in canonical mode
+    line: Vec<u8>,
+    /// input ready to be read
+    ready: VecDeque<u8>,
+    /// whether Ctrl-D was typed on an empty line, which the next read reports
+    eof: bool,
+    /// foreground process set by `TIOCSPGRP`
+    foreground: Option<usize>,
+    /// process that last read from the terminal
+    last_reader: Option<usize>,
+    /// tasks waiting for input
+    read_waiters: VecDeque<Arc<TaskControlBlock>>,
+}
+
+lazy_static! {
+    /// The terminal on the console
+    pub static ref TTY: Arc<Tty> = Arc::new(Tty {
+        inner: SpinLock::new(TtyInner {
+            lflag: ISIG | ICANON | ECHO,
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            eof: false,
+            foreground: None,
+            last_reader: None,
+            read_waiters: VecDeque::new(),
+        }),
+    });
+}
+
+impl TtyInner {
+    fn echo(&self, bytes: &[u8]) {
+        if self.lflag & ECHO != 0 {
+            console::write_bytes(bytes);
+        }
+    }
+    /// Run `byte` through the line discipline, returning the process to send
+    /// SIGINT to, if it is Ctrl-C
+    fn receive(&mut self, byte: u8) -> Option<usize> {
+        if self.lflag & ISIG != 0 && byte == INTR {
+            self.line.clear();
+            self.echo(b"^C\n");
+            return self.foreground.or(self.last_reader);
+        }
+        if self.lflag & ICANON == 0 {
+            self.ready.push_back(byte);
+            self.echo(&[byte]);
+            return None;
+        }
+        match byte {
+            BS | DEL => {
+                if self.line.pop().is_some() {
+                    self.echo(b"\x08 \x08");
+                }
+            }
+            EOF => {
+                if self.line.is_empty() {
+                    self.eof = true;
+                } else {
+                    self.ready.extend(self.line.drain(..));
+                }
+            }
+            b'\r' | b'\n' => {
+                self.echo(b"\n");
+                self.line.push(b'\n');
+                self.ready.extend(self.line.drain(..));
+            }
+            _ => {
+                self.echo(&[byte]);
+                self.line.push(byte);
+            }
+        }
+        None
+    }
+}
+
+impl Tty {
+    /// Take the input of the console through the line discipline, and wake
+    /// readers up if they can go on
+    pub fn receive_input(&self) {
+        let mut buf = [0u8; 32];
+        let mut inner = self.inner.lock();
+        let mut interrupted = None;
+        loop {
+            let len = console::read_bytes(&mut buf);
+            if len == 0 {
+                break;
+            }
+            for byte in &buf[..len] {
+                if let Some(pid) = inner.receive(*byte) {
+                    interrupted = Some(pid);
+                }
+            }
+        }
+        // readers of the interrupted process must return to take the signal
+        let waiters = if !inner.ready.is_empty() || inner.eof || interrupted.is_some() {
+            core::mem::take(&mut inner.read_waiters)
+        } else {
+            VecDeque::new()
+        };
+        drop(inner);
+        if let Some(task) = interrupted.and_then(pid2process).and_then(|process| {
+            process
+                .inner_exclusive_access()
+                .tasks
+                .first()
+                .cloned()
+                .flatten()
+        }) {
+            send_signal(&task, SignalFlags::SIGINT);
+        }
+        waiters.into_iter().for_each(wakeup_task);
+    }
+}
+
+impl File for Tty {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        true
+    }
+    /// Wait for a line in canonical mode, or for any input in raw mode. A
+    /// read interrupted by a signal fails with [`EINTR`].
+    fn read(&self, buf: &mut [u8]) -> isize {
+        if buf.is_empty() {
+            return 0;
+        }
+        let task = current_task().unwrap();
+        let pid = current_process().pid.0;
+        loop {
+            self.receive_input();
+            let mut inner = self.inner.lock();
+            inner.last_reader = Some(pid);
+            if !inner.ready.is_empty() {
+                let canonical = inner.lflag & ICANON != 0;
+                let mut len = 0;
+                while len < buf.len() {
+                    let Some(byte) = inner.ready.pop_front() else {
+                        break;
+                    };
+                    buf[len] = byte;
+                    len += 1;
+                    // one line at a time
+                    if canonical && byte == b'\n' {
+                        break;
+                    }
+                }
+                return len as isize;
+            }
+            if inner.eof {
+                inner.eof = false;
+                return 0;
+            }
+            if current_process_ended() || current_signal_pending() {
+                inner.read_waiters.retain(|t| !Arc::ptr_eq(t, &task));
+                return EINTR;
+            }
+            if backend() == ConsoleBackend::Uart {
+                inner.read_waiters.push_back(task.clone());
+                block_current_interruptible_and_run_next(inner);
+            } else {
+                // nobody tells us about input on SBI, poll again later
+                drop(inner);
+                suspend_current_and_run_next();
+            }
+        }
+    }
+    fn write(&self, buf: &[u8]) -> isize {
+        console::write_bytes(buf);
+        buf.len() as isize
+    }
+    /// Every request takes a pointer to its argument, which must not be null
+    fn ioctl(&self, request: usize, arg: usize) -> isize {
+        if arg == 0 {
+            return -1;
+        }
+        let mut inner = self.inner.lock();
+        match request {
+            TCGETS => unsafe {
+                *(arg as *mut Termios) = Termios { lflag: inner.lflag };
+            },
+            TCSETS => {
+                let termios = unsafe { *(arg as *const Termios) };
+                if termios.lflag & !(ISIG | ICANON | ECHO) != 0 {
+                    return -1;
+                }
+                if inner.lflag & ICANON != 0 && termios.lflag & ICANON == 0 {
+                    // what was typed so far becomes readable
+                    let line = core::mem::take(&mut inner.line);
+                    inner.ready.extend(line);
+                }
+                inner.lflag = termios.lflag;
+            }
+            TIOCGPGRP => unsafe {
+                *(arg as *mut usize) = inner.foreground.or(inner.last_reader).unwrap_or(0);
+            },
+            TIOCSPGRP => {
+                let pid = unsafe { *(arg as *const usize) };
+                if pid2process(pid).is_none() {
+                    return -1;
+                }
+                inner.foreground = Some(pid);
+            }
+            _ => return -1,
+        }
+        0
+    }
+}
@@ -1,98 +1,311 @@
 //! Loading user applications into memory
 //!
-//! For chapter 3, user applications are simply part of the data included in the
-//! kernel binary, so we only need to copy them to the space allocated for each
-//! app to load them. We also allocate fixed spaces for each task's
-//! [`KernelStack`] and [`UserStack`].
+//! For chapter 3, every app is an ELF file linked at the space allocated for
+//! it. We only need to copy its loadable segments there to load it, which is
+//! done afresh whenever a process starts running the app. The stacks of the
+//! threads running the apps come from the pools in `task::id`.
+//!
+//! The apps are the files of the [`APP_DIR`] directory on the disk, which
+//! `easy-fs-fuse` packs into the image. With the `embedded-apps` feature, they
+//! are instead part of the data included in the kernel binary by `build.rs`,
+//! named by its `_app_names` table. Either way, they are linked at the spaces
+//! given by the order of their names, except for the shell [`INIT_APP`], which
+//! is linked at the last space whatever the other apps are. Apps that find no
+//! space left are left out.
+//!
+//! Starting an app takes two steps: [`read_app`] reads its segments into an
+//! [`AppImage`], which may sleep on the disk, and [`AppImage::load`] then
+//! copies them to the space of the app.
+//!
+//! An app may also have a `PT_TLS` segment, the template of the thread-local
+//! storage block every thread of the app gets a copy of.
 
 use crate::config::*;
-use crate::trap::TrapContext;
+use crate::mm::{frame_alloc_contiguous, FrameTracker};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::asm;
+use lazy_static::*;
+use xmas_elf::program::Type;
+use xmas_elf::ElfFile;
 
-#[repr(align(4096))]
-#[derive(Copy, Clone)]
-struct KernelStack {
-    data: [u8; KERNEL_STACK_SIZE],
+#[cfg(not(feature = "embedded-apps"))]
+use crate::fs::{list_dir, open_file, OSInode, OpenFlags};
+#[cfg(not(feature = "embedded-apps"))]
+use alloc::{format, sync::Arc};
+
+/// Get base address of app i.
+pub fn get_base_i(app_id: usize) -> usize {
+    let space = if APP_NAMES[app_id] == INIT_APP {
+        MAX_APP_NUM - 1
+    } else {
+        app_id
+    };
+    APP_BASE_ADDRESS + space * APP_SIZE_LIMIT
 }
 
-#[repr(align(4096))]
-#[derive(Copy, Clone)]
-struct UserStack {
-    data: [u8; USER_STACK_SIZE],
+/// Get the names of the apps in the kernel binary
+#[cfg(feature = "embedded-apps")]
+fn read_app_names() -> Vec<String> {
+    extern "C" {
+        fn _num_app();
+        fn _app_names();
+    }
+    let num_app = unsafe { (_num_app as usize as *const usize).read_volatile() };
+    let mut start = _app_names as usize as *const u8;
+    let mut names = Vec::new();
+    for _ in 0..num_app {
+        unsafe {
+            let mut end = start;
+            while end.read_volatile() != b'\0' {
+                end = end.add(1);
+            }
+            let slice = core::slice::from_raw_parts(start, end as usize - start as usize);
+            names.push(String::from(core::str::from_utf8(slice).unwrap()));
+            start = end.add(1);
+        }
+    }
+    names
 }
 
-static KERNEL_STACK: [KernelStack; MAX_APP_NUM] = [KernelStack {
-    data: [0; KERNEL_STACK_SIZE],
-}; MAX_APP_NUM];
+/// Get the names of the apps on the disk
+#[cfg(not(feature = "embedded-apps"))]
+fn read_app_names() -> Vec<String> {
+    list_dir(APP_DIR).unwrap_or_default()
+}
 
-static USER_STACK: [UserStack; MAX_APP_NUM] = [UserStack {
-    data: [0; USER_STACK_SIZE],
-}; MAX_APP_NUM];
+/// The ELF file of an app
+#[cfg(feature = "embedded-apps")]
+type AppFile = &'static [u8];
+/// The ELF file of an app
+#[cfg(not(feature = "embedded-apps"))]
+type AppFile = Arc<OSInode>;
 
-impl KernelStack {
-    fn get_sp(&self) -> usize {
-        self.data.as_ptr() as usize + KERNEL_STACK_SIZE
+/// Get the ELF file of app `app_id` in the kernel binary
+#[cfg(feature = "embedded-apps")]
+fn open_app_file(app_id: usize) -> Option<AppFile> {
+    extern "C" {
+        fn _num_app();
     }
-    pub fn push_context(&self, trap_cx: TrapContext) -> usize {
-        let trap_cx_ptr = (self.get_sp() - core::mem::size_of::<TrapContext>()) as *mut TrapContext;
-        unsafe {
-            *trap_cx_ptr = trap_cx;
-        }
-        trap_cx_ptr as usize
+    let num_app_ptr = _num_app as usize as *const usize;
+    let num_app = unsafe { num_app_ptr.read_volatile() };
+    let app_start = unsafe { core::slice::from_raw_parts(num_app_ptr.add(1), num_app + 1) };
+    // the position of the app in the tables of `build.rs`
+    let i = read_app_names()
+        .iter()
+        .position(|name| *name == APP_NAMES[app_id])?;
+    unsafe {
+        Some(core::slice::from_raw_parts(
+            app_start[i] as *const u8,
+            app_start[i + 1] - app_start[i],
+        ))
     }
 }
 
-impl UserStack {
-    fn get_sp(&self) -> usize {
-        self.data.as_ptr() as usize + USER_STACK_SIZE
+/// Open the ELF file of app `app_id` on the disk
+#[cfg(not(feature = "embedded-apps"))]
+fn open_app_file(app_id: usize) -> Option<AppFile> {
+    open_file(
+        &format!("{}/{}", APP_DIR, APP_NAMES[app_id]),
+        OpenFlags::empty(),
+    )
+}
+
+/// Read `file` from `offset` into `buf`, returning how many bytes were read
+fn read_app_file(file: &AppFile, offset: usize, buf: &mut [u8]) -> usize {
+    #[cfg(feature = "embedded-apps")]
+    {
+        let src = file.get(offset..).unwrap_or_default();
+        let len = src.len().min(buf.len());
+        buf[..len].copy_from_slice(&src[..len]);
+        len
     }
+    #[cfg(not(feature = "embedded-apps"))]
+    file.read_at(offset, buf)
 }
 
-/// Get base address of app i.
-fn get_base_i(app_id: usize) -> usize {
-    APP_BASE_ADDRESS + app_id * APP_SIZE_LIMIT
+/// Order the apps called `names` by app id: the apps in the order of their
+/// names, as many as there is room for, then [`INIT_APP`], which has the last
+/// space to itself
+fn arrange_apps(mut names: Vec<String>) -> Vec<String> {
+    names.sort();
+    let init_app = names
+        .iter()
+        .position(|name| name == INIT_APP)
+        .map(|i| names.remove(i));
+    let fitting = names.len().min(MAX_APP_NUM - 1);
+    for name in names.drain(fitting..) {
+        println!("[kernel] No space left for app {}, skipped.", name);
+    }
+    names.extend(init_app);
+    names
+}
+
+lazy_static! {
+    /// Names of the apps, indexed by app id
+    static ref APP_NAMES: Vec<String> = arrange_apps(read_app_names());
+    /// Names of the apps, each of them followed by a NUL byte
+    static ref APP_NAMES_TABLE: Vec<u8> = APP_NAMES
+        .iter()
+        .flat_map(|name| name.bytes().chain([0]))
+        .collect();
 }
 
 /// Get the total number of applications.
 pub fn get_num_app() -> usize {
-    extern "C" {
-        fn _num_app();
+    APP_NAMES.len()
+}
+
+/// Get the name of app `app_id`
+pub fn get_app_name(app_id: usize) -> &'static str {
+    &APP_NAMES[app_id]
+}
+
+/// Get the id of the app called `name`
+pub fn get_app_id_by_name(name: &[u8]) -> Option<usize> {
+    APP_NAMES.iter().position(|app| app.as_bytes() == name)
+}
+
+/// Get the names of all apps, each of them followed by a NUL byte
+pub fn get_app_names_table() -> &'static [u8] {
+    &APP_NAMES_TABLE
+}
+
+/// Print the names of all apps
+pub fn list_apps() {
+    println!("/**** APPS ****");
+    for app in APP_NAMES.iter() {
+        println!("{}", app);
     }
-    unsafe { (_num_app as usize as *const usize).read_volatile() }
+    println!("**************/");
+}
+
+/// The loadable segments of an app, laid out as in the space of the app
+pub struct AppImage {
+    app_id: usize,
+    /// [`APP_SIZE_LIMIT`] bytes of memory holding the image
+    frames: Vec<FrameTracker>,
+    entry: usize,
+    tls: Option<TlsTemplate>,
 }
 
-/// Load nth user app at
+/// Read the ELF file of app `app_id` into an image of its space. Returns None
+/// if the file cannot be read, is not an ELF file, or has segments outside
 /// [APP_BASE_ADDRESS + n * APP_SIZE_LIMIT, APP_BASE_ADDRESS + (n+1) * APP_SIZE_LIMIT).
-pub fn load_apps() {
-    extern "C" {
-        fn _num_app();
+pub fn read_app(app_id: usize) -> Option<AppImage> {
+    let file = open_app_file(app_id)?;
+    // the ELF header, and the program headers right after it
+    let mut head = vec![0u8; PAGE_SIZE];
+    let len = read_app_file(&file, 0, &mut head);
+    let elf = ElfFile::new(&head[..len]).ok()?;
+    let ph_end = elf.header.pt2.ph_offset() as usize
+        + elf.header.pt2.ph_count() as usize * elf.header.pt2.ph_entry_size() as usize;
+    if ph_end > len {
+        return None;
     }
-    let num_app_ptr = _num_app as usize as *const usize;
-    let num_app = get_num_app();
-    let app_start = unsafe { core::slice::from_raw_parts(num_app_ptr.add(1), num_app + 1) };
-    // clear i-cache first
-    unsafe {
-        asm!("fence.i");
+    let frames = frame_alloc_contiguous(APP_SIZE_LIMIT / PAGE_SIZE)?;
+    let image =
+        unsafe { core::slice::from_raw_parts_mut(frames[0].addr() as *mut u8, APP_SIZE_LIMIT) };
+    let base_i = get_base_i(app_id);
+    let mut tls = None;
+    for ph in elf.program_iter() {
+        match ph.get_type() {
+            Ok(Type::Load) => {
+                let start = ph.virtual_addr() as usize;
+                let end = start + ph.mem_size() as usize;
+                let file_size = ph.file_size() as usize;
+                if start < base_i || end > base_i + APP_SIZE_LIMIT || file_size > end - start {
+                    return None;
+                }
+                // the rest of the segment stays zero
+                let dst = &mut image[start - base_i..start - base_i + file_size];
+                if read_app_file(&file, ph.offset() as usize, dst) != file_size {
+                    return None;
+                }
+            }
+            Ok(Type::Tls) => {
+                tls = Some(TlsTemplate {
+                    vaddr: ph.virtual_addr() as usize,
+                    file_size: ph.file_size() as usize,
+                    mem_size: ph.mem_size() as usize,
+                    align: (ph.align() as usize).max(1),
+                });
+            }
+            _ => {}
+        }
+    }
+    Some(AppImage {
+        app_id,
+        frames,
+        entry: elf.header.pt2.entry_point() as usize,
+        tls,
+    })
+}
+
+impl AppImage {
+    /// Copy the image to the space of the app, overwriting whatever a
+    /// previous run of it left there
+    pub fn load(&self) {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.frames[0].addr() as *const u8,
+                get_base_i(self.app_id) as *mut u8,
+                APP_SIZE_LIMIT,
+            );
+        }
+        // clear i-cache, as the app may have been run before
+        unsafe {
+            asm!("fence.i");
+        }
     }
-    // load apps
-    for i in 0..num_app {
-        let base_i = get_base_i(i);
-        // clear region
-        (base_i..base_i + APP_SIZE_LIMIT)
-            .for_each(|addr| unsafe { (addr as *mut u8).write_volatile(0) });
-        // load app from data section to memory
-        let src = unsafe {
-            core::slice::from_raw_parts(app_start[i] as *const u8, app_start[i + 1] - app_start[i])
-        };
-        let dst = unsafe { core::slice::from_raw_parts_mut(base_i as *mut u8, src.len()) };
-        dst.copy_from_slice(src);
+    /// Entry point of the app
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+    /// Thread-local storage template of the app, if it has one
+    pub fn tls(&self) -> Option<TlsTemplate> {
+        self.tls
     }
 }
 
-/// get app info with entry and sp and save `TrapContext` in kernel stack
-pub fn init_app_cx(app_id: usize) -> usize {
-    KERNEL_STACK[app_id].push_context(TrapContext::app_init_context(
-        get_base_i(app_id),
-        USER_STACK[app_id].get_sp(),
-    ))
+/// The `PT_TLS` segment of a loaded app
+#[derive(Copy, Clone)]
+pub struct TlsTemplate {
+    /// where the initialized part (`.tdata`) is loaded
+    vaddr: usize,
+    /// size of `.tdata`
+    file_size: usize,
+    /// size of `.tdata` and `.tbss`
+    mem_size: usize,
+    /// alignment of the block
+    align: usize,
+}
+
+impl TlsTemplate {
+    /// Whether the block takes at most half of a user stack, as a thread needs
+    /// room left for its own frames
+    pub fn fits_in_stack(&self) -> bool {
+        self.mem_size <= USER_STACK_SIZE / 2
+    }
+    /// Put a fresh copy of the block right below `stack_top`, and return the
+    /// thread pointer to it, or None if it takes more than half of the stack.
+    ///
+    /// RISC-V uses TLS variant I without a TCB, so `tp` points to the block
+    /// itself, where the linker expects the first thread-local variable.
+    pub fn init_block(&self, stack_top: usize) -> Option<usize> {
+        if !self.fits_in_stack() {
+            return None;
+        }
+        let tp = (stack_top - self.mem_size) & !(self.align - 1);
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.vaddr as *const u8, tp as *mut u8, self.file_size);
+            core::ptr::write_bytes(
+                (tp + self.file_size) as *mut u8,
+                0,
+                self.mem_size - self.file_size,
+            );
+        }
+        Some(tp)
+    }
 }
@@ -12,8 +12,9 @@
 //! initialize various pieces of functionality. (See its source code for
 //! details.)
 //!
-//! We then call [`task::run_first_task()`] and for the first time go to
-//! userspace.
+//! The first hart to boot initializes the kernel and then starts all other
+//! harts through the SBI HSM extension. Every hart finally calls
+//! [`task::run_tasks()`] and for the first time go to userspace.
 
 #![deny(missing_docs)]
 #![deny(warnings)]
@@ -21,6 +22,8 @@
 #![no_main]
 #![feature(panic_info_message)]
 #![feature(alloc_error_handler)]
+#![feature(const_caller_location)]
+#![feature(asm_const)]
 
 #[macro_use]
 extern crate log;
@@ -30,10 +33,13 @@ extern crate alloc;
 #[macro_use]
 mod console;
 pub mod config;
+pub mod drivers;
+pub mod fs;
 mod heap_alloc;
 pub mod lang_items;
 mod loader;
 pub mod logging;
+pub mod mm;
 pub mod sbi;
 pub mod sync;
 pub mod syscall;
@@ -41,9 +47,20 @@ pub mod task;
 pub mod timer;
 pub mod trap;
 
-core::arch::global_asm!(include_str!("entry.asm"));
+core::arch::global_asm!(
+    include_str!("entry.asm"),
+    MAX_HARTS = const config::MAX_HARTS,
+    BOOT_STACK_SIZE = const config::BOOT_STACK_SIZE,
+);
+#[cfg(feature = "embedded-apps")]
 core::arch::global_asm!(include_str!("link_app.S"));
 
+use config::MAX_HARTS;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// set by the boot hart once global initialization is done
+static BOOTED: AtomicBool = AtomicBool::new(false);
+
 /// clear BSS segment
 fn clear_bss() {
     extern "C" {
@@ -92,16 +109,48 @@ fn kernel_log_info() {
     error!("[kernel] .bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
 }
 
+/// start all other harts at `_start` through the SBI HSM extension
+fn start_other_harts(hartid: usize) {
+    extern "C" {
+        fn _start();
+    }
+    for i in (0..MAX_HARTS).filter(|&i| i != hartid) {
+        // harts that do not exist on this machine are rejected by SBI
+        if sbi::hart_start(i, _start as usize, 0) == 0 {
+            debug!("[kernel] start hart {}", i);
+        }
+    }
+}
+
 #[no_mangle]
 /// the rust entry-point of os
-pub fn rust_main() -> ! {
+pub fn rust_main(hartid: usize) -> ! {
+    if BOOTED.load(Ordering::Acquire) {
+        secondary_main(hartid);
+    }
     clear_bss();
     kernel_log_info();
     heap_alloc::init_heap();
+    mm::init();
+    trap::init();
+    loader::list_apps();
+    task::add_initproc();
+    drivers::init(hartid);
+    trap::enable_timer_interrupt();
+    trap::enable_external_interrupt();
+    timer::set_next_trigger();
+    BOOTED.store(true, Ordering::Release);
+    start_other_harts(hartid);
+    task::run_tasks();
+}
+
+/// the rust entry-point of all harts other than the boot one
+fn secondary_main(hartid: usize) -> ! {
+    info!("[kernel] hart {} is online", hartid);
     trap::init();
-    loader::load_apps();
+    drivers::init_hart(hartid);
     trap::enable_timer_interrupt();
+    trap::enable_external_interrupt();
     timer::set_next_trigger();
-    task::run_first_task();
-    panic!("Unreachable in rust_main!");
+    task::run_tasks();
 }
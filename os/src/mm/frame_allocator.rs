@@ -0,0 +1,128 @@
+//! Allocation of physical page frames
+//!
+//! Frames are given out from `current`, the low end of the free memory, and
+//! those given back are recycled first. For several contiguous frames, the
+//! recycled frames just below `current` are first merged back into it. The
+//! frames then come from a run of recycled frames if there is one, and from
+//! `current` otherwise.
+
+use crate::config::{APP_BASE_ADDRESS, APP_SIZE_LIMIT, MAX_APP_NUM, MEMORY_END, PAGE_SIZE};
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+
+/// A frame owned by its holder, and given back to the allocator on drop
+pub struct FrameTracker {
+    /// physical page number of the frame
+    pub ppn: usize,
+}
+
+impl FrameTracker {
+    /// Take frame `ppn`, clearing it
+    fn new(ppn: usize) -> Self {
+        let tracker = Self { ppn };
+        unsafe {
+            core::ptr::write_bytes(tracker.addr() as *mut u8, 0, PAGE_SIZE);
+        }
+        tracker
+    }
+    /// Physical address of the frame
+    pub fn addr(&self) -> usize {
+        self.ppn * PAGE_SIZE
+    }
+}
+
+impl Drop for FrameTracker {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR.lock().dealloc(self.ppn);
+    }
+}
+
+/// An allocator of frames that are never split
+struct StackFrameAllocator {
+    /// first frame never given out yet
+    current: usize,
+    /// end of the free memory, as a page number
+    end: usize,
+    /// frames given back
+    recycled: Vec<usize>,
+}
+
+impl StackFrameAllocator {
+    const fn new() -> Self {
+        Self {
+            current: 0,
+            end: 0,
+            recycled: Vec::new(),
+        }
+    }
+    /// Hand out the frames in `[l, r)`
+    fn init(&mut self, l: usize, r: usize) {
+        self.current = l;
+        self.end = r;
+    }
+    fn alloc(&mut self) -> Option<usize> {
+        if let Some(ppn) = self.recycled.pop() {
+            return Some(ppn);
+        }
+        self.alloc_contiguous(1)
+    }
+    /// Take `pages` contiguous frames, returning the first one
+    fn alloc_contiguous(&mut self, pages: usize) -> Option<usize> {
+        self.recycled.sort_unstable();
+        // the recycled frames just below `current` are merged back into it
+        while self.recycled.last().map(|ppn| ppn + 1) == Some(self.current) {
+            self.recycled.pop();
+            self.current -= 1;
+        }
+        // find a run of `pages` recycled frames
+        let mut run_start = 0;
+        for i in 0..self.recycled.len() {
+            if i > 0 && self.recycled[i] != self.recycled[i - 1] + 1 {
+                run_start = i;
+            }
+            if i + 1 - run_start == pages {
+                let first = self.recycled[run_start];
+                self.recycled.drain(run_start..=i);
+                return Some(first);
+            }
+        }
+        if self.end - self.current < pages {
+            return None;
+        }
+        self.current += pages;
+        Some(self.current - pages)
+    }
+    fn dealloc(&mut self, ppn: usize) {
+        // validity check
+        if ppn >= self.current || self.recycled.contains(&ppn) {
+            panic!("Frame ppn={:#x} has not been allocated!", ppn);
+        }
+        self.recycled.push(ppn);
+    }
+}
+
+static FRAME_ALLOCATOR: SpinLock<StackFrameAllocator> = SpinLock::new(StackFrameAllocator::new());
+
+/// Hand the memory above the kernel and the apps to the frame allocator
+pub fn init_frame_allocator() {
+    extern "C" {
+        fn ekernel();
+    }
+    let start = (ekernel as usize).max(APP_BASE_ADDRESS + MAX_APP_NUM * APP_SIZE_LIMIT);
+    FRAME_ALLOCATOR
+        .lock()
+        .init(start.div_ceil(PAGE_SIZE), MEMORY_END / PAGE_SIZE);
+}
+
+/// Allocate a cleared frame
+pub fn frame_alloc() -> Option<FrameTracker> {
+    let ppn = FRAME_ALLOCATOR.lock().alloc()?;
+    Some(FrameTracker::new(ppn))
+}
+
+/// Allocate `pages` cleared frames that are contiguous in memory, as devices
+/// need for a buffer spanning several pages
+pub fn frame_alloc_contiguous(pages: usize) -> Option<Vec<FrameTracker>> {
+    let first = FRAME_ALLOCATOR.lock().alloc_contiguous(pages)?;
+    Some((first..first + pages).map(FrameTracker::new).collect())
+}
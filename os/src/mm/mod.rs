@@ -0,0 +1,14 @@
+//! Memory management
+//!
+//! Without paging, the kernel sees all memory at its physical addresses. The
+//! memory left after the kernel and the spaces of the apps is handed out one
+//! page at a time by the [`frame_allocator`], mostly for devices to do DMA.
+
+mod frame_allocator;
+
+pub use frame_allocator::{frame_alloc, frame_alloc_contiguous, FrameTracker};
+
+/// Initialize memory management
+pub fn init() {
+    frame_allocator::init_frame_allocator();
+}
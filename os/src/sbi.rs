@@ -1,11 +1,22 @@
 //! SBI call wrappers
 
 use core::arch::asm;
+use lazy_static::*;
 
 const SBI_SET_TIMER: usize = 0;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
+const SBI_CONSOLE_GETCHAR: usize = 2;
 const SBI_SHUTDOWN: usize = 8;
 
+const SBI_EXT_BASE: usize = 0x10;
+const SBI_BASE_PROBE_EXTENSION: usize = 3;
+
+const SBI_EXT_HSM: usize = 0x48534D;
+const SBI_HSM_HART_START: usize = 0;
+
+const SBI_EXT_DBCN: usize = 0x4442434E;
+const SBI_DBCN_CONSOLE_WRITE: usize = 0;
+
 /// general sbi call
 #[inline(always)]
 fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
@@ -23,6 +34,23 @@ fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     ret
 }
 
+/// sbi call following the SBI v0.2 calling convention, returns `(error, value)`
+#[inline(always)]
+fn sbi_call_ext(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, usize) {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2,
+            in("x16") fid,
+            in("x17") eid,
+        );
+    }
+    (error, value)
+}
+
 /// use sbi call to set timer
 pub fn set_timer(timer: usize) {
     sbi_call(SBI_SET_TIMER, timer, 0, 0);
@@ -33,6 +61,61 @@ pub fn console_putchar(c: usize) {
     sbi_call(SBI_CONSOLE_PUTCHAR, c, 0, 0);
 }
 
+/// use sbi call to getchar from console (qemu uart handler), None if nothing
+/// has been typed
+pub fn console_getchar() -> Option<u8> {
+    match sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0) as isize {
+        c @ 0..=255 => Some(c as u8),
+        _ => None,
+    }
+}
+
+/// use sbi base extension to check whether extension `eid` is available
+fn probe_extension(eid: usize) -> bool {
+    let (error, value) = sbi_call_ext(SBI_EXT_BASE, SBI_BASE_PROBE_EXTENSION, eid, 0, 0);
+    error == 0 && value != 0
+}
+
+lazy_static! {
+    /// whether the SBI implementation has the debug console extension
+    static ref HAS_DBCN: bool = probe_extension(SBI_EXT_DBCN);
+}
+
+/// use sbi DBCN extension to write `bytes` to the console in as few calls as
+/// possible. Returns false if the extension is not available.
+pub fn console_write(bytes: &[u8]) -> bool {
+    if !*HAS_DBCN {
+        return false;
+    }
+    let mut written = 0;
+    while written < bytes.len() {
+        // there is no paging, so the buffer is at the same physical address
+        let addr = bytes[written..].as_ptr() as usize;
+        let (error, value) = sbi_call_ext(
+            SBI_EXT_DBCN,
+            SBI_DBCN_CONSOLE_WRITE,
+            bytes.len() - written,
+            addr,
+            0,
+        );
+        if error != 0 {
+            // give the rest to the fallback
+            bytes[written..]
+                .iter()
+                .for_each(|byte| console_putchar(*byte as usize));
+            break;
+        }
+        written += value;
+    }
+    true
+}
+
+/// use sbi HSM extension to start hart `hartid` at `start_addr` in supervisor mode,
+/// with `a0 = hartid` and `a1 = opaque`. Returns the SBI error code, 0 on success.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> isize {
+    sbi_call_ext(SBI_EXT_HSM, SBI_HSM_HART_START, hartid, start_addr, opaque).0
+}
+
 /// use sbi call to shutdown the kernel
 pub fn shutdown() -> ! {
     sbi_call(SBI_SHUTDOWN, 0, 0, 0);
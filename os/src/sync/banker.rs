@@ -0,0 +1,139 @@
+//! Deadlock avoidance for user mutexes and semaphores
+//!
+//! Every process owns a [`Banker`], which keeps the available, allocation and
+//! need matrices of the banker's algorithm over the mutexes and semaphores of
+//! the process. The matrices are always kept up to date, but requests are only
+//! checked once userspace turns detection on with `sys_enable_deadlock_detect`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// A resource tracked by the banker
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Resource {
+    /// mutex with the given id
+    Mutex(usize),
+    /// semaphore with the given id
+    Semaphore(usize),
+}
+
+/// Banker's algorithm state of a process
+pub struct Banker {
+    /// whether requests are checked
+    enabled: bool,
+    /// the resource of each column
+    resources: Vec<Resource>,
+    /// `available[r]`: free units of resource `r`
+    available: Vec<usize>,
+    /// `allocation[t][r]`: units of resource `r` held by thread `t`
+    allocation: Vec<Vec<usize>>,
+    /// `need[t][r]`: units of resource `r` thread `t` is waiting for
+    need: Vec<Vec<usize>>,
+}
+
+impl Banker {
+    /// Create a banker with no resource and detection turned off
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            resources: Vec::new(),
+            available: Vec::new(),
+            allocation: Vec::new(),
+            need: Vec::new(),
+        }
+    }
+}
+
+impl Default for Banker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Banker {
+    /// Turn checking of requests on or off
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    /// Start tracking `resource` with `units` free units
+    pub fn add_resource(&mut self, resource: Resource, units: usize) {
+        self.resources.push(resource);
+        self.available.push(units);
+        for row in self.allocation.iter_mut().chain(self.need.iter_mut()) {
+            row.push(0);
+        }
+    }
+    /// Get the column of `resource`
+    fn column(&self, resource: Resource) -> usize {
+        self.resources.iter().position(|r| *r == resource).unwrap()
+    }
+    /// Make sure thread `tid` has a row in the matrices
+    fn ensure_thread(&mut self, tid: usize) {
+        while self.allocation.len() <= tid {
+            self.allocation.push(vec![0; self.resources.len()]);
+            self.need.push(vec![0; self.resources.len()]);
+        }
+    }
+    /// Thread `tid` asks for one unit of `resource`, and may have to wait for it.
+    ///
+    /// Returns false if detection is on and the request leads to an unsafe
+    /// state, in which case nothing is recorded and the thread must not wait.
+    pub fn request(&mut self, tid: usize, resource: Resource) -> bool {
+        self.ensure_thread(tid);
+        let r = self.column(resource);
+        self.need[tid][r] += 1;
+        if self.enabled && !self.is_safe() {
+            self.need[tid][r] -= 1;
+            return false;
+        }
+        true
+    }
+    /// Thread `tid` got the unit of `resource` it requested, or took it back
+    /// without asking, like a mutex locked again after waiting on a condvar
+    pub fn acquired(&mut self, tid: usize, resource: Resource) {
+        self.ensure_thread(tid);
+        let r = self.column(resource);
+        if self.need[tid][r] > 0 {
+            self.need[tid][r] -= 1;
+        }
+        self.allocation[tid][r] += 1;
+        // an unexpected acquisition must not take the kernel down
+        self.available[r] = self.available[r].saturating_sub(1);
+    }
+    /// Thread `tid` stopped waiting for the unit of `resource` it requested,
+    /// as its process ended
+    pub fn cancel(&mut self, tid: usize, resource: Resource) {
+        self.ensure_thread(tid);
+        let r = self.column(resource);
+        if self.need[tid][r] > 0 {
+            self.need[tid][r] -= 1;
+        }
+    }
+    /// Thread `tid` gives one unit of `resource` back. A semaphore may be
+    /// released by a thread that never acquired it.
+    pub fn release(&mut self, tid: usize, resource: Resource) {
+        self.ensure_thread(tid);
+        let r = self.column(resource);
+        if self.allocation[tid][r] > 0 {
+            self.allocation[tid][r] -= 1;
+        }
+        self.available[r] += 1;
+    }
+    /// The safety check: whether all threads can finish in some order
+    fn is_safe(&self) -> bool {
+        let mut work = self.available.clone();
+        let mut finish = vec![false; self.allocation.len()];
+        loop {
+            let Some(tid) = (0..finish.len()).find(|&tid| {
+                !finish[tid] && self.need[tid].iter().zip(work.iter()).all(|(n, w)| n <= w)
+            }) else {
+                break;
+            };
+            for (w, a) in work.iter_mut().zip(self.allocation[tid].iter()) {
+                *w += a;
+            }
+            finish[tid] = true;
+        }
+        finish.iter().all(|&f| f)
+    }
+}
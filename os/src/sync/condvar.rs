@@ -0,0 +1,73 @@
+//! Condition variable handed out to userspace through `sys_condvar_create`
+
+use super::{SpinLock, UserMutex};
+use crate::task::{
+    block_current_interruptible_and_run_next, current_task, wakeup_task, TaskControlBlock,
+};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// Condition variable structure
+pub struct Condvar {
+    /// Condition variable inner
+    pub inner: SpinLock<CondvarInner>,
+}
+
+/// Inner of Condvar
+pub struct CondvarInner {
+    /// tasks waiting for the condition
+    pub wait_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl Condvar {
+    /// Create a new condition variable
+    pub fn new() -> Self {
+        trace!("kernel: Condvar::new");
+        Self {
+            inner: SpinLock::new(CondvarInner {
+                wait_queue: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Condvar {
+    /// Signal a task waiting on the condition variable
+    pub fn signal(&self) {
+        let mut inner = self.inner.lock();
+        if let Some(task) = inner.wait_queue.pop_front() {
+            drop(inner);
+            wakeup_task(task);
+        }
+    }
+
+    /// Release `mutex` and wait on the condition variable, then take `mutex` again.
+    ///
+    /// The task is queued before `mutex` is released, so a signal sent right
+    /// after the release is never lost. Returns false, without `mutex`, if a
+    /// signal arrived or the process ended while waiting, including while
+    /// waiting for `mutex` again.
+    pub fn wait_with_mutex(&self, mutex: Arc<dyn UserMutex>) -> bool {
+        trace!("kernel: Condvar::wait_with_mutex");
+        let task = current_task().unwrap();
+        let mut inner = self.inner.lock();
+        inner.wait_queue.push_back(task.clone());
+        mutex.unlock();
+        block_current_interruptible_and_run_next(inner);
+        let mut inner = self.inner.lock();
+        // `signal` takes us out of the queue, while a signal or the exit of the
+        // process does not
+        if let Some(pos) = inner.wait_queue.iter().position(|t| Arc::ptr_eq(t, &task)) {
+            inner.wait_queue.remove(pos);
+            return false;
+        }
+        drop(inner);
+        mutex.lock()
+    }
+}
@@ -0,0 +1,141 @@
+//! Fast userspace mutexes
+//!
+//! A futex is a 32-bit word in user memory. Userspace takes and releases its
+//! locks with atomic instructions on the word alone, and only asks the kernel
+//! to sleep when the word says the lock is taken, or to wake sleepers when it
+//! knows there are some.
+//!
+//! Sleepers are kept in a fixed table of wait queues, hashed by the address
+//! of the word. As there is no paging yet, the address is the same for every
+//! task and identifies the word in the whole system.
+
+use super::SpinLock;
+use crate::task::{
+    block_current_interruptible_and_run_next, current_process, current_task, wakeup_task,
+    TaskControlBlock,
+};
+use crate::timer::{add_timer, get_time_ms, remove_timer};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+/// number of wait queues in the hash table
+const FUTEX_HASH_SIZE: usize = 64;
+
+/// still sleeping
+const WAITING: u8 = 0;
+/// woken by [`futex_wake`]
+const WOKEN: u8 = 1;
+/// woken by its timeout
+const TIMED_OUT: u8 = 2;
+
+/// A task sleeping on a futex
+struct FutexWaiter {
+    /// address of the futex word
+    uaddr: usize,
+    task: Arc<TaskControlBlock>,
+    /// only changed with the queue locked, and whoever moves it out of
+    /// `WAITING` removes it from the queue and wakes the task
+    state: AtomicU8,
+}
+
+type FutexQueue = SpinLock<VecDeque<Arc<FutexWaiter>>>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const QUEUE_INIT: FutexQueue = SpinLock::new(VecDeque::new());
+/// The hashed wait queues
+static FUTEX_QUEUES: [FutexQueue; FUTEX_HASH_SIZE] = [QUEUE_INIT; FUTEX_HASH_SIZE];
+
+/// Why [`futex_wait`] returned without being woken
+pub enum FutexError {
+    /// the word did not hold the expected value
+    Again,
+    /// the timeout expired first
+    TimedOut,
+    /// a signal arrived or the process ended first
+    Interrupted,
+}
+
+fn queue_of(uaddr: usize) -> &'static FutexQueue {
+    &FUTEX_QUEUES[(uaddr >> 2) % FUTEX_HASH_SIZE]
+}
+
+/// Whether `uaddr` can be used as a futex word by the current process, which
+/// must own the memory holding it
+pub fn futex_addr_valid(uaddr: usize) -> bool {
+    let size = core::mem::size_of::<u32>();
+    uaddr % size == 0
+        && current_process()
+            .inner_exclusive_access()
+            .in_user_memory(uaddr, size)
+}
+
+/// Sleep on the futex at `uaddr` if it still holds `val`, until a
+/// [`futex_wake`] on it, until a signal or, if given, until `timeout_ms`
+/// milliseconds passed.
+///
+/// The word is checked with the queue locked, so a waker that changes the word
+/// and then calls [`futex_wake`] can never be missed.
+pub fn futex_wait(uaddr: usize, val: u32, timeout_ms: Option<usize>) -> Result<(), FutexError> {
+    let queue = queue_of(uaddr);
+    let mut waiters = queue.lock();
+    let word = unsafe { &*(uaddr as *const AtomicU32) };
+    if word.load(Ordering::SeqCst) != val {
+        return Err(FutexError::Again);
+    }
+    let waiter = Arc::new(FutexWaiter {
+        uaddr,
+        task: current_task().unwrap(),
+        state: AtomicU8::new(WAITING),
+    });
+    waiters.push_back(waiter.clone());
+    let timer = timeout_ms.map(|timeout_ms| {
+        let waiter = waiter.clone();
+        add_timer(get_time_ms() + timeout_ms, move || {
+            // the queue lock also waits for the task to be blocked
+            let mut waiters = queue_of(waiter.uaddr).lock();
+            if waiter
+                .state
+                .compare_exchange(WAITING, TIMED_OUT, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                waiters.retain(|other| !Arc::ptr_eq(other, &waiter));
+                drop(waiters);
+                wakeup_task(waiter.task.clone());
+            }
+        })
+    });
+    block_current_interruptible_and_run_next(waiters);
+    let mut waiters = queue.lock();
+    let ret = match waiter.state.load(Ordering::Acquire) {
+        TIMED_OUT => return Err(FutexError::TimedOut),
+        // woken by a signal or the exit of the process: leave the queue
+        WAITING => {
+            waiters.retain(|other| !Arc::ptr_eq(other, &waiter));
+            Err(FutexError::Interrupted)
+        }
+        _ => Ok(()),
+    };
+    drop(waiters);
+    if let Some(timer) = timer {
+        remove_timer(timer);
+    }
+    ret
+}
+
+/// Wake at most `count` tasks sleeping on the futex at `uaddr`, returning how
+/// many were woken
+pub fn futex_wake(uaddr: usize, count: usize) -> usize {
+    let mut waiters = queue_of(uaddr).lock();
+    let mut woken = 0;
+    waiters.retain(|waiter| {
+        if woken == count || waiter.uaddr != uaddr {
+            return true;
+        }
+        waiter.state.store(WOKEN, Ordering::Release);
+        wakeup_task(waiter.task.clone());
+        woken += 1;
+        false
+    });
+    woken
+}
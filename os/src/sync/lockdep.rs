@@ -0,0 +1,367 @@
+//! Lock dependency validator, only built in debug builds
+//!
+//! Every spin lock belongs to a lock class, namely the place in the source
+//! where it was created: all TCBs share one class for their inner lock, while
+//! `TASK_MANAGER` has a class of its own. Whenever a hart acquires a lock of
+//! class B while holding a lock of class A, the dependency A -> B is recorded
+//! together with the places where both were acquired.
+//!
+//! If B can already reach A through recorded dependencies, two harts taking
+//! these locks in opposite orders may deadlock. The whole cycle is reported as
+//! soon as the inversion is seen, even if this run did not deadlock. Like in
+//! Linux, lockdep turns itself off after its first report.
+//!
+//! Nesting two locks of the same class is not recorded, since their order can
+//! only be decided by the instances.
+//!
+//! A sleeping [`super::Mutex`] stays held while its task sleeps, and may be
+//! released on another hart, so the mutexes held by a task are recorded in its
+//! [`TaskLocks`] rather than by the hart. The idle control flow points each
+//! hart at the [`TaskLocks`] of the task it runs, and dependencies are taken
+//! from both the locks of the hart and those of its task.
+
+use super::{pop_off, push_off};
+use crate::config::MAX_HARTS;
+use crate::task::hart_id;
+use core::cell::UnsafeCell;
+use core::panic::Location;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+/// the max number of lock classes
+const MAX_CLASSES: usize = 32;
+/// the max number of spin locks a hart, or mutexes a task, may hold at the same time
+const MAX_HELD: usize = 16;
+
+/// A place in the source code
+type Site = &'static Location<'static>;
+
+/// A lock held by a hart or a task
+#[derive(Copy, Clone)]
+struct HeldLock {
+    class: usize,
+    /// where it was acquired
+    site: Site,
+}
+
+/// Locks held by a hart or a task, in acquisition order
+struct HeldStack {
+    locks: [Option<HeldLock>; MAX_HELD],
+    depth: usize,
+}
+
+impl HeldStack {
+    const fn new() -> Self {
+        Self {
+            locks: [None; MAX_HELD],
+            depth: 0,
+        }
+    }
+    fn held(&self) -> impl Iterator<Item = &HeldLock> {
+        self.locks[..self.depth].iter().flatten()
+    }
+    fn remove(&mut self, class: Option<usize>) {
+        // locks are not always released in the reverse order of acquisition
+        if let Some(pos) = self.locks[..self.depth]
+            .iter()
+            .rposition(|lock| lock.map(|lock| lock.class) == class)
+        {
+            self.locks.copy_within(pos + 1..self.depth, pos);
+            self.depth -= 1;
+            self.locks[self.depth] = None;
+        }
+    }
+}
+
+/// The sleeping mutexes held by a task, only accessed by the hart running it
+pub struct TaskLocks(UnsafeCell<HeldStack>);
+
+unsafe impl Sync for TaskLocks {}
+
+impl TaskLocks {
+    /// Create an empty set, for a new task
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new(HeldStack::new()))
+    }
+}
+
+impl Default for TaskLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Recorded lock classes and dependencies
+struct Graph {
+    /// creation site of each class
+    classes: [Option<Site>; MAX_CLASSES],
+    /// `deps[a][b]`: where `a` was held and where `b` was then acquired,
+    /// the first time `b` was acquired while holding `a`
+    deps: [[Option<(Site, Site)>; MAX_CLASSES]; MAX_CLASSES],
+}
+
+/// A cell only accessed by its own hart, with interrupts disabled
+struct PerHart<T>(UnsafeCell<T>);
+
+unsafe impl<T> Sync for PerHart<T> {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const HELD_INIT: PerHart<HeldStack> = PerHart(UnsafeCell::new(HeldStack::new()));
+static HELD: [PerHart<HeldStack>; MAX_HARTS] = [HELD_INIT; MAX_HARTS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const RUNNING_INIT: AtomicPtr<TaskLocks> = AtomicPtr::new(null_mut());
+/// the [`TaskLocks`] of the task running on each hart, null in the idle control flow
+static RUNNING: [AtomicPtr<TaskLocks>; MAX_HARTS] = [RUNNING_INIT; MAX_HARTS];
+
+/// Lockdep cannot track its own lock, so it uses a bare spin lock
+struct GraphLock {
+    locked: AtomicBool,
+    graph: UnsafeCell<Graph>,
+}
+
+unsafe impl Sync for GraphLock {}
+
+static GRAPH: GraphLock = GraphLock {
+    locked: AtomicBool::new(false),
+    graph: UnsafeCell::new(Graph {
+        classes: [None; MAX_CLASSES],
+        deps: [[None; MAX_CLASSES]; MAX_CLASSES],
+    }),
+};
+
+/// Cleared after the first report
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+impl GraphLock {
+    fn with<R>(&self, f: impl FnOnce(&mut Graph) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let ret = f(unsafe { &mut *self.graph.get() });
+        self.locked.store(false, Ordering::Release);
+        ret
+    }
+}
+
+impl Graph {
+    /// Get the id of the class created at `key`, registering it if needed
+    fn class_id(&mut self, key: Site) -> Option<usize> {
+        for (id, class) in self.classes.iter_mut().enumerate() {
+            match class {
+                Some(site) if *site == key => return Some(id),
+                None => {
+                    *class = Some(key);
+                    return Some(id);
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+    /// Find a path of recorded dependencies `from -> ... -> to`, and write its
+    /// classes backwards into `path`, returning its length
+    fn find_path(&self, from: usize, to: usize, path: &mut [usize; MAX_CLASSES]) -> Option<usize> {
+        let mut parent = [usize::MAX; MAX_CLASSES];
+        let mut queue = [0usize; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        parent[from] = from;
+        while head < tail {
+            let cur = queue[head];
+            head += 1;
+            if cur == to {
+                let mut len = 0;
+                let mut node = to;
+                loop {
+                    path[len] = node;
+                    len += 1;
+                    if node == from {
+                        return Some(len);
+                    }
+                    node = parent[node];
+                }
+            }
+            for (next, dep) in self.deps[cur].iter().enumerate() {
+                if dep.is_some() && parent[next] == usize::MAX {
+                    parent[next] = cur;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// What lockdep found out while recording an acquisition
+#[allow(clippy::large_enum_variant)]
+enum Verdict {
+    Ok,
+    /// too many classes or held locks to keep tracking
+    Overflow(&'static str),
+    /// the held lock, the class being acquired, and a reverse path from it
+    Inversion {
+        held: HeldLock,
+        class: usize,
+        path: [usize; MAX_CLASSES],
+        len: usize,
+    },
+}
+
+/// Point the current hart at the mutexes held by the task it is about to run,
+/// or at none when it goes back to the idle control flow
+///
+/// The task must stay alive until the hart is pointed elsewhere.
+pub fn switch_task(locks: Option<&TaskLocks>) {
+    let ptr = locks.map_or(null_mut(), |locks| locks as *const _ as *mut TaskLocks);
+    RUNNING[hart_id()].store(ptr, Ordering::Relaxed);
+}
+
+/// The mutexes held by the task running on the current hart, if any
+///
+/// Interrupts must be disabled.
+fn running_task_locks() -> Option<&'static mut HeldStack> {
+    let ptr = RUNNING[hart_id()].load(Ordering::Relaxed);
+    unsafe { ptr.as_ref() }.map(|locks| unsafe { &mut *locks.0.get() })
+}
+
+/// Record that the current hart is about to acquire a spin lock of class `key` at `site`
+pub fn lock_acquire(key: Site, site: Site) {
+    acquire(key, site, false);
+}
+
+/// Record that the current hart has released a spin lock of class `key`
+pub fn lock_release(key: Site) {
+    release(key, false);
+}
+
+/// Record that the current task is about to acquire a mutex of class `key` at `site`
+pub fn mutex_acquire(key: Site, site: Site) {
+    acquire(key, site, true);
+}
+
+/// Record that the current task has released a mutex of class `key`
+pub fn mutex_release(key: Site) {
+    release(key, true);
+}
+
+/// Record an acquisition, held by the task if `sleeping`, or else by the hart
+fn acquire(key: Site, site: Site, sleeping: bool) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    push_off();
+    let hart_held = unsafe { &mut *HELD[hart_id()].0.get() };
+    let task_held = running_task_locks();
+    let verdict = GRAPH.with(|graph| {
+        let Some(class) = graph.class_id(key) else {
+            return Verdict::Overflow("too many lock classes");
+        };
+        let prevs = hart_held
+            .held()
+            .chain(task_held.iter().flat_map(|held| held.held()));
+        for prev in prevs {
+            if prev.class == class || graph.deps[prev.class][class].is_some() {
+                continue;
+            }
+            let mut path = [0; MAX_CLASSES];
+            if let Some(len) = graph.find_path(class, prev.class, &mut path) {
+                return Verdict::Inversion {
+                    held: *prev,
+                    class,
+                    path,
+                    len,
+                };
+            }
+            graph.deps[prev.class][class] = Some((prev.site, site));
+        }
+        let held = match task_held {
+            Some(task_held) if sleeping => task_held,
+            // a mutex outside of a task cannot sleep, nor be tracked
+            None if sleeping => return Verdict::Ok,
+            _ => &mut *hart_held,
+        };
+        if held.depth == MAX_HELD {
+            return Verdict::Overflow("too many locks held");
+        }
+        held.locks[held.depth] = Some(HeldLock { class, site });
+        held.depth += 1;
+        Verdict::Ok
+    });
+    pop_off();
+    match verdict {
+        Verdict::Ok => {}
+        Verdict::Overflow(reason) => {
+            ENABLED.store(false, Ordering::Relaxed);
+            warn!("[lockdep] {}, turned off", reason);
+        }
+        Verdict::Inversion {
+            held,
+            class,
+            path,
+            len,
+        } => {
+            ENABLED.store(false, Ordering::Relaxed);
+            report(held, class, site, &path[..len]);
+        }
+    }
+}
+
+/// Record a release, from the task if `sleeping`, or else from the hart
+fn release(key: Site, sleeping: bool) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    push_off();
+    let class = GRAPH.with(|graph| graph.class_id(key));
+    if sleeping {
+        if let Some(task_held) = running_task_locks() {
+            task_held.remove(class);
+        }
+    } else {
+        unsafe { &mut *HELD[hart_id()].0.get() }.remove(class);
+    }
+    pop_off();
+}
+
+/// Print a dependency cycle. `path` goes backwards from `held.class` to `class`.
+///
+/// Lockdep is already off, so printing does not come back here.
+fn report(held: HeldLock, class: usize, site: Site, path: &[usize]) {
+    GRAPH.with(|graph| print_cycle(graph, held, class, site, path));
+}
+
+fn print_cycle(graph: &Graph, held: HeldLock, class: usize, site: Site, path: &[usize]) {
+    let (classes, deps) = (&graph.classes, &graph.deps);
+    println!("[lockdep] possible deadlock detected on hart {}", hart_id());
+    println!(
+        "[lockdep] acquiring lock created at {} at {}",
+        classes[class].unwrap(),
+        site
+    );
+    println!(
+        "[lockdep]   while holding lock created at {} acquired at {}",
+        classes[held.class].unwrap(),
+        held.site
+    );
+    println!("[lockdep] but the reverse order has been recorded before:");
+    for pair in path.windows(2).rev() {
+        let (to, from) = (pair[0], pair[1]);
+        let (from_site, to_site) = deps[from][to].unwrap();
+        println!(
+            "[lockdep]   lock created at {} acquired at {}",
+            classes[from].unwrap(),
+            from_site
+        );
+        println!(
+            "[lockdep]   then lock created at {} acquired at {}",
+            classes[to].unwrap(),
+            to_site
+        );
+    }
+}
@@ -1,5 +1,27 @@
 //! Synchronization and interior mutability primitives
+//!
+//! All of them are safe to share between harts. Prefer [`SpinLock`] for short
+//! critical sections, and [`Mutex`] when the holder may block or run for long.
+//!
+//! [`UserMutex`], [`Semaphore`] and [`Condvar`] are the kernel objects behind
+//! the synchronization syscalls of userspace, and a [`Banker`] can refuse to
+//! let a task wait on them when that may deadlock. Futexes let userspace
+//! build its own locks, and only sleep in the kernel under contention.
 
-mod up;
+mod banker;
+mod condvar;
+mod futex;
+#[cfg(debug_assertions)]
+pub mod lockdep;
+mod mutex;
+mod semaphore;
+mod spin;
+mod user_mutex;
 
-pub use up::UPSafeCell;
+pub use banker::{Banker, Resource};
+pub use condvar::Condvar;
+pub use futex::{futex_addr_valid, futex_wait, futex_wake, FutexError};
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use spin::{pop_off, push_off, SpinLock, SpinLockGuard, SpinNoIrq, SpinNoIrqGuard};
+pub use user_mutex::{MutexBlocking, MutexSpin, UserMutex};
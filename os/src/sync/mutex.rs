@@ -0,0 +1,124 @@
+//! A sleeping mutual exclusion lock
+//!
+//! Unlike a spin lock, a task waiting for a [`Mutex`] gives up its hart and
+//! sleeps on the wait queue of the mutex until the holder hands the mutex over
+//! to it. It must only be used from a task, never in an interrupt handler or
+//! before the first task runs.
+//!
+//! In debug builds, the mutexes held by each task are reported to
+//! [`super::lockdep`], with the place where the mutex was created as its lock
+//! class, just like spin locks.
+
+use super::SpinLock;
+use crate::task::{block_current_and_run_next, current_task, wakeup_task, TaskControlBlock};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::panic::Location;
+
+/// A sleeping lock protecting data of type `T`
+pub struct Mutex<T: ?Sized> {
+    inner: SpinLock<MutexInner>,
+    /// where the mutex was created, which is its lock class for lockdep
+    #[cfg(debug_assertions)]
+    class: &'static Location<'static>,
+    data: UnsafeCell<T>,
+}
+
+struct MutexInner {
+    locked: bool,
+    /// the task holding the mutex, to catch a task locking it twice
+    #[cfg(debug_assertions)]
+    owner: *const TaskControlBlock,
+    wait_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+// `owner` is only compared, never dereferenced
+unsafe impl Send for MutexInner {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+/// RAII guard of [`Mutex`], handing the mutex over to the next waiter on drop
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Create a new unlocked Mutex
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: SpinLock::new(MutexInner {
+                locked: false,
+                #[cfg(debug_assertions)]
+                owner: core::ptr::null(),
+                wait_queue: VecDeque::new(),
+            }),
+            #[cfg(debug_assertions)]
+            class: Location::caller(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Acquire the mutex, sleeping until it is available
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let task = current_task().unwrap();
+        #[cfg(debug_assertions)]
+        super::lockdep::mutex_acquire(self.class, Location::caller());
+        let mut inner = self.inner.lock();
+        #[cfg(debug_assertions)]
+        if inner.locked && inner.owner == Arc::as_ptr(&task) {
+            panic!("double acquisition of a mutex by the same task");
+        }
+        if inner.locked {
+            inner.wait_queue.push_back(task.clone());
+            block_current_and_run_next(inner);
+            // the mutex has been handed over to us by the previous holder
+        } else {
+            inner.locked = true;
+            drop(inner);
+        }
+        #[cfg(debug_assertions)]
+        {
+            self.inner.lock().owner = Arc::as_ptr(&task);
+        }
+        MutexGuard { mutex: self }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut inner = self.mutex.inner.lock();
+        #[cfg(debug_assertions)]
+        {
+            inner.owner = core::ptr::null();
+        }
+        if let Some(waiter) = inner.wait_queue.pop_front() {
+            // keep `locked`, the waiter owns the mutex from now on
+            drop(inner);
+            wakeup_task(waiter);
+        } else {
+            inner.locked = false;
+        }
+        #[cfg(debug_assertions)]
+        super::lockdep::mutex_release(self.mutex.class);
+    }
+}
@@ -0,0 +1,73 @@
+//! Semaphore handed out to userspace through `sys_semaphore_create`
+
+use super::SpinLock;
+use crate::task::{
+    block_current_interruptible_and_run_next, current_task, wakeup_task, TaskControlBlock,
+};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// semaphore structure
+pub struct Semaphore {
+    /// semaphore inner
+    pub inner: SpinLock<SemaphoreInner>,
+}
+
+/// Inner of Semaphore
+pub struct SemaphoreInner {
+    /// available resources, or the number of waiters if negative
+    pub count: isize,
+    /// tasks waiting for the semaphore
+    pub wait_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl Semaphore {
+    /// Create a new semaphore with `res_count` resources
+    pub fn new(res_count: usize) -> Self {
+        trace!("kernel: Semaphore::new");
+        Self {
+            inner: SpinLock::new(SemaphoreInner {
+                count: res_count as isize,
+                wait_queue: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// up operation of semaphore
+    pub fn up(&self) {
+        trace!("kernel: Semaphore::up");
+        let mut inner = self.inner.lock();
+        inner.count += 1;
+        if inner.count <= 0 {
+            if let Some(task) = inner.wait_queue.pop_front() {
+                drop(inner);
+                wakeup_task(task);
+            }
+        }
+    }
+
+    /// down operation of semaphore
+    ///
+    /// Returns false, without the resource, if a signal arrived or the process
+    /// ended while waiting.
+    pub fn down(&self) -> bool {
+        trace!("kernel: Semaphore::down");
+        let task = current_task().unwrap();
+        let mut inner = self.inner.lock();
+        inner.count -= 1;
+        if inner.count >= 0 {
+            return true;
+        }
+        inner.wait_queue.push_back(task.clone());
+        block_current_interruptible_and_run_next(inner);
+        let mut inner = self.inner.lock();
+        // `up` takes us out of the queue, while a signal or the exit of the
+        // process does not
+        let Some(pos) = inner.wait_queue.iter().position(|t| Arc::ptr_eq(t, &task)) else {
+            return true;
+        };
+        inner.wait_queue.remove(pos);
+        inner.count += 1;
+        false
+    }
+}
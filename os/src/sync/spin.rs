@@ -0,0 +1,214 @@
+//! Spin locks that are safe to share between harts
+//!
+//! [`SpinLock`] disables interrupts on the local hart while it is held, so an
+//! interrupt handler on the same hart can never spin forever on a lock its own
+//! hart already holds. [`SpinNoIrq`] leaves the interrupt state alone and may
+//! only protect data that is never touched from an interrupt handler.
+//!
+//! In debug builds, both locks remember which hart holds them and panic when
+//! that hart tries to acquire them a second time, which would otherwise spin
+//! forever. They also report every acquisition to [`super::lockdep`], with
+//! the place where the lock was created as its lock class.
+
+use crate::config::MAX_HARTS;
+use crate::task::hart_id;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::sstatus;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOFF_INIT: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const INTENA_INIT: AtomicBool = AtomicBool::new(false);
+/// depth of nested [`push_off`] on each hart
+static NOFF: [AtomicUsize; MAX_HARTS] = [NOFF_INIT; MAX_HARTS];
+/// whether interrupts were enabled before the outermost [`push_off`] on each hart
+static INTENA: [AtomicBool; MAX_HARTS] = [INTENA_INIT; MAX_HARTS];
+
+/// Disable interrupts on the local hart, and remember whether they were
+/// enabled if this is the outermost call.
+pub fn push_off() {
+    let sie = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+    let hartid = hart_id();
+    if NOFF[hartid].fetch_add(1, Ordering::Relaxed) == 0 {
+        INTENA[hartid].store(sie, Ordering::Relaxed);
+    }
+}
+
+/// Undo one [`push_off`], re-enabling interrupts after the outermost one if
+/// they were enabled before.
+pub fn pop_off() {
+    let hartid = hart_id();
+    assert!(!sstatus::read().sie(), "pop_off with interrupts enabled");
+    let noff = NOFF[hartid].fetch_sub(1, Ordering::Relaxed);
+    assert!(noff > 0, "pop_off without push_off");
+    if noff == 1 && INTENA[hartid].load(Ordering::Relaxed) {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+}
+
+/// The lock word shared by [`SpinLock`] and [`SpinNoIrq`]
+struct RawSpin {
+    locked: AtomicBool,
+    /// hart id + 1 of the holder, 0 if not held
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+    /// where the lock was created, which is its lock class for lockdep
+    #[cfg(debug_assertions)]
+    class: &'static Location<'static>,
+}
+
+impl RawSpin {
+    #[track_caller]
+    const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            class: Location::caller(),
+        }
+    }
+    #[track_caller]
+    fn acquire(&self) {
+        #[cfg(debug_assertions)]
+        if self.owner.load(Ordering::Relaxed) == hart_id() + 1 {
+            panic!("double acquisition of a spin lock on hart {}", hart_id());
+        }
+        #[cfg(debug_assertions)]
+        super::lockdep::lock_acquire(self.class, Location::caller());
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        #[cfg(debug_assertions)]
+        self.owner.store(hart_id() + 1, Ordering::Relaxed);
+    }
+    fn release(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        #[cfg(debug_assertions)]
+        super::lockdep::lock_release(self.class);
+    }
+}
+
+/// A spin lock that disables interrupts on the local hart while held
+pub struct SpinLock<T: ?Sized> {
+    raw: RawSpin,
+    data: UnsafeCell<T>,
+}
+
+/// A spin lock that does not touch interrupts, for data never used in interrupt handlers
+pub struct SpinNoIrq<T: ?Sized> {
+    raw: RawSpin,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinNoIrq<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinNoIrq<T> {}
+
+/// RAII guard of [`SpinLock`], releasing the lock and restoring interrupts on drop
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+}
+
+/// RAII guard of [`SpinNoIrq`], releasing the lock on drop
+pub struct SpinNoIrqGuard<'a, T: ?Sized> {
+    lock: &'a SpinNoIrq<T>,
+}
+
+impl<T> SpinLock<T> {
+    /// Create a new unlocked SpinLock
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            raw: RawSpin::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Spin until the lock is acquired, with interrupts disabled on this hart
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        push_off();
+        self.raw.acquire();
+        SpinLockGuard { lock: self }
+    }
+}
+
+impl<T> SpinNoIrq<T> {
+    /// Create a new unlocked SpinNoIrq
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            raw: RawSpin::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinNoIrq<T> {
+    /// Spin until the lock is acquired
+    #[track_caller]
+    pub fn lock(&self) -> SpinNoIrqGuard<'_, T> {
+        self.raw.acquire();
+        SpinNoIrqGuard { lock: self }
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.release();
+        pop_off();
+    }
+}
+
+impl<T: ?Sized> Deref for SpinNoIrqGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinNoIrqGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinNoIrqGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.release();
+    }
+}
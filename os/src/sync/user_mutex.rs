@@ -0,0 +1,155 @@
+//! Mutexes handed out to userspace through `sys_mutex_create`
+//!
+//! [`MutexSpin`] keeps yielding the hart until the mutex is free, while
+//! [`MutexBlocking`] puts waiters to sleep on a wait queue. Both exist so
+//! that apps can compare the two.
+//!
+//! Both record the tid of the thread holding them, as only the holder may
+//! unlock a mutex.
+
+use super::SpinLock;
+use crate::task::{
+    block_current_interruptible_and_run_next, current_process_ended, current_signal_pending,
+    current_task, suspend_current_and_run_next, wakeup_task, TaskControlBlock,
+};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// Mutex trait shared by the spinning and the blocking user mutex
+pub trait UserMutex: Sync + Send {
+    /// Lock the mutex for the current thread. Returns false, without the
+    /// mutex, if a signal arrived or the process ended while waiting.
+    fn lock(&self) -> bool;
+    /// Unlock the mutex, which the current thread must hold
+    fn unlock(&self);
+    /// Whether the current thread holds the mutex
+    fn is_held(&self) -> bool;
+}
+
+/// The tid of the current thread, recorded as the holder of a mutex
+fn current_tid() -> usize {
+    current_task().unwrap().tid
+}
+
+/// Spinlock Mutex struct
+pub struct MutexSpin {
+    /// tid of the holder, None if unlocked
+    owner: SpinLock<Option<usize>>,
+}
+
+impl MutexSpin {
+    /// Create a new spinlock mutex
+    pub fn new() -> Self {
+        Self {
+            owner: SpinLock::new(None),
+        }
+    }
+}
+
+impl Default for MutexSpin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UserMutex for MutexSpin {
+    /// Lock the spinlock mutex, yielding the hart while it is taken
+    fn lock(&self) -> bool {
+        trace!("kernel: MutexSpin::lock");
+        let tid = current_tid();
+        loop {
+            let mut owner = self.owner.lock();
+            if owner.is_some() {
+                drop(owner);
+                suspend_current_and_run_next();
+                if current_process_ended() || current_signal_pending() {
+                    return false;
+                }
+                continue;
+            } else {
+                *owner = Some(tid);
+                return true;
+            }
+        }
+    }
+
+    fn unlock(&self) {
+        trace!("kernel: MutexSpin::unlock");
+        *self.owner.lock() = None;
+    }
+
+    fn is_held(&self) -> bool {
+        *self.owner.lock() == Some(current_tid())
+    }
+}
+
+/// Blocking Mutex struct
+pub struct MutexBlocking {
+    inner: SpinLock<MutexBlockingInner>,
+}
+
+/// Inner of Blocking Mutex
+pub struct MutexBlockingInner {
+    /// tid of the holder, None if unlocked
+    owner: Option<usize>,
+    wait_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl MutexBlocking {
+    /// Create a new blocking mutex
+    pub fn new() -> Self {
+        trace!("kernel: MutexBlocking::new");
+        Self {
+            inner: SpinLock::new(MutexBlockingInner {
+                owner: None,
+                wait_queue: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl Default for MutexBlocking {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UserMutex for MutexBlocking {
+    /// Lock the blocking mutex, sleeping while it is taken
+    fn lock(&self) -> bool {
+        trace!("kernel: MutexBlocking::lock");
+        let task = current_task().unwrap();
+        let mut mutex_inner = self.inner.lock();
+        if mutex_inner.owner.is_none() {
+            mutex_inner.owner = Some(task.tid);
+            return true;
+        }
+        mutex_inner.wait_queue.push_back(task.clone());
+        block_current_interruptible_and_run_next(mutex_inner);
+        let mut mutex_inner = self.inner.lock();
+        if mutex_inner.owner == Some(task.tid) {
+            // the mutex has been handed over to us by the unlocking task
+            return true;
+        }
+        // woken up by a signal or the exit of the process instead
+        mutex_inner.wait_queue.retain(|t| !Arc::ptr_eq(t, &task));
+        false
+    }
+
+    /// Unlock the blocking mutex, handing it over to the first waiter if any
+    fn unlock(&self) {
+        trace!("kernel: MutexBlocking::unlock");
+        let mut mutex_inner = self.inner.lock();
+        if let Some(waking_task) = mutex_inner.wait_queue.pop_front() {
+            mutex_inner.owner = Some(waking_task.tid);
+            drop(mutex_inner);
+            wakeup_task(waking_task);
+        } else {
+            mutex_inner.owner = None;
+        }
+    }
+
+    fn is_held(&self) -> bool {
+        self.inner.lock().owner == Some(current_tid())
+    }
+}
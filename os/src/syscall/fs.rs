@@ -1,19 +1,233 @@
 //! File and filesystem-related syscalls
 
-const FD_STDOUT: usize = 1;
+use super::read_path;
+use crate::config::MAX_FD_NUM;
+use crate::fs::{
+    link_file, make_pipe, open_file, sync_all, unlink_file, FileDescriptor, OpenFlags,
+};
+use crate::task::current_process;
+use alloc::string::String;
 
 /// write buf of length `len`  to a file with `fd`
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     trace!("kernel: sys_write");
-    match fd {
-        FD_STDOUT => {
-            let slice = unsafe { core::slice::from_raw_parts(buf, len) };
-            let str = core::str::from_utf8(slice).unwrap();
-            print!("{}", str);
-            len as isize
-        }
-        _ => {
-            panic!("Unsupported fd in sys_write!");
-        }
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let Some(Some(FileDescriptor { file, .. })) = inner.fd_table.get(fd) else {
+        return -1;
+    };
+    if !file.writable() {
+        return -1;
     }
+    let file = file.clone();
+    // release the process, as writing may block
+    drop(inner);
+    let buf = unsafe { core::slice::from_raw_parts(buf, len) };
+    file.write(buf)
+}
+
+/// read up to `len` bytes from a file with `fd` into buf
+pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
+    trace!("kernel: sys_read");
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let Some(Some(FileDescriptor { file, .. })) = inner.fd_table.get(fd) else {
+        return -1;
+    };
+    if !file.readable() {
+        return -1;
+    }
+    let file = file.clone();
+    // release the process, as reading may block
+    drop(inner);
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf, len) };
+    file.read(buf)
+}
+
+/// pipe syscall, storing the descriptors of the read end and the write end of
+/// a new pipe at `pipe[0]` and `pipe[1]`
+///
+/// Returns -1 if there are not two free descriptors.
+pub fn sys_pipe(pipe: *mut usize) -> isize {
+    trace!("kernel: sys_pipe");
+    // made first, so that an unused pipe is closed after the lock is released
+    let (pipe_read, pipe_write) = make_pipe();
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let Some(read_fd) = inner.alloc_fd() else {
+        return -1;
+    };
+    inner.fd_table[read_fd] = Some(FileDescriptor::new(pipe_read));
+    let Some(write_fd) = inner.alloc_fd() else {
+        let read_end = inner.fd_table[read_fd].take();
+        // the pipe is closed for good, which must not happen under the lock
+        drop(inner);
+        drop(read_end);
+        return -1;
+    };
+    inner.fd_table[write_fd] = Some(FileDescriptor::new(pipe_write));
+    drop(inner);
+    unsafe {
+        *pipe = read_fd;
+        *pipe.add(1) = write_fd;
+    }
+    0
+}
+
+/// open syscall, opening the file at `path` on the disk with `flags` as a
+/// new descriptor
+///
+/// Returns -1 if the path is not valid UTF-8, the flags are invalid, the
+/// file cannot be opened, or no descriptor is free.
+pub fn sys_open(path: *const u8, flags: u32) -> isize {
+    trace!("kernel: sys_open");
+    let Some(flags) = OpenFlags::from_bits(flags) else {
+        return -1;
+    };
+    let Some(path) = read_path(path).and_then(|path| String::from_utf8(path).ok()) else {
+        return -1;
+    };
+    let Some(inode) = open_file(&path, flags) else {
+        return -1;
+    };
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let Some(fd) = inner.alloc_fd() else {
+        return -1;
+    };
+    inner.fd_table[fd] = Some(FileDescriptor {
+        file: inode,
+        cloexec: flags.contains(OpenFlags::CLOEXEC),
+    });
+    fd as isize
+}
+
+/// unlinkat syscall, removing the entry at `path`
+///
+/// The directory descriptor and the flags are ignored, as paths start from the
+/// root directory, and an empty directory is removed like a file. Returns -1
+/// if there is no such entry, it is a directory that is not empty, or the last
+/// link to a file still open.
+pub fn sys_unlinkat(path: *const u8) -> isize {
+    trace!("kernel: sys_unlinkat");
+    let Some(path) = read_path(path).and_then(|path| String::from_utf8(path).ok()) else {
+        return -1;
+    };
+    if unlink_file(&path) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// linkat syscall, adding the entry `new_path` for the file at `old_path`
+///
+/// The directory descriptors are ignored, as paths start from the root
+/// directory. Returns -1 if there is no such file, it is a directory, or
+/// `new_path` exists already.
+pub fn sys_linkat(old_path: *const u8, new_path: *const u8) -> isize {
+    trace!("kernel: sys_linkat");
+    let read = |path| read_path(path).and_then(|path| String::from_utf8(path).ok());
+    let (Some(old_path), Some(new_path)) = (read(old_path), read(new_path)) else {
+        return -1;
+    };
+    if link_file(&old_path, &new_path) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// close syscall, freeing descriptor `fd`
+pub fn sys_close(fd: usize) -> isize {
+    trace!("kernel: sys_close");
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let Some(Some(_)) = inner.fd_table.get(fd) else {
+        return -1;
+    };
+    let fd = inner.fd_table[fd].take();
+    // the file may be closed for good, which must not happen under the lock
+    drop(inner);
+    drop(fd);
+    0
+}
+
+/// dup syscall, duplicating `fd` to the lowest free descriptor
+///
+/// Returns -1 if `fd` is not open or no descriptor is free.
+pub fn sys_dup(fd: usize) -> isize {
+    trace!("kernel: sys_dup");
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let Some(Some(file_descriptor)) = inner.fd_table.get(fd) else {
+        return -1;
+    };
+    let file = file_descriptor.file.clone();
+    let Some(new_fd) = inner.alloc_fd() else {
+        return -1;
+    };
+    inner.fd_table[new_fd] = Some(FileDescriptor::new(file));
+    new_fd as isize
+}
+
+/// `O_CLOEXEC` of the flags of `sys_dup3`
+const O_CLOEXEC: u32 = 0o2000000;
+
+/// dup3 syscall, duplicating `old_fd` to `new_fd`, which is closed first if
+/// it is open
+///
+/// With `O_CLOEXEC` in `flags`, `new_fd` is closed by `sys_exec`. Returns -1
+/// if `old_fd` is not open, both are the same, `new_fd` is out of range, or
+/// `flags` is invalid.
+pub fn sys_dup3(old_fd: usize, new_fd: usize, flags: u32) -> isize {
+    trace!("kernel: sys_dup3");
+    if old_fd == new_fd || new_fd >= MAX_FD_NUM || flags & !O_CLOEXEC != 0 {
+        return -1;
+    }
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let Some(Some(file_descriptor)) = inner.fd_table.get(old_fd) else {
+        return -1;
+    };
+    let file_descriptor = FileDescriptor {
+        file: file_descriptor.file.clone(),
+        cloexec: flags & O_CLOEXEC != 0,
+    };
+    while inner.fd_table.len() <= new_fd {
+        inner.fd_table.push(None);
+    }
+    let old = inner.fd_table[new_fd].replace(file_descriptor);
+    drop(inner);
+    drop(old);
+    new_fd as isize
+}
+
+/// ioctl syscall, handling device-specific `request` of the file with `fd`
+pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
+    trace!("kernel: sys_ioctl");
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let Some(Some(FileDescriptor { file, .. })) = inner.fd_table.get(fd) else {
+        return -1;
+    };
+    let file = file.clone();
+    drop(inner);
+    file.ioctl(request, arg)
+}
+
+/// fsync syscall, writing what was written to the disk back before returning
+///
+/// Every write reaches the disk as its transaction commits, so this only
+/// writes back the blocks still modified in the cache, of any file.
+pub fn sys_fsync(fd: usize) -> isize {
+    trace!("kernel: sys_fsync");
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let Some(Some(_)) = inner.fd_table.get(fd) else {
+        return -1;
+    };
+    drop(inner);
+    sync_all();
+    0
 }
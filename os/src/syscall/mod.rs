@@ -10,33 +10,181 @@
 //! `sys_` then the name of the syscall. You can find functions like this in
 //! submodules, and you should also implement syscalls this way.
 
+/// dup syscall
+const SYSCALL_DUP: usize = 23;
+/// dup3 syscall
+const SYSCALL_DUP3: usize = 24;
+/// ioctl syscall
+const SYSCALL_IOCTL: usize = 29;
+/// unlinkat syscall
+const SYSCALL_UNLINKAT: usize = 35;
+/// linkat syscall
+const SYSCALL_LINKAT: usize = 37;
+/// open syscall
+const SYSCALL_OPEN: usize = 56;
+/// close syscall
+const SYSCALL_CLOSE: usize = 57;
+/// pipe syscall
+const SYSCALL_PIPE: usize = 59;
+/// read syscall
+const SYSCALL_READ: usize = 63;
 /// write syscall
 const SYSCALL_WRITE: usize = 64;
+/// fsync syscall
+const SYSCALL_FSYNC: usize = 82;
 /// exit syscall
 const SYSCALL_EXIT: usize = 93;
+/// futex syscall
+const SYSCALL_FUTEX: usize = 98;
+/// getitimer syscall
+const SYSCALL_GETITIMER: usize = 102;
+/// setitimer syscall
+const SYSCALL_SETITIMER: usize = 103;
 /// yield syscall
 const SYSCALL_YIELD: usize = 124;
+/// kill syscall
+const SYSCALL_KILL: usize = 129;
+/// sigaction syscall
+const SYSCALL_SIGACTION: usize = 134;
+/// sigprocmask syscall
+const SYSCALL_SIGPROCMASK: usize = 135;
+/// sigreturn syscall
+const SYSCALL_SIGRETURN: usize = 139;
 /// gettime syscall
 const SYSCALL_GET_TIME: usize = 169;
+/// getpid syscall
+const SYSCALL_GETPID: usize = 172;
+/// exec syscall
+const SYSCALL_EXEC: usize = 221;
+/// waitpid syscall
+const SYSCALL_WAITPID: usize = 260;
+/// spawn syscall
+const SYSCALL_SPAWN: usize = 400;
+/// list_apps syscall
+const SYSCALL_LIST_APPS: usize = 401;
 /// taskinfo syscall
 const SYSCALL_TASK_INFO: usize = 410;
+/// enable_deadlock_detect syscall
+const SYSCALL_ENABLE_DEADLOCK_DETECT: usize = 469;
+/// thread_create syscall
+const SYSCALL_THREAD_CREATE: usize = 1000;
+/// gettid syscall
+const SYSCALL_GETTID: usize = 1001;
+/// waittid syscall
+const SYSCALL_WAITTID: usize = 1002;
+/// mutex_create syscall
+const SYSCALL_MUTEX_CREATE: usize = 1010;
+/// mutex_lock syscall
+const SYSCALL_MUTEX_LOCK: usize = 1011;
+/// mutex_unlock syscall
+const SYSCALL_MUTEX_UNLOCK: usize = 1012;
+/// semaphore_create syscall
+const SYSCALL_SEMAPHORE_CREATE: usize = 1020;
+/// semaphore_up syscall
+const SYSCALL_SEMAPHORE_UP: usize = 1021;
+/// semaphore_down syscall
+const SYSCALL_SEMAPHORE_DOWN: usize = 1022;
+/// condvar_create syscall
+const SYSCALL_CONDVAR_CREATE: usize = 1030;
+/// condvar_signal syscall
+const SYSCALL_CONDVAR_SIGNAL: usize = 1031;
+/// condvar_wait syscall
+const SYSCALL_CONDVAR_WAIT: usize = 1032;
+/// alarm syscall
+const SYSCALL_ALARM: usize = 1040;
 
 mod fs;
 mod process;
+mod sync;
+mod thread;
 
 use fs::*;
 use process::*;
-use super::task::update_syscall_times;
+use sync::*;
+use thread::*;
+use super::task::{update_syscall_times, SignalAction};
+use alloc::vec::Vec;
+
+/// longest path taken by the syscalls, without the NUL
+const MAX_PATH_LEN: usize = 255;
+
+/// Copy the NUL-terminated string at `ptr`, or None if it is longer than
+/// `max_len`
+fn read_c_str(ptr: *const u8, max_len: usize) -> Option<Vec<u8>> {
+    let mut s = Vec::new();
+    for i in 0..=max_len {
+        let byte = unsafe { *ptr.add(i) };
+        if byte == 0 {
+            return Some(s);
+        }
+        s.push(byte);
+    }
+    None
+}
+
+/// Copy the NUL-terminated string at `ptr`, or None if it is longer than
+/// [`MAX_PATH_LEN`]
+fn read_path(ptr: *const u8) -> Option<Vec<u8>> {
+    read_c_str(ptr, MAX_PATH_LEN)
+}
 
 /// handle syscall exception with `syscall_id` and other arguments
-pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
+pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
     update_syscall_times(syscall_id);
     match syscall_id {
+        SYSCALL_DUP => sys_dup(args[0]),
+        SYSCALL_DUP3 => sys_dup3(args[0], args[1], args[2] as u32),
+        SYSCALL_IOCTL => sys_ioctl(args[0], args[1], args[2]),
+        SYSCALL_UNLINKAT => sys_unlinkat(args[1] as *const u8),
+        SYSCALL_LINKAT => sys_linkat(args[1] as *const u8, args[3] as *const u8),
+        SYSCALL_OPEN => sys_open(args[0] as *const u8, args[1] as u32),
+        SYSCALL_CLOSE => sys_close(args[0]),
+        SYSCALL_PIPE => sys_pipe(args[0] as *mut usize),
+        SYSCALL_READ => sys_read(args[0], args[1] as *mut u8, args[2]),
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+        SYSCALL_FSYNC => sys_fsync(args[0]),
         SYSCALL_EXIT => sys_exit(args[0] as i32),
+        SYSCALL_FUTEX => sys_futex(args[0], args[1], args[2] as u32, args[3]),
+        SYSCALL_GETITIMER => sys_getitimer(args[0], args[1] as *mut ITimerVal),
+        SYSCALL_SETITIMER => sys_setitimer(
+            args[0],
+            args[1] as *const ITimerVal,
+            args[2] as *mut ITimerVal,
+        ),
         SYSCALL_YIELD => sys_yield(),
+        SYSCALL_KILL => sys_kill(args[0], args[1] as u32),
+        SYSCALL_SIGACTION => sys_sigaction(
+            args[0] as i32,
+            args[1] as *const SignalAction,
+            args[2] as *mut SignalAction,
+        ),
+        SYSCALL_SIGPROCMASK => sys_sigprocmask(args[0] as u32),
+        SYSCALL_SIGRETURN => sys_sigreturn(),
         SYSCALL_GET_TIME => sys_get_time(args[0] as *mut TimeVal, args[1]),
+        SYSCALL_GETPID => sys_getpid(),
+        SYSCALL_EXEC => sys_exec(
+            args[0] as *const u8,
+            args[1] as *const *const u8,
+            args[2] as *const *const u8,
+        ),
+        SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
+        SYSCALL_SPAWN => sys_spawn(args[0] as *const u8),
+        SYSCALL_LIST_APPS => sys_list_apps(args[0] as *mut u8, args[1]),
         SYSCALL_TASK_INFO => sys_task_info(args[0] as *mut TaskInfo),
+        SYSCALL_ENABLE_DEADLOCK_DETECT => sys_enable_deadlock_detect(args[0]),
+        SYSCALL_THREAD_CREATE => sys_thread_create(args[0], args[1]),
+        SYSCALL_GETTID => sys_gettid(),
+        SYSCALL_WAITTID => sys_waittid(args[0]),
+        SYSCALL_MUTEX_CREATE => sys_mutex_create(args[0] == 1),
+        SYSCALL_MUTEX_LOCK => sys_mutex_lock(args[0]),
+        SYSCALL_MUTEX_UNLOCK => sys_mutex_unlock(args[0]),
+        SYSCALL_SEMAPHORE_CREATE => sys_semaphore_create(args[0]),
+        SYSCALL_SEMAPHORE_UP => sys_semaphore_up(args[0]),
+        SYSCALL_SEMAPHORE_DOWN => sys_semaphore_down(args[0]),
+        SYSCALL_CONDVAR_CREATE => sys_condvar_create(),
+        SYSCALL_CONDVAR_SIGNAL => sys_condvar_signal(args[0]),
+        SYSCALL_CONDVAR_WAIT => sys_condvar_wait(args[0], args[1]),
+        SYSCALL_ALARM => sys_alarm(args[0]),
         _ => panic!("Unsupported syscall_id: {}", syscall_id),
     }
 }
@@ -1,10 +1,18 @@
 //! Process management syscalls
 
+use super::{read_c_str, read_path};
 use crate::{
-    config::MAX_SYSCALL_NUM,
-    task::{exit_current_and_run_next, get_syscall_times, get_time_segment, suspend_current_and_run_next, TaskStatus},
+    config::{MAX_ARG_SIZE, MAX_SYSCALL_NUM},
+    loader::{get_app_id_by_name, get_app_names_table},
+    task::{
+        current_process, current_task, exit_current_and_run_next, get_itimer, get_syscall_times,
+        get_time_segment, pid2process, send_signal, set_itimer, sigreturn,
+        suspend_current_and_run_next, ITimer, ProcessControlBlock, SignalAction, SignalFlags,
+        TaskStatus, ITIMER_REAL,
+    },
     timer::get_time_us,
 };
+use alloc::vec::Vec;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -13,6 +21,38 @@ pub struct TimeVal {
     pub usec: usize,
 }
 
+/// Setting of an interval timer, as exchanged with `sys_setitimer`
+#[repr(C)]
+#[derive(Debug)]
+pub struct ITimerVal {
+    /// value the timer is rearmed with once expired
+    pub interval: TimeVal,
+    /// time left before the timer expires
+    pub value: TimeVal,
+}
+
+impl TimeVal {
+    fn from_us(us: usize) -> Self {
+        Self {
+            sec: us / 1_000_000,
+            usec: us % 1_000_000,
+        }
+    }
+    /// The time in microseconds, or None if it overflows
+    fn as_us(&self) -> Option<usize> {
+        self.sec.checked_mul(1_000_000)?.checked_add(self.usec)
+    }
+}
+
+impl From<ITimer> for ITimerVal {
+    fn from(itimer: ITimer) -> Self {
+        Self {
+            interval: TimeVal::from_us(itimer.interval_us),
+            value: TimeVal::from_us(itimer.value_us),
+        }
+    }
+}
+
 /// Task information
 #[allow(dead_code)]
 pub struct TaskInfo {
@@ -27,7 +67,7 @@ pub struct TaskInfo {
 /// task exits and submit an exit code
 pub fn sys_exit(exit_code: i32) -> ! {
     trace!("[kernel] Application exited with code {}", exit_code);
-    exit_current_and_run_next();
+    exit_current_and_run_next(exit_code);
     panic!("Unreachable in sys_exit!");
 }
 
@@ -65,3 +105,286 @@ pub fn sys_task_info(_ti: *mut TaskInfo) -> isize {
 
     0
 }
+
+/// kill syscall, sending signal `signum` to process `pid`
+///
+/// The signal goes to the main thread of the process. SIGKILL ends the
+/// process right away, even if all of its threads are blocked.
+pub fn sys_kill(pid: usize, signum: u32) -> isize {
+    trace!("kernel: sys_kill");
+    let Some(process) = pid2process(pid) else {
+        return -1;
+    };
+    let Some(signal) = SignalFlags::from_signum(signum as usize) else {
+        return -1;
+    };
+    if signal == SignalFlags::SIGKILL {
+        println!("[kernel] Application killed by {:?}.", signal);
+        process.exit(-(signum as i32));
+        return 0;
+    }
+    let main_thread = process
+        .inner_exclusive_access()
+        .tasks
+        .first()
+        .cloned()
+        .flatten();
+    match main_thread {
+        Some(task) => {
+            send_signal(&task, signal);
+            0
+        }
+        None => -1,
+    }
+}
+
+/// sigaction syscall, setting the action of signal `signum` to `*action` and
+/// storing the previous one at `old_action`, unless either pointer is null
+pub fn sys_sigaction(
+    signum: i32,
+    action: *const SignalAction,
+    old_action: *mut SignalAction,
+) -> isize {
+    trace!("kernel: sys_sigaction");
+    if signum < 0 {
+        return -1;
+    }
+    let Some(signal) = SignalFlags::from_signum(signum as usize) else {
+        return -1;
+    };
+    if SignalFlags::unmaskable().contains(signal) {
+        return -1;
+    }
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    if !old_action.is_null() {
+        unsafe {
+            *old_action = inner.signal_actions[signum as usize];
+        }
+    }
+    if !action.is_null() {
+        let mut action = unsafe { *action };
+        action.mask -= SignalFlags::unmaskable();
+        inner.signal_actions[signum as usize] = action;
+    }
+    0
+}
+
+/// sigprocmask syscall, setting the signal mask of the current thread
+///
+/// Returns the previous mask, or -1 if `mask` holds invalid signals.
+pub fn sys_sigprocmask(mask: u32) -> isize {
+    trace!("kernel: sys_sigprocmask");
+    let Some(mask) = SignalFlags::from_bits(mask) else {
+        return -1;
+    };
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    let old_mask = task_inner.signal_mask;
+    task_inner.signal_mask = mask - SignalFlags::unmaskable();
+    old_mask.bits() as isize
+}
+
+/// sigreturn syscall, leaving a signal handler
+///
+/// The context from before the handler is restored on the return to
+/// userspace, `a0` included. Returns -1 outside of a handler.
+pub fn sys_sigreturn() -> isize {
+    trace!("kernel: sys_sigreturn");
+    sigreturn()
+}
+
+/// getitimer syscall, storing the setting of interval timer `which` at `curr_value`
+///
+/// Returns -1 if there is no such timer, or `curr_value` is null or not in the
+/// memory of the process.
+pub fn sys_getitimer(which: usize, curr_value: *mut ITimerVal) -> isize {
+    trace!("kernel: sys_getitimer");
+    let process = current_process();
+    if curr_value.is_null()
+        || !process
+            .inner_exclusive_access()
+            .in_user_memory(curr_value as usize, core::mem::size_of::<ITimerVal>())
+    {
+        return -1;
+    }
+    let Some(itimer) = get_itimer(&process, which) else {
+        return -1;
+    };
+    unsafe {
+        *curr_value = itimer.into();
+    }
+    0
+}
+
+/// setitimer syscall, setting interval timer `which` to `*new_value` and
+/// storing the previous setting at `old_value`, unless it is null
+///
+/// A zero value disarms the timer. Returns -1 if there is no such timer,
+/// `new_value` is null, or a time is not normalized or too long.
+pub fn sys_setitimer(
+    which: usize,
+    new_value: *const ITimerVal,
+    old_value: *mut ITimerVal,
+) -> isize {
+    trace!("kernel: sys_setitimer");
+    if new_value.is_null() {
+        return -1;
+    }
+    let new_value = unsafe { &*new_value };
+    if new_value.interval.usec >= 1_000_000 || new_value.value.usec >= 1_000_000 {
+        return -1;
+    }
+    let (Some(value_us), Some(interval_us)) = (new_value.value.as_us(), new_value.interval.as_us())
+    else {
+        return -1;
+    };
+    let itimer = ITimer {
+        value_us,
+        interval_us,
+    };
+    let Some(old) = set_itimer(&current_process(), which, itimer) else {
+        return -1;
+    };
+    if !old_value.is_null() {
+        unsafe {
+            *old_value = old.into();
+        }
+    }
+    0
+}
+
+/// alarm syscall, sending SIGALRM to the process after `seconds` seconds, or
+/// cancelling the pending alarm if it is 0
+///
+/// It shares `ITIMER_REAL` with `sys_setitimer`. Returns the seconds left
+/// before the previous alarm, rounded up, or -1 if `seconds` is too long.
+pub fn sys_alarm(seconds: usize) -> isize {
+    trace!("kernel: sys_alarm");
+    let Some(value_us) = seconds.checked_mul(1_000_000) else {
+        return -1;
+    };
+    let itimer = ITimer {
+        value_us,
+        interval_us: 0,
+    };
+    let old = set_itimer(&current_process(), ITIMER_REAL, itimer).unwrap();
+    old.value_us.div_ceil(1_000_000) as isize
+}
+
+/// Copy the strings of the NULL-terminated array at `ptr`, which may be null
+/// itself for no strings, or None if they are longer than [`MAX_ARG_SIZE`]
+/// in total
+fn read_str_array(ptr: *const *const u8) -> Option<Vec<Vec<u8>>> {
+    let mut strs = Vec::new();
+    if ptr.is_null() {
+        return Some(strs);
+    }
+    let mut total = 0;
+    loop {
+        let str_ptr = unsafe { *ptr.add(strs.len()) };
+        if str_ptr.is_null() {
+            return Some(strs);
+        }
+        let s = read_c_str(str_ptr, MAX_ARG_SIZE - total)?;
+        total += s.len() + 1;
+        strs.push(s);
+        if total > MAX_ARG_SIZE {
+            return None;
+        }
+    }
+}
+
+/// getpid syscall
+pub fn sys_getpid() -> isize {
+    trace!("kernel: sys_getpid");
+    current_process().pid.0 as isize
+}
+
+/// spawn syscall, starting the app named `path` in a new child process
+///
+/// Returns the pid of the child, or -1 if there is no such app or it is
+/// already running.
+pub fn sys_spawn(path: *const u8) -> isize {
+    trace!("kernel: sys_spawn");
+    let Some(app_id) = read_path(path).and_then(|path| get_app_id_by_name(&path)) else {
+        return -1;
+    };
+    match ProcessControlBlock::new(app_id, Some(&current_process())) {
+        Some(child) => child.pid.0 as isize,
+        None => -1,
+    }
+}
+
+/// exec syscall, replacing the app of the current process with the one named
+/// `path`, run with the NULL-terminated arrays of arguments `argv` and
+/// environment `envp`
+///
+/// The new app finds `argc` in `a0`, `argv` in `a1` and `envp` in `a2`. It
+/// does not return on success. Returns -1 if there is no such app, it is run
+/// by another process, other threads of the process are still alive, or the
+/// arguments are too long.
+pub fn sys_exec(path: *const u8, argv: *const *const u8, envp: *const *const u8) -> isize {
+    trace!("kernel: sys_exec");
+    let Some(app_id) = read_path(path).and_then(|path| get_app_id_by_name(&path)) else {
+        return -1;
+    };
+    // copy them now, as they are gone with the old image
+    let (Some(args), Some(envs)) = (read_str_array(argv), read_str_array(envp)) else {
+        return -1;
+    };
+    let task = current_task().unwrap();
+    let Some(trap_cx) = current_process().exec(&task, app_id, &args, &envs) else {
+        return -1;
+    };
+    task.inner_exclusive_access().new_trap_cx = Some(trap_cx);
+    0
+}
+
+/// waitpid syscall, collecting the exit code of child `pid` into `exit_code`,
+/// or of any child if `pid` is -1
+///
+/// Returns the pid of the child, -1 if there is no such child, or -2 if it
+/// has not exited yet.
+pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
+    trace!("kernel: sys_waitpid");
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    if !inner
+        .children
+        .iter()
+        .any(|child| pid == -1 || child.pid.0 as isize == pid)
+    {
+        return -1;
+    }
+    let Some(idx) = inner.children.iter().position(|child| {
+        (pid == -1 || child.pid.0 as isize == pid) && child.inner_exclusive_access().is_zombie
+    }) else {
+        return -2;
+    };
+    let child = inner.children.remove(idx);
+    drop(inner);
+    let child_pid = child.pid.0;
+    let child_exit_code = child.inner_exclusive_access().exit_code;
+    if !exit_code.is_null() {
+        unsafe {
+            *exit_code = child_exit_code;
+        }
+    }
+    child_pid as isize
+}
+
+/// list_apps syscall, copying the names of the apps, each ended by a NUL, into
+/// `buf` of `len` bytes
+///
+/// Returns the length of the whole list, which is only partly copied if it is
+/// longer than `len`.
+pub fn sys_list_apps(buf: *mut u8, len: usize) -> isize {
+    trace!("kernel: sys_list_apps");
+    let names = get_app_names_table();
+    let copied = names.len().min(len);
+    unsafe {
+        core::ptr::copy_nonoverlapping(names.as_ptr(), buf, copied);
+    }
+    names.len() as isize
+}
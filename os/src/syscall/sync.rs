@@ -0,0 +1,263 @@
+//! Synchronization syscalls
+//!
+//! Mutexes, semaphores and condition variables live in per-process tables, and
+//! userspace refers to them by their index in the table.
+//!
+//! Futexes are the exception: they are keyed by the address of a word in user
+//! memory and need no creation.
+//!
+//! Every lock and down is also recorded by the banker of the process, which
+//! turns down requests that may deadlock once detection is enabled.
+//!
+//! A signal the thread does not mask cuts its lock, down, condvar or futex
+//! wait short, and the wait fails with `EINTR`. So does the end of its
+//! process, after which the thread exits anyway.
+
+use crate::fs::EINTR;
+use crate::sync::{
+    futex_addr_valid, futex_wait, futex_wake, Condvar, FutexError, MutexBlocking, MutexSpin,
+    Resource, Semaphore, UserMutex,
+};
+use crate::task::{current_process, current_task};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Put `item` into the first free slot of `list` and return the slot index
+fn insert_resource<T>(list: &mut Vec<Option<T>>, item: T) -> usize {
+    if let Some(id) = list.iter().position(|slot| slot.is_none()) {
+        list[id] = Some(item);
+        id
+    } else {
+        list.push(Some(item));
+        list.len() - 1
+    }
+}
+
+/// Returned instead of waiting when deadlock detection finds an unsafe state
+const EDEADLK: isize = -0xDEAD;
+
+/// Returned by futex wait when the word no longer holds the expected value
+const EAGAIN: isize = -11;
+/// Returned by futex wait when the timeout expired
+const ETIMEDOUT: isize = -110;
+
+/// futex op: sleep while the word holds `val`
+const FUTEX_WAIT: usize = 0;
+/// futex op: wake at most `val` sleepers
+const FUTEX_WAKE: usize = 1;
+/// futex op: like `FUTEX_WAIT`, but give up after `timeout_ms` milliseconds
+const FUTEX_WAIT_TIMEOUT: usize = 2;
+
+/// Look up resource `id` in `list`, which may be any value from userspace
+fn get_resource<T: Clone>(list: &[Option<T>], id: usize) -> Option<T> {
+    list.get(id).and_then(|slot| slot.clone())
+}
+
+/// mutex create syscall, a blocking mutex if `blocking` else a spinning one
+pub fn sys_mutex_create(blocking: bool) -> isize {
+    trace!("kernel: sys_mutex_create");
+    let process = current_process();
+    let mutex: Arc<dyn UserMutex> = if blocking {
+        Arc::new(MutexBlocking::new())
+    } else {
+        Arc::new(MutexSpin::new())
+    };
+    let mut process_inner = process.inner_exclusive_access();
+    let id = insert_resource(&mut process_inner.mutex_list, mutex);
+    process_inner.banker.add_resource(Resource::Mutex(id), 1);
+    id as isize
+}
+
+/// mutex lock syscall
+pub fn sys_mutex_lock(mutex_id: usize) -> isize {
+    trace!("kernel: sys_mutex_lock");
+    let process = current_process();
+    let tid = current_task().unwrap().tid;
+    let mut process_inner = process.inner_exclusive_access();
+    let Some(mutex) = get_resource(&process_inner.mutex_list, mutex_id) else {
+        return -1;
+    };
+    if !process_inner.banker.request(tid, Resource::Mutex(mutex_id)) {
+        return EDEADLK;
+    }
+    drop(process_inner);
+    if !mutex.lock() {
+        process
+            .inner_exclusive_access()
+            .banker
+            .cancel(tid, Resource::Mutex(mutex_id));
+        return EINTR;
+    }
+    process
+        .inner_exclusive_access()
+        .banker
+        .acquired(tid, Resource::Mutex(mutex_id));
+    0
+}
+
+/// mutex unlock syscall, failing if the current thread does not hold the mutex
+pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
+    trace!("kernel: sys_mutex_unlock");
+    let process = current_process();
+    let tid = current_task().unwrap().tid;
+    let mut process_inner = process.inner_exclusive_access();
+    let Some(mutex) = get_resource(&process_inner.mutex_list, mutex_id) else {
+        return -1;
+    };
+    // no other thread can take the mutex from us, so it stays held until unlocked
+    if !mutex.is_held() {
+        return -1;
+    }
+    process_inner.banker.release(tid, Resource::Mutex(mutex_id));
+    drop(process_inner);
+    drop(process);
+    mutex.unlock();
+    0
+}
+
+/// semaphore create syscall
+pub fn sys_semaphore_create(res_count: usize) -> isize {
+    trace!("kernel: sys_semaphore_create");
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let id = insert_resource(
+        &mut process_inner.semaphore_list,
+        Arc::new(Semaphore::new(res_count)),
+    );
+    process_inner
+        .banker
+        .add_resource(Resource::Semaphore(id), res_count);
+    id as isize
+}
+
+/// semaphore up syscall
+pub fn sys_semaphore_up(sem_id: usize) -> isize {
+    trace!("kernel: sys_semaphore_up");
+    let process = current_process();
+    let tid = current_task().unwrap().tid;
+    let mut process_inner = process.inner_exclusive_access();
+    let Some(sem) = get_resource(&process_inner.semaphore_list, sem_id) else {
+        return -1;
+    };
+    process_inner
+        .banker
+        .release(tid, Resource::Semaphore(sem_id));
+    drop(process_inner);
+    sem.up();
+    0
+}
+
+/// semaphore down syscall
+pub fn sys_semaphore_down(sem_id: usize) -> isize {
+    trace!("kernel: sys_semaphore_down");
+    let process = current_process();
+    let tid = current_task().unwrap().tid;
+    let mut process_inner = process.inner_exclusive_access();
+    let Some(sem) = get_resource(&process_inner.semaphore_list, sem_id) else {
+        return -1;
+    };
+    if !process_inner
+        .banker
+        .request(tid, Resource::Semaphore(sem_id))
+    {
+        return EDEADLK;
+    }
+    drop(process_inner);
+    if !sem.down() {
+        process
+            .inner_exclusive_access()
+            .banker
+            .cancel(tid, Resource::Semaphore(sem_id));
+        return EINTR;
+    }
+    process
+        .inner_exclusive_access()
+        .banker
+        .acquired(tid, Resource::Semaphore(sem_id));
+    0
+}
+
+/// condvar create syscall
+pub fn sys_condvar_create() -> isize {
+    trace!("kernel: sys_condvar_create");
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    insert_resource(&mut process_inner.condvar_list, Arc::new(Condvar::new())) as isize
+}
+
+/// condvar signal syscall
+pub fn sys_condvar_signal(condvar_id: usize) -> isize {
+    trace!("kernel: sys_condvar_signal");
+    let process = current_process();
+    let process_inner = process.inner_exclusive_access();
+    let Some(condvar) = get_resource(&process_inner.condvar_list, condvar_id) else {
+        return -1;
+    };
+    drop(process_inner);
+    condvar.signal();
+    0
+}
+
+/// condvar wait syscall, releasing mutex `mutex_id` while waiting, which the
+/// current thread must hold
+pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
+    trace!("kernel: sys_condvar_wait");
+    let process = current_process();
+    let tid = current_task().unwrap().tid;
+    let mut process_inner = process.inner_exclusive_access();
+    let (Some(condvar), Some(mutex)) = (
+        get_resource(&process_inner.condvar_list, condvar_id),
+        get_resource(&process_inner.mutex_list, mutex_id),
+    ) else {
+        return -1;
+    };
+    if !mutex.is_held() {
+        return -1;
+    }
+    process_inner.banker.release(tid, Resource::Mutex(mutex_id));
+    drop(process_inner);
+    if !condvar.wait_with_mutex(mutex) {
+        return EINTR;
+    }
+    process
+        .inner_exclusive_access()
+        .banker
+        .acquired(tid, Resource::Mutex(mutex_id));
+    0
+}
+
+/// enable deadlock detection syscall, turning it on if `enabled` is 1 and off if 0
+pub fn sys_enable_deadlock_detect(enabled: usize) -> isize {
+    trace!("kernel: sys_enable_deadlock_detect");
+    let enabled = match enabled {
+        0 => false,
+        1 => true,
+        _ => return -1,
+    };
+    let process = current_process();
+    process.inner_exclusive_access().banker.set_enabled(enabled);
+    0
+}
+
+/// futex syscall on the 32-bit word at `uaddr`
+///
+/// Waits return 0 once woken, `EAGAIN` if the word does not hold `val` and
+/// `ETIMEDOUT` if the timeout expired. Wakes return the number of tasks woken.
+pub fn sys_futex(uaddr: usize, op: usize, val: u32, timeout_ms: usize) -> isize {
+    trace!("kernel: sys_futex");
+    if !futex_addr_valid(uaddr) {
+        return -1;
+    }
+    let ret = match op {
+        FUTEX_WAIT => futex_wait(uaddr, val, None),
+        FUTEX_WAIT_TIMEOUT => futex_wait(uaddr, val, Some(timeout_ms)),
+        FUTEX_WAKE => return futex_wake(uaddr, val as usize) as isize,
+        _ => return -1,
+    };
+    match ret {
+        Ok(()) => 0,
+        Err(FutexError::Again) => EAGAIN,
+        Err(FutexError::TimedOut) => ETIMEDOUT,
+        Err(FutexError::Interrupted) => EINTR,
+    }
+}
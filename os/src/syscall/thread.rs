@@ -0,0 +1,51 @@
+//! Thread management syscalls
+
+use crate::task::{add_task, current_process, current_task};
+
+/// thread create syscall, starting a thread at `entry` with `arg` as its argument
+///
+/// Returns the id of the new thread, or -1 if no stack is left for it.
+pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
+    trace!("kernel: sys_thread_create");
+    let process = current_process();
+    let Some(task) = process.create_thread(entry, arg) else {
+        return -1;
+    };
+    let tid = task.tid;
+    add_task(task);
+    tid as isize
+}
+
+/// gettid syscall
+pub fn sys_gettid() -> isize {
+    trace!("kernel: sys_gettid");
+    current_task().unwrap().tid as isize
+}
+
+/// waittid syscall, freeing thread `tid` of the current process once it has exited
+///
+/// Returns its exit code, -1 if there is no such thread or it is the caller,
+/// and -2 if it has not exited yet.
+pub fn sys_waittid(tid: usize) -> isize {
+    trace!("kernel: sys_waittid");
+    let task = current_task().unwrap();
+    if task.tid == tid {
+        return -1;
+    }
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let Some(waited_task) = process_inner.tasks.get(tid).and_then(|slot| slot.clone()) else {
+        return -1;
+    };
+    let exit_code = waited_task.inner_exclusive_access().exit_code;
+    let Some(exit_code) = exit_code else {
+        return -2;
+    };
+    process_inner.tasks[tid] = None;
+    process_inner.task_res_allocator.dealloc(tid);
+    drop(process_inner);
+    // the thread may still be switching out on its hart, which holds the
+    // last reference then and frees its kernel stack after that
+    drop(waited_task);
+    exit_code as isize
+}
@@ -0,0 +1,56 @@
+//! Implementation of [`FpContext`]
+//!
+//! The floating-point registers of a task are switched lazily, following
+//! `sstatus.FS` of the task, which is kept in its trap context:
+//!
+//! - `Off`: the task has never used them. A new task starts so, and its first
+//!   floating-point instruction traps, which turns them on.
+//! - `Clean`: the registers of the hart match the saved [`FpContext`].
+//! - `Dirty`: the task has changed them since, so they are saved on its next
+//!   trap and marked `Clean` again.
+//!
+//! The kernel itself never uses them, so a task that gets a hart back only has
+//! to reload its registers if they are not `Off`.
+
+use core::arch::global_asm;
+use riscv::register::sstatus::{self, FS};
+
+global_asm!(include_str!("fp.S"));
+
+extern "C" {
+    fn __save_fp(fp_cx_ptr: *mut FpContext);
+    fn __restore_fp(fp_cx_ptr: *const FpContext);
+}
+
+/// Floating-point registers f0-f31 and fcsr of a task
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct FpContext {
+    f: [u64; 32],
+    fcsr: usize,
+}
+
+impl FpContext {
+    /// Create the context of a task that has not used floating point yet
+    pub fn zero_init() -> Self {
+        Self {
+            f: [0; 32],
+            fcsr: 0,
+        }
+    }
+    /// Save the registers of this hart, which `sstatus.FS` must allow
+    pub fn save(&mut self) {
+        unsafe {
+            __save_fp(self);
+        }
+    }
+    /// Load the registers of this hart
+    pub fn restore(&self) {
+        unsafe {
+            // the live FS belongs to whatever ran before, and is reset by
+            // `__restore` from the trap context
+            sstatus::set_fs(FS::Clean);
+            __restore_fp(self);
+        }
+    }
+}
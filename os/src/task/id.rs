@@ -0,0 +1,148 @@
+//! Allocators of process ids, thread ids and stacks
+//!
+//! There is no paging yet, so kernel and user stacks of threads come from two
+//! static pools of [`MAX_THREAD_NUM`] stacks each, shared by all processes.
+//! Every id or stack is held by an RAII handle and recycled when dropped.
+
+use crate::config::{KERNEL_STACK_SIZE, MAX_THREAD_NUM, USER_STACK_SIZE};
+use crate::sync::SpinLock;
+use crate::trap::TrapContext;
+use alloc::vec::Vec;
+
+/// Hands out the smallest-possible unused ids below a limit
+pub struct RecycleAllocator {
+    current: usize,
+    recycled: Vec<usize>,
+    limit: usize,
+}
+
+impl RecycleAllocator {
+    /// Create an allocator of ids in `0..limit`
+    pub const fn new(limit: usize) -> Self {
+        Self {
+            current: 0,
+            recycled: Vec::new(),
+            limit,
+        }
+    }
+    /// Allocate an id, or None if all of them are in use
+    pub fn alloc(&mut self) -> Option<usize> {
+        if let Some(id) = self.recycled.pop() {
+            Some(id)
+        } else if self.current < self.limit {
+            self.current += 1;
+            Some(self.current - 1)
+        } else {
+            None
+        }
+    }
+    /// Give `id` back
+    pub fn dealloc(&mut self, id: usize) {
+        assert!(id < self.current);
+        assert!(
+            !self.recycled.iter().any(|i| *i == id),
+            "id {} has been deallocated!",
+            id
+        );
+        self.recycled.push(id);
+    }
+}
+
+static PID_ALLOCATOR: SpinLock<RecycleAllocator> = SpinLock::new(RecycleAllocator::new(usize::MAX));
+static KSTACK_ALLOCATOR: SpinLock<RecycleAllocator> =
+    SpinLock::new(RecycleAllocator::new(MAX_THREAD_NUM));
+static USTACK_ALLOCATOR: SpinLock<RecycleAllocator> =
+    SpinLock::new(RecycleAllocator::new(MAX_THREAD_NUM));
+
+/// A process id, given back on drop
+pub struct PidHandle(pub usize);
+
+impl Drop for PidHandle {
+    fn drop(&mut self) {
+        PID_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
+
+/// Allocate a process id
+pub fn pid_alloc() -> PidHandle {
+    PidHandle(PID_ALLOCATOR.lock().alloc().unwrap())
+}
+
+#[repr(align(4096))]
+#[derive(Copy, Clone)]
+struct KernelStackData([u8; KERNEL_STACK_SIZE]);
+
+#[repr(align(4096))]
+#[derive(Copy, Clone)]
+struct UserStackData([u8; USER_STACK_SIZE]);
+
+static KERNEL_STACK: [KernelStackData; MAX_THREAD_NUM] =
+    [KernelStackData([0; KERNEL_STACK_SIZE]); MAX_THREAD_NUM];
+
+static USER_STACK: [UserStackData; MAX_THREAD_NUM] =
+    [UserStackData([0; USER_STACK_SIZE]); MAX_THREAD_NUM];
+
+/// The kernel stack of a thread, given back to the pool on drop
+pub struct KernelStack(usize);
+
+/// Take a kernel stack from the pool, or None if it is empty
+pub fn kstack_alloc() -> Option<KernelStack> {
+    KSTACK_ALLOCATOR.lock().alloc().map(KernelStack)
+}
+
+impl KernelStack {
+    /// Get the top of the stack
+    pub fn get_top(&self) -> usize {
+        KERNEL_STACK[self.0].0.as_ptr() as usize + KERNEL_STACK_SIZE
+    }
+    /// Get the address of the trap context on the top of the stack, where
+    /// `__alltraps` saves it
+    fn get_trap_cx_ptr(&self) -> *mut TrapContext {
+        (self.get_top() - core::mem::size_of::<TrapContext>()) as *mut TrapContext
+    }
+    /// Put `trap_cx` on the top of the stack and return its address
+    pub fn push_context(&self, trap_cx: TrapContext) -> usize {
+        let trap_cx_ptr = self.get_trap_cx_ptr();
+        unsafe {
+            *trap_cx_ptr = trap_cx;
+        }
+        trap_cx_ptr as usize
+    }
+    /// Get the trap context of the thread owning the stack, which is only
+    /// meaningful while the thread is in the kernel or not running
+    pub fn get_trap_cx(&self) -> &'static mut TrapContext {
+        unsafe { &mut *self.get_trap_cx_ptr() }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        KSTACK_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
+
+/// The user stack of a thread, given back to the pool on drop
+pub struct UserStack(usize);
+
+/// Take a user stack from the pool, or None if it is empty
+pub fn ustack_alloc() -> Option<UserStack> {
+    USTACK_ALLOCATOR.lock().alloc().map(UserStack)
+}
+
+impl UserStack {
+    /// Get the top of the stack
+    pub fn get_top(&self) -> usize {
+        USER_STACK[self.0].0.as_ptr() as usize + USER_STACK_SIZE
+    }
+    /// Whether the `len` bytes at `start` lie inside the stack
+    pub fn contains(&self, start: usize, len: usize) -> bool {
+        let top = self.get_top();
+        start >= top - USER_STACK_SIZE && start.checked_add(len).is_some_and(|end| end <= top)
+    }
+}
+
+impl Drop for UserStack {
+    fn drop(&mut self) {
+        USTACK_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
@@ -0,0 +1,208 @@
+//! Time accounting and interval timers
+//!
+//! Every task records the time it has spent running in userspace and in the
+//! kernel. The time since the last stamp is charged on each trap from
+//! userspace, each return to it, and each switch out of the task.
+//!
+//! A process has three interval timers, as with `setitimer` on Unix:
+//!
+//! - `ITIMER_REAL` counts wall-clock time in the timer queue and sends SIGALRM.
+//! - `ITIMER_VIRTUAL` counts the user time of its threads and sends SIGVTALRM.
+//! - `ITIMER_PROF` counts their user and kernel time and sends SIGPROF.
+//!
+//! The signal of an expired timer goes to the main thread for `ITIMER_REAL`,
+//! and to the thread whose time made it expire for the other two.
+
+use super::{send_signal, ProcessControlBlock, SignalFlags, TaskControlBlock};
+use crate::timer::{add_timer, get_time_us, remove_timer};
+use alloc::sync::{Arc, Weak};
+
+/// timer of wall-clock time
+pub const ITIMER_REAL: usize = 0;
+/// timer of user time
+pub const ITIMER_VIRTUAL: usize = 1;
+/// timer of user and kernel time
+pub const ITIMER_PROF: usize = 2;
+
+/// The setting of an interval timer, in microseconds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ITimer {
+    /// time left before the timer expires, 0 if it is disarmed
+    pub value_us: usize,
+    /// value the timer is rearmed with once expired, 0 for a one-shot timer
+    pub interval_us: usize,
+}
+
+impl ITimer {
+    /// Count `delta_us` down, returning true if the timer expires
+    fn tick(&mut self, delta_us: usize) -> bool {
+        if self.value_us == 0 {
+            return false;
+        }
+        if self.value_us > delta_us {
+            self.value_us -= delta_us;
+            return false;
+        }
+        self.value_us = self.interval_us;
+        true
+    }
+}
+
+/// The interval timers of a process
+#[derive(Default)]
+pub struct ITimers {
+    /// deadline of `ITIMER_REAL` since boot, 0 if it is disarmed
+    real_deadline_us: usize,
+    /// interval of `ITIMER_REAL`
+    real_interval_us: usize,
+    /// entry of `ITIMER_REAL` in the timer queue
+    real_timer_id: Option<usize>,
+    /// bumped whenever `ITIMER_REAL` is armed, so that a callback knows
+    /// whether it is still the current one
+    real_generation: usize,
+    /// `ITIMER_VIRTUAL`
+    virt: ITimer,
+    /// `ITIMER_PROF`
+    prof: ITimer,
+}
+
+impl ITimers {
+    /// Get timer `which`, or None if there is no such timer
+    fn get(&self, which: usize) -> Option<ITimer> {
+        match which {
+            ITIMER_REAL => Some(ITimer {
+                value_us: self.real_deadline_us.saturating_sub(get_time_us()),
+                interval_us: self.real_interval_us,
+            }),
+            ITIMER_VIRTUAL => Some(self.virt),
+            ITIMER_PROF => Some(self.prof),
+            _ => None,
+        }
+    }
+    /// Take the entry of `ITIMER_REAL` in the timer queue, which the caller
+    /// cancels once the lock of the process is released
+    pub fn take_real_timer(&mut self) -> Option<usize> {
+        self.real_timer_id.take()
+    }
+}
+
+/// Get timer `which` of `process`, or None if there is no such timer
+pub fn get_itimer(process: &ProcessControlBlock, which: usize) -> Option<ITimer> {
+    process.inner_exclusive_access().itimers.get(which)
+}
+
+/// Set timer `which` of `process` to `itimer`, returning the previous setting,
+/// or None if there is no such timer
+pub fn set_itimer(
+    process: &Arc<ProcessControlBlock>,
+    which: usize,
+    itimer: ITimer,
+) -> Option<ITimer> {
+    let mut inner = process.inner_exclusive_access();
+    let old = inner.itimers.get(which)?;
+    match which {
+        ITIMER_REAL => {
+            let old_timer_id = inner.itimers.real_timer_id.take();
+            inner.itimers.real_interval_us = itimer.interval_us;
+            if itimer.value_us == 0 {
+                inner.itimers.real_deadline_us = 0;
+                // a callback still on its way finds a new generation
+                inner.itimers.real_generation += 1;
+            } else {
+                inner.itimers.real_deadline_us = get_time_us().saturating_add(itimer.value_us);
+                arm_real_timer(process, &mut inner.itimers);
+            }
+            drop(inner);
+            if let Some(id) = old_timer_id {
+                remove_timer(id);
+            }
+        }
+        ITIMER_VIRTUAL => inner.itimers.virt = itimer,
+        _ => inner.itimers.prof = itimer,
+    }
+    Some(old)
+}
+
+/// Put `ITIMER_REAL` of `process` into the timer queue at its deadline
+fn arm_real_timer(process: &Arc<ProcessControlBlock>, itimers: &mut ITimers) {
+    itimers.real_generation += 1;
+    let generation = itimers.real_generation;
+    let process = Arc::downgrade(process);
+    let expire_ms = itimers.real_deadline_us.div_ceil(1000);
+    itimers.real_timer_id = Some(add_timer(expire_ms, move || {
+        real_timer_expired(process, generation)
+    }));
+}
+
+/// Callback of `ITIMER_REAL` armed as `generation`
+fn real_timer_expired(process: Weak<ProcessControlBlock>, generation: usize) {
+    let Some(process) = process.upgrade() else {
+        return;
+    };
+    let mut inner = process.inner_exclusive_access();
+    if inner.is_zombie || inner.itimers.real_generation != generation {
+        return;
+    }
+    if inner.itimers.real_interval_us == 0 {
+        inner.itimers.real_deadline_us = 0;
+        inner.itimers.real_timer_id = None;
+    } else {
+        inner.itimers.real_deadline_us = inner
+            .itimers
+            .real_deadline_us
+            .saturating_add(inner.itimers.real_interval_us);
+        arm_real_timer(&process, &mut inner.itimers);
+    }
+    let main_thread = inner.tasks.first().cloned().flatten();
+    drop(inner);
+    if let Some(task) = main_thread {
+        send_signal(&task, SignalFlags::SIGALRM);
+    }
+}
+
+/// Start counting the time of `task`, which is about to run
+pub fn stamp_time(task: &TaskControlBlock) {
+    task.inner_exclusive_access().time_stamp_us = get_time_us();
+}
+
+/// Charge the time since the last stamp to `task` as user time, on a trap
+/// from userspace
+pub fn charge_user_time(task: &Arc<TaskControlBlock>) {
+    charge_time(task, true);
+}
+
+/// Charge the time since the last stamp to `task` as kernel time, on a return
+/// to userspace or when it gives up its hart
+pub fn charge_kernel_time(task: &Arc<TaskControlBlock>) {
+    charge_time(task, false);
+}
+
+/// Charge the time since the last stamp to `task`, and count it down on the
+/// timers of its process
+fn charge_time(task: &Arc<TaskControlBlock>, user: bool) {
+    let now = get_time_us();
+    let mut task_inner = task.inner_exclusive_access();
+    let delta_us = now - task_inner.time_stamp_us;
+    task_inner.time_stamp_us = now;
+    if user {
+        task_inner.user_time_us += delta_us;
+    } else {
+        task_inner.kernel_time_us += delta_us;
+    }
+    drop(task_inner);
+    let Some(process) = task.process.upgrade() else {
+        return;
+    };
+    let mut inner = process.inner_exclusive_access();
+    let mut signals = SignalFlags::empty();
+    if user && inner.itimers.virt.tick(delta_us) {
+        signals |= SignalFlags::SIGVTALRM;
+    }
+    if inner.itimers.prof.tick(delta_us) {
+        signals |= SignalFlags::SIGPROF;
+    }
+    drop(inner);
+    if !signals.is_empty() {
+        send_signal(task, signals);
+    }
+}
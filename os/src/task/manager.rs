@@ -0,0 +1,73 @@
+//! Implementation of [`TaskManager`]
+//!
+//! The run queue is shared by all harts: a hart that has nothing to do takes
+//! the first task from it, and a task that gives up its hart is put back at
+//! the end of it.
+
+use super::TaskControlBlock;
+use crate::sync::SpinLock;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use lazy_static::*;
+
+/// A FIFO run queue of `Ready` tasks, shared by all harts
+pub struct TaskManager {
+    /// tasks that are ready to run
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+    /// number of processes that have not exited yet
+    alive: usize,
+}
+
+impl TaskManager {
+    /// Create an empty TaskManager
+    fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+            alive: 0,
+        }
+    }
+    /// Add the main thread of a newly created process to the run queue
+    pub fn spawn(&mut self, task: Arc<TaskControlBlock>) {
+        self.alive += 1;
+        self.add(task);
+    }
+    /// Put a task that is ready to run back to the run queue
+    pub fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    /// Find next task to run, the one that has been waiting for the longest time.
+    ///
+    /// Only `Ready` tasks are ever in the run queue: a `Blocked` task sits in
+    /// the wait queue of what it waits for, until `wakeup_task` puts it back.
+    pub fn find_next_task(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+    /// Account for a process whose main thread has exited and been switched out
+    pub fn mark_exited(&mut self) {
+        self.alive -= 1;
+    }
+    /// Whether all processes ever spawned have exited
+    pub fn all_exited(&self) -> bool {
+        self.alive == 0
+    }
+}
+
+lazy_static! {
+    /// Global variable: TASK_MANAGER
+    pub static ref TASK_MANAGER: SpinLock<TaskManager> = SpinLock::new(TaskManager::new());
+}
+
+/// Add the main thread of a newly created process to the run queue
+pub fn spawn_task(task: Arc<TaskControlBlock>) {
+    TASK_MANAGER.lock().spawn(task);
+}
+
+/// Put a task back to the run queue
+pub fn add_task(task: Arc<TaskControlBlock>) {
+    TASK_MANAGER.lock().add(task);
+}
+
+/// Take the next task to run out of the run queue
+pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
+    TASK_MANAGER.lock().find_next_task()
+}
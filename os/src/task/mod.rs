@@ -3,236 +3,245 @@
 //! Everything about task management, like starting and switching tasks is
 //! implemented here.
 //!
-//! A single global instance of [`TaskManager`] called `TASK_MANAGER` controls
-//! all the tasks in the operating system.
+//! A single global instance of [`TaskManager`] called `TASK_MANAGER` holds the
+//! run queue shared by all harts, while each hart records the task it is
+//! running in its own [`Processor`].
+//!
+//! Every app runs as a [`ProcessControlBlock`], and the tasks being scheduled
+//! are the threads of these processes.
 //!
 //! Be careful when you see `__switch` ASM function in `switch.S`. Control flow around this function
 //! might not be what you expect.
 
 mod context;
+mod fp;
+mod id;
+mod itimer;
+mod manager;
+mod process;
+mod processor;
+mod signal;
 mod switch;
 #[allow(clippy::module_inception)]
 mod task;
 
-use crate::config::{MAX_APP_NUM, MAX_SYSCALL_NUM, SYSCALL_NUM};
-use crate::loader::{get_num_app, init_app_cx};
-use crate::sync::UPSafeCell;
+use crate::config::{INIT_APP, MAX_SYSCALL_NUM};
+use crate::loader::{get_app_id_by_name, get_num_app};
 use crate::timer::get_time_ms;
-use lazy_static::*;
+use crate::trap::TrapContext;
+use alloc::sync::Arc;
+pub use process::{pid2process, ProcessControlBlock, ProcessControlBlockInner};
+use riscv::register::sstatus::FS;
 use switch::__switch;
-pub use task::{TaskControlBlock, TaskStatus, IDTimesPair};
+pub use task::{args_size, IDTimesPair, TaskControlBlock, TaskControlBlockInner, TaskStatus};
 
 pub use context::TaskContext;
-
-/// The task manager, where all the tasks are managed.
-///
-/// Functions implemented on `TaskManager` deals with all task state transitions
-/// and task context switching. For convenience, you can find wrappers around it
-/// in the module level.
-///
-/// Most of `TaskManager` are hidden behind the field `inner`, to defer
-/// borrowing checks to runtime. You can see examples on how to use `inner` in
-/// existing functions on `TaskManager`.
-pub struct TaskManager {
-    /// total number of tasks
-    num_app: usize,
-    /// use inner value to get mutable access
-    inner: UPSafeCell<TaskManagerInner>,
+pub use fp::FpContext;
+pub use itimer::{
+    charge_kernel_time, charge_user_time, get_itimer, set_itimer, stamp_time, ITimer, ITimers,
+    ITIMER_PROF, ITIMER_REAL, ITIMER_VIRTUAL,
+};
+pub use manager::{add_task, fetch_task, spawn_task, TaskManager, TASK_MANAGER};
+pub use processor::{
+    current_processor, current_task, hart_id, run_tasks, schedule, take_current_task, Processor,
+};
+pub use signal::{
+    current_signal_pending, handle_signals, raise_fault_signal, send_signal, sigreturn,
+    SignalAction, SignalActions, SignalFlags, MAX_SIG, SIG_IGN,
+};
+
+/// Start the initial process, which is [`INIT_APP`] if there is such an app.
+/// Otherwise, every app is started at once, as a batch.
+pub fn add_initproc() {
+    if let Some(app_id) = get_app_id_by_name(INIT_APP.as_bytes()) {
+        ProcessControlBlock::new(app_id, None).expect("failed to start the initial process");
+        return;
+    }
+    for app_id in 0..get_num_app() {
+        if ProcessControlBlock::new(app_id, None).is_none() {
+            println!("[kernel] Failed to start app {}.", app_id);
+        }
+    }
 }
 
-/// Inner of Task Manager
-pub struct TaskManagerInner {
-    /// task list
-    tasks: [TaskControlBlock; MAX_APP_NUM],
-    /// id of current `Running` task
-    current_task: usize,
+/// Take the context of the app the current thread has just started with
+/// `sys_exec`, if it has
+pub fn take_new_trap_cx() -> Option<TrapContext> {
+    current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .new_trap_cx
+        .take()
 }
 
-lazy_static! {
-    /// Global variable: TASK_MANAGER
-    pub static ref TASK_MANAGER: TaskManager = {
-        let num_app = get_num_app();
-        let id_list : [usize; SYSCALL_NUM] = [64, 93, 124, 169, 410];
-        let id_times_pairs: [IDTimesPair; SYSCALL_NUM] = [
-            IDTimesPair { syscall_id: id_list[0], syscall_times: 0 },
-            IDTimesPair { syscall_id: id_list[1], syscall_times: 0 },
-            IDTimesPair { syscall_id: id_list[2], syscall_times: 0 },
-            IDTimesPair { syscall_id: id_list[3], syscall_times: 0 },
-            IDTimesPair { syscall_id: id_list[4], syscall_times: 0 },
-        ];
-        let mut tasks = [TaskControlBlock {
-            task_cx: TaskContext::zero_init(),
-            task_status: TaskStatus::UnInit,
-            first_call_time: 0,
-            first_call: true,
-            id_times_pairs: id_times_pairs,
-        }; MAX_APP_NUM];
-        for (i, task) in tasks.iter_mut().enumerate() {
-            task.task_cx = TaskContext::goto_restore(init_app_cx(i));
-            task.task_status = TaskStatus::Ready;
-        }
-        TaskManager {
-            num_app,
-            inner: unsafe {
-                UPSafeCell::new(TaskManagerInner {
-                    tasks,
-                    current_task: 0,
-                })
-            },
-        }
-    };
+/// Get the process of the running thread
+pub fn current_process() -> Arc<ProcessControlBlock> {
+    current_task().unwrap().process.upgrade().unwrap()
 }
 
-impl TaskManager {
-    /// Run the first task in task list.
-    ///
-    /// Generally, the first task in task list is an idle task (we call it zero process later).
-    /// But in ch3, we load apps statically, so the first task is a real app.
-    fn run_first_task(&self) -> ! {
-        let mut inner = self.inner.exclusive_access();
-        let task0 = &mut inner.tasks[0];
-        task0.task_status = TaskStatus::Running;
-        if task0.first_call {
-            task0.first_call = false;
-            task0.first_call_time = get_time_ms();
-        }
-        let next_task_cx_ptr = &task0.task_cx as *const TaskContext;
-        drop(inner);
-        let mut _unused = TaskContext::zero_init();
-        // before this, we should drop local variables that must be dropped manually
-        unsafe {
-            __switch(&mut _unused as *mut TaskContext, next_task_cx_ptr);
-        }
-        panic!("unreachable in run_first_task!");
-    }
-
-    /// Change the status of current `Running` task into `Ready`.
-    fn mark_current_suspended(&self) {
-        let mut inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        inner.tasks[current].task_status = TaskStatus::Ready;
-    }
-
-    /// Change the status of current `Running` task into `Exited`.
-    fn mark_current_exited(&self) {
-        let mut inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        inner.tasks[current].task_status = TaskStatus::Exited;
-    }
-
-    /// Find next task to run and return task id.
-    ///
-    /// In this case, we only return the first `Ready` task in task list.
-    fn find_next_task(&self) -> Option<usize> {
-        let inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        (current + 1..current + self.num_app + 1)
-            .map(|id| id % self.num_app)
-            .find(|id| inner.tasks[*id].task_status == TaskStatus::Ready)
-    }
-
-    /// Switch current `Running` task to the task we have found,
-    /// or there is no `Ready` task and we can exit with all applications completed
-    fn run_next_task(&self) {
-        if let Some(next) = self.find_next_task() {
-            let mut inner = self.inner.exclusive_access();
-            let current = inner.current_task;
-            inner.tasks[next].task_status = TaskStatus::Running;
-            if inner.tasks[next].first_call {
-                inner.tasks[next].first_call_time = get_time_ms();
-                inner.tasks[next].first_call = false;
-            }
-            inner.current_task = next;
-            let current_task_cx_ptr = &mut inner.tasks[current].task_cx as *mut TaskContext;
-            let next_task_cx_ptr = &inner.tasks[next].task_cx as *const TaskContext;
-            drop(inner);
-            // before this, we should drop local variables that must be dropped manually
-            unsafe {
-                __switch(current_task_cx_ptr, next_task_cx_ptr);
-            }
-            // go back to user mode
-        } else {
-            panic!("All applications completed!");
-        }
-    }
-
-    fn get_time_segment(&self) -> usize {
-        let inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        let current_time = get_time_ms();
-        let time_segment = current_time - inner.tasks[current].first_call_time;
-        time_segment
-    }
-
-    /// update syscall times by id 
-    fn update_syscall_times(&self, syscall_id: usize) {
-        let mut inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        for pair in inner.tasks[current].id_times_pairs.iter_mut() {
-            if pair.syscall_id == syscall_id {
-                pair.syscall_times += 1;
-                break;
-            }
-        }
-    }
-    /// and return syscall times
-    fn get_syscall_times(&self) -> [IDTimesPair; SYSCALL_NUM] {
-        let inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        inner.tasks[current].id_times_pairs
+/// Whether the process of the running thread has ended, in which case the
+/// thread must exit instead of going on
+pub fn current_process_ended() -> bool {
+    match current_task().unwrap().process.upgrade() {
+        Some(process) => process.inner_exclusive_access().is_zombie,
+        None => true,
     }
 }
 
-/// Run the first task in task list.
-pub fn run_first_task() {
-    TASK_MANAGER.run_first_task();
+/// Suspend the current 'Running' task and run the next task in task list.
+pub fn suspend_current_and_run_next() {
+    // There must be an application running.
+    let task = current_task().unwrap();
+
+    // ---- access current TCB exclusively
+    let mut task_inner = task.inner_exclusive_access();
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    // Change status to Ready, the idle control flow will push it back to the run queue
+    task_inner.task_status = TaskStatus::Ready;
+    drop(task_inner);
+    // ---- release current TCB
+
+    drop(task);
+    // jump to scheduling cycle
+    unsafe { schedule(task_cx_ptr) };
 }
 
-/// Switch current `Running` task to the task we have found,
-/// or there is no `Ready` task and we can exit with all applications completed
-fn run_next_task() {
-    TASK_MANAGER.run_next_task();
+/// Block the current 'Running' task and run the next task in task list.
+///
+/// The caller must have put the current task into a wait queue, and passes the
+/// guard of the lock protecting that queue. The guard is released only after
+/// the task is marked `Blocked`, so that a waker holding the same lock always
+/// finds the task in a state it can wake up from.
+pub fn block_current_and_run_next<G>(wait_queue_guard: G) {
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    task_inner.task_status = TaskStatus::Blocked;
+    drop(task_inner);
+    drop(wait_queue_guard);
+    drop(task);
+    unsafe { schedule(task_cx_ptr) };
 }
 
-/// Change the status of current `Running` task into `Ready`.
-fn mark_current_suspended() {
-    TASK_MANAGER.mark_current_suspended();
+/// Like [`block_current_and_run_next`], but a signal the task does not mask
+/// wakes it up too.
+///
+/// The task does not block at all if such a signal is already pending. The
+/// signal is checked with the TCB locked, as [`send_signal`] does, so it
+/// cannot slip in before the task is marked `Blocked`. Once back, the caller
+/// has to find out whether it got what it waited for, and otherwise leave the
+/// wait queue itself.
+pub fn block_current_interruptible_and_run_next<G>(wait_queue_guard: G) {
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    if !(task_inner.signals - task_inner.signal_mask).is_empty() {
+        return;
+    }
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    task_inner.task_status = TaskStatus::Blocked;
+    task_inner.interruptible = true;
+    drop(task_inner);
+    drop(wait_queue_guard);
+    drop(task);
+    unsafe { schedule(task_cx_ptr) };
 }
 
-/// Change the status of current `Running` task into `Exited`.
-fn mark_current_exited() {
-    TASK_MANAGER.mark_current_exited();
+/// Wake up a task blocked by [`block_current_and_run_next`] or
+/// [`block_current_interruptible_and_run_next`].
+pub fn wakeup_task(task: Arc<TaskControlBlock>) {
+    let mut task_inner = task.inner_exclusive_access();
+    if task_inner.task_status != TaskStatus::Blocked {
+        return;
+    }
+    task_inner.task_status = TaskStatus::Ready;
+    task_inner.interruptible = false;
+    // if the task has not been switched out yet, its hart will put it back
+    // to the run queue as soon as it is
+    let switched_out = !task_inner.on_cpu;
+    drop(task_inner);
+    if switched_out {
+        add_task(task);
+    }
 }
 
-/// Suspend the current 'Running' task and run the next task in task list.
-pub fn suspend_current_and_run_next() {
-    mark_current_suspended();
-    run_next_task();
+/// Exit the current 'Running' task and run the next task in task list.
+///
+/// The exit of the main thread ends the whole process.
+pub fn exit_current_and_run_next(exit_code: i32) {
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    task_inner.task_status = TaskStatus::Exited;
+    task_inner.exit_code = Some(exit_code);
+    // we never go back to userspace, so the user stack can go now
+    let ustack = task_inner.ustack.take();
+    drop(task_inner);
+    drop(ustack);
+    if task.tid == 0 {
+        if let Some(process) = task.process.upgrade() {
+            process.exit(exit_code);
+        }
+    }
+    drop(task);
+    unsafe { schedule(task_cx_ptr) };
 }
 
-/// Exit the current 'Running' task and run the next task in task list.
-pub fn exit_current_and_run_next() {
-    mark_current_exited();
-    run_next_task();
+/// End the whole process of the current 'Running' task with `exit_code`, and
+/// run the next task in task list.
+pub fn exit_current_process_and_run_next(exit_code: i32) {
+    current_process().exit(exit_code);
+    exit_current_and_run_next(exit_code);
 }
 
 /// return current task time segment
 pub fn get_time_segment() -> usize {
-    TASK_MANAGER.get_time_segment()
+    let task = current_task().unwrap();
+    let task_inner = task.inner_exclusive_access();
+    get_time_ms() - task_inner.first_call_time
 }
 
 /// return syscall times
 pub fn get_syscall_times() -> [u32; MAX_SYSCALL_NUM] {
-    let mut syscall_times: [u32;MAX_SYSCALL_NUM] = [0;MAX_SYSCALL_NUM];
-    let manager_syscall_times = TASK_MANAGER.get_syscall_times();
-    for each in manager_syscall_times.iter() {
-        let index = each.syscall_id;
-        syscall_times[index] = each.syscall_times;
+    let mut syscall_times: [u32; MAX_SYSCALL_NUM] = [0; MAX_SYSCALL_NUM];
+    let task = current_task().unwrap();
+    let task_inner = task.inner_exclusive_access();
+    for each in task_inner.id_times_pairs.iter() {
+        syscall_times[each.syscall_id] = each.syscall_times;
     }
     syscall_times
 }
 
 /// update syscall times
 pub fn update_syscall_times(syscall_id: usize) {
-    TASK_MANAGER.update_syscall_times(syscall_id);
-}
\ No newline at end of file
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    if let Some(pair) = task_inner
+        .id_times_pairs
+        .iter_mut()
+        .find(|pair| pair.syscall_id == syscall_id)
+    {
+        pair.syscall_times += 1;
+    }
+}
+
+/// Save the FP registers of the current task if it has changed them since
+/// they were last saved, and mark them clean in its trap context `cx`
+pub fn save_current_fp(cx: &mut TrapContext) {
+    if cx.sstatus.fs() == FS::Dirty {
+        let task = current_task().unwrap();
+        task.inner_exclusive_access().fp_cx.save();
+        cx.set_fs(FS::Clean);
+    }
+}
+
+/// Turn on the FP registers for the current task, which has used them for the
+/// first time. Returns false if they were on already.
+pub fn enable_current_fp(cx: &mut TrapContext) -> bool {
+    if cx.sstatus.fs() != FS::Off {
+        return false;
+    }
+    let task = current_task().unwrap();
+    task.inner_exclusive_access().fp_cx.restore();
+    cx.set_fs(FS::Clean);
+    true
+}
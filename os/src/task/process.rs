@@ -0,0 +1,321 @@
+//! Types related to processes
+//!
+//! A process is an app in execution. It owns the memory of the app and the
+//! kernel objects created by its threads, while each of its threads is a
+//! [`TaskControlBlock`] with its own stacks and contexts.
+//!
+//! Without paging, the memory of an app is the space it is linked at, so an
+//! app can only be run by one process at a time.
+
+use super::id::{pid_alloc, PidHandle, RecycleAllocator};
+use super::{
+    args_size, spawn_task, wakeup_task, ITimers, SignalAction, SignalActions, TaskControlBlock,
+    MAX_SIG, SIG_IGN,
+};
+use crate::config::{APP_SIZE_LIMIT, MAX_ARG_SIZE, MAX_FD_NUM};
+use crate::fs::{FileDescriptor, TTY};
+use crate::loader::{get_app_name, get_base_i, read_app, TlsTemplate};
+use crate::sync::{Banker, Condvar, Semaphore, SpinLock, SpinLockGuard, UserMutex};
+use crate::timer::remove_timer;
+use crate::trap::TrapContext;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+/// The process control block (PCB) of a process
+pub struct ProcessControlBlock {
+    /// Process id
+    pub pid: PidHandle,
+    /// Mutable part of the PCB
+    inner: SpinLock<ProcessControlBlockInner>,
+}
+
+/// Mutable part of the PCB
+pub struct ProcessControlBlockInner {
+    /// Whether the main thread has exited, ending the process
+    pub is_zombie: bool,
+    /// Exit code of the main thread
+    pub exit_code: i32,
+    /// The app this process runs
+    pub app_id: usize,
+    /// Template of the thread-local storage of the app, if it has any
+    pub tls: Option<TlsTemplate>,
+    /// The process that spawned this one, if it is still there
+    pub parent: Option<Weak<ProcessControlBlock>>,
+    /// Processes spawned by this one and not waited for yet
+    pub children: Vec<Arc<ProcessControlBlock>>,
+    /// Threads of the process, indexed by thread id. A slot is freed once the
+    /// thread has exited and been waited for.
+    pub tasks: Vec<Option<Arc<TaskControlBlock>>>,
+    /// Allocator of thread ids
+    pub task_res_allocator: RecycleAllocator,
+    /// Open files shared by the threads, indexed by file descriptor
+    pub fd_table: Vec<Option<FileDescriptor>>,
+    /// mutexes created by `sys_mutex_create`, indexed by mutex id
+    pub mutex_list: Vec<Option<Arc<dyn UserMutex>>>,
+    /// semaphores created by `sys_semaphore_create`, indexed by semaphore id
+    pub semaphore_list: Vec<Option<Arc<Semaphore>>>,
+    /// condition variables created by `sys_condvar_create`, indexed by condvar id
+    pub condvar_list: Vec<Option<Arc<Condvar>>>,
+    /// accounting of mutexes and semaphores for deadlock detection
+    pub banker: Banker,
+    /// what to do on each signal, set by `sys_sigaction`
+    pub signal_actions: SignalActions,
+    /// interval timers set by `sys_setitimer`
+    pub itimers: ITimers,
+}
+
+impl ProcessControlBlockInner {
+    /// Allocate the lowest free file descriptor, or None if all the
+    /// [`MAX_FD_NUM`] descriptors are in use
+    pub fn alloc_fd(&mut self) -> Option<usize> {
+        if let Some(fd) = (0..self.fd_table.len()).find(|fd| self.fd_table[*fd].is_none()) {
+            Some(fd)
+        } else if self.fd_table.len() < MAX_FD_NUM {
+            self.fd_table.push(None);
+            Some(self.fd_table.len() - 1)
+        } else {
+            None
+        }
+    }
+    /// Whether the `len` bytes at `start` lie inside the memory of the
+    /// process, i.e. the space of its app or the user stack of a thread
+    pub fn in_user_memory(&self, start: usize, len: usize) -> bool {
+        let base = get_base_i(self.app_id);
+        if start >= base
+            && start
+                .checked_add(len)
+                .is_some_and(|end| end <= base + APP_SIZE_LIMIT)
+        {
+            return true;
+        }
+        self.tasks.iter().flatten().any(|task| {
+            task.inner_exclusive_access()
+                .ustack
+                .as_ref()
+                .is_some_and(|ustack| ustack.contains(start, len))
+        })
+    }
+    /// Close the descriptors marked close-on-exec, returning them so that the
+    /// caller drops the files once the lock is released
+    pub fn close_on_exec(&mut self) -> Vec<FileDescriptor> {
+        self.fd_table
+            .iter_mut()
+            .filter(|slot| slot.as_ref().is_some_and(|fd| fd.cloexec))
+            .filter_map(|slot| slot.take())
+            .collect()
+    }
+}
+
+lazy_static! {
+    /// Processes that have not exited yet, indexed by pid
+    static ref PID2PCB: SpinLock<BTreeMap<usize, Arc<ProcessControlBlock>>> =
+        SpinLock::new(BTreeMap::new());
+}
+
+/// Whether app `app_id` is run by a process in `pid2pcb` other than `except_pid`
+fn app_running(
+    pid2pcb: &BTreeMap<usize, Arc<ProcessControlBlock>>,
+    app_id: usize,
+    except_pid: usize,
+) -> bool {
+    pid2pcb.values().any(|process| {
+        process.pid.0 != except_pid && process.inner_exclusive_access().app_id == app_id
+    })
+}
+
+impl ProcessControlBlock {
+    /// Create a process for app `app_id` as a child of `parent`, and put its
+    /// main thread into the run queue. Returns None if the app cannot be read,
+    /// is already running, or no stack is left.
+    ///
+    /// The child inherits the open files of its parent, except those marked
+    /// close-on-exec. Its only argument is the name of the app.
+    pub fn new(app_id: usize, parent: Option<&Arc<Self>>) -> Option<Arc<Self>> {
+        let image = read_app(app_id)?;
+        let fd_table = match parent {
+            Some(parent) => parent
+                .inner_exclusive_access()
+                .fd_table
+                .iter()
+                .map(|slot| slot.clone().filter(|fd| !fd.cloexec))
+                .collect(),
+            None => vec![
+                // 0 -> stdin
+                Some(FileDescriptor::new(TTY.clone())),
+                // 1 -> stdout
+                Some(FileDescriptor::new(TTY.clone())),
+                // 2 -> stderr
+                Some(FileDescriptor::new(TTY.clone())),
+            ],
+        };
+        // hold it until the process is in, so that no one else loads the app
+        let mut pid2pcb = PID2PCB.lock();
+        if app_running(&pid2pcb, app_id, usize::MAX) {
+            return None;
+        }
+        image.load();
+        let process = Arc::new(Self {
+            pid: pid_alloc(),
+            inner: SpinLock::new(ProcessControlBlockInner {
+                is_zombie: false,
+                exit_code: 0,
+                app_id,
+                tls: image.tls(),
+                parent: parent.map(Arc::downgrade),
+                children: Vec::new(),
+                tasks: Vec::new(),
+                task_res_allocator: RecycleAllocator::new(usize::MAX),
+                fd_table,
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                banker: Banker::new(),
+                signal_actions: [SignalAction::default(); MAX_SIG + 1],
+                itimers: ITimers::default(),
+            }),
+        });
+        let main_thread = process.create_thread(image.entry(), 0)?;
+        let name = get_app_name(app_id).as_bytes().to_vec();
+        if !main_thread.push_args(&[name], &[]) {
+            return None;
+        }
+        pid2pcb.insert(process.pid.0, process.clone());
+        drop(pid2pcb);
+        if let Some(parent) = parent {
+            parent
+                .inner_exclusive_access()
+                .children
+                .push(process.clone());
+        }
+        spawn_task(main_thread);
+        Some(process)
+    }
+    /// Lock the mutable part of the PCB
+    pub fn inner_exclusive_access(&self) -> SpinLockGuard<'_, ProcessControlBlockInner> {
+        self.inner.lock()
+    }
+    /// Create a thread starting at `entry` with `arg` in `a0`, without putting
+    /// it into the run queue. Returns None if no stack is left.
+    pub fn create_thread(
+        self: &Arc<Self>,
+        entry: usize,
+        arg: usize,
+    ) -> Option<Arc<TaskControlBlock>> {
+        let mut inner = self.inner_exclusive_access();
+        let tid = inner.task_res_allocator.alloc()?;
+        let tls = inner.tls;
+        drop(inner);
+        let Some(task) = TaskControlBlock::new(self, tid, entry, arg, tls.as_ref()) else {
+            self.inner_exclusive_access()
+                .task_res_allocator
+                .dealloc(tid);
+            return None;
+        };
+        let task = Arc::new(task);
+        let mut inner = self.inner_exclusive_access();
+        while inner.tasks.len() <= tid {
+            inner.tasks.push(None);
+        }
+        inner.tasks[tid] = Some(task.clone());
+        Some(task)
+    }
+    /// Replace the image of the process with app `app_id` run with `args` and
+    /// `envs`, on behalf of its thread `task`, which must be the only one
+    /// left. Returns the trap context `task` starts the app with, or None if
+    /// the app cannot be read, there are other threads, the app is run by
+    /// another process, or the app or its arguments cannot fit in a stack.
+    ///
+    /// The files marked close-on-exec are closed, caught signals get their
+    /// default action back, and the synchronization objects are gone with the
+    /// old image.
+    pub fn exec(
+        &self,
+        task: &TaskControlBlock,
+        app_id: usize,
+        args: &[Vec<u8>],
+        envs: &[Vec<u8>],
+    ) -> Option<TrapContext> {
+        let image = read_app(app_id)?;
+        let pid2pcb = PID2PCB.lock();
+        let fits = image.tls().map_or(true, |tls| tls.fits_in_stack())
+            && args_size(args, envs) <= MAX_ARG_SIZE;
+        if !fits || app_running(&pid2pcb, app_id, self.pid.0) {
+            return None;
+        }
+        let mut inner = self.inner_exclusive_access();
+        let other_threads = inner
+            .tasks
+            .iter()
+            .flatten()
+            .any(|t| t.tid != task.tid && t.inner_exclusive_access().exit_code.is_none());
+        if other_threads {
+            return None;
+        }
+        // past this point, the old image is gone and there is no going back
+        drop(pid2pcb);
+        image.load();
+        inner.app_id = app_id;
+        inner.tls = image.tls();
+        let tls = inner.tls;
+        let closed = inner.close_on_exec();
+        for action in inner.signal_actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SignalAction::default();
+            }
+        }
+        let mutex_list = core::mem::take(&mut inner.mutex_list);
+        let semaphore_list = core::mem::take(&mut inner.semaphore_list);
+        let condvar_list = core::mem::take(&mut inner.condvar_list);
+        inner.banker = Banker::new();
+        drop(inner);
+        drop((closed, mutex_list, semaphore_list, condvar_list));
+        task.exec_trap_cx(image.entry(), tls.as_ref(), args, envs)
+    }
+    /// End the process after its main thread exited with `exit_code`.
+    ///
+    /// Other threads exit on their next trap. Blocked ones are woken up for
+    /// that, as nobody may ever wake them otherwise. Only the first call has
+    /// an effect, as a process may also be ended by a signal before its main
+    /// thread exits.
+    pub fn exit(&self, exit_code: i32) {
+        let mut inner = self.inner_exclusive_access();
+        if inner.is_zombie {
+            return;
+        }
+        inner.is_zombie = true;
+        inner.exit_code = exit_code;
+        // drop them after releasing the lock, as dropping a thread frees its stacks
+        let tasks = core::mem::take(&mut inner.tasks);
+        // they are not waited for anymore
+        let children = core::mem::take(&mut inner.children);
+        let fd_table = core::mem::take(&mut inner.fd_table);
+        let mutex_list = core::mem::take(&mut inner.mutex_list);
+        let semaphore_list = core::mem::take(&mut inner.semaphore_list);
+        let condvar_list = core::mem::take(&mut inner.condvar_list);
+        let real_timer = inner.itimers.take_real_timer();
+        drop(inner);
+        if let Some(id) = real_timer {
+            remove_timer(id);
+        }
+        for task in tasks.iter().flatten() {
+            wakeup_task(task.clone());
+        }
+        drop((
+            tasks,
+            children,
+            fd_table,
+            mutex_list,
+            semaphore_list,
+            condvar_list,
+        ));
+        PID2PCB.lock().remove(&self.pid.0);
+    }
+}
+
+/// Get the process `pid`, if it has not exited yet
+pub fn pid2process(pid: usize) -> Option<Arc<ProcessControlBlock>> {
+    PID2PCB.lock().get(&pid).cloned()
+}
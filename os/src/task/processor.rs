@@ -0,0 +1,154 @@
+//! Implementation of [`Processor`] and the per-hart idle control flow.
+//!
+//! Every hart owns a [`Processor`], which records the task it is running and
+//! the context of its idle control flow in [`run_tasks()`]. A task that gives
+//! up its hart always switches back to the idle control flow, which then puts
+//! the task back to the run queue: only after this point another hart may pick
+//! the task up, so its context is never resumed before being fully saved.
+
+use super::__switch;
+use super::{
+    add_task, charge_kernel_time, fetch_task, stamp_time, TaskContext, TaskControlBlock,
+    TaskStatus, TASK_MANAGER,
+};
+use crate::config::MAX_HARTS;
+use crate::sync::SpinLock;
+use crate::timer::get_time_ms;
+use crate::trap::wait_for_interrupt;
+use alloc::sync::Arc;
+use core::arch::asm;
+use lazy_static::*;
+use riscv::register::sstatus::FS;
+
+/// Processor management structure
+pub struct Processor {
+    /// The task currently executing on this hart
+    current: Option<Arc<TaskControlBlock>>,
+    /// The basic control flow of each hart, helping to select and switch process
+    idle_task_cx: TaskContext,
+}
+
+impl Processor {
+    /// Create an empty Processor
+    fn new() -> Self {
+        Self {
+            current: None,
+            idle_task_cx: TaskContext::zero_init(),
+        }
+    }
+    /// Get mutable reference to `idle_task_cx`
+    fn get_idle_task_cx_ptr(&mut self) -> *mut TaskContext {
+        &mut self.idle_task_cx as *mut _
+    }
+    /// Get current task in moving semanteme
+    pub fn take_current(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.current.take()
+    }
+    /// Get current task in cloning semanteme
+    pub fn current(&self) -> Option<Arc<TaskControlBlock>> {
+        self.current.clone()
+    }
+}
+
+lazy_static! {
+    /// One [`Processor`] for each hart, indexed by hart id
+    pub static ref PROCESSORS: [SpinLock<Processor>; MAX_HARTS] =
+        core::array::from_fn(|_| SpinLock::new(Processor::new()));
+}
+
+/// Get the id of the hart we are running on, which `entry.asm` keeps in `tp`
+pub fn hart_id() -> usize {
+    let hartid;
+    unsafe {
+        asm!("mv {}, tp", out(reg) hartid);
+    }
+    hartid
+}
+
+/// Get the [`Processor`] of the current hart
+pub fn current_processor() -> &'static SpinLock<Processor> {
+    &PROCESSORS[hart_id()]
+}
+
+/// The main part of process execution and scheduling.
+/// Loop `fetch_task` to get the task that needs to run, and switch the task
+/// through `__switch`. Once the task gives up this hart, decide where it goes.
+pub fn run_tasks() -> ! {
+    loop {
+        if let Some(task) = fetch_task() {
+            let mut processor = current_processor().lock();
+            let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
+            // access coming task TCB exclusively
+            let mut task_inner = task.inner_exclusive_access();
+            let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
+            task_inner.task_status = TaskStatus::Running;
+            task_inner.on_cpu = true;
+            if task_inner.first_call {
+                task_inner.first_call = false;
+                task_inner.first_call_time = get_time_ms();
+            }
+            // the FP registers of the hart may belong to any other task
+            if task.kstack.get_trap_cx().sstatus.fs() != FS::Off {
+                task_inner.fp_cx.restore();
+            }
+            // release coming task_inner manually
+            drop(task_inner);
+            stamp_time(&task);
+            #[cfg(debug_assertions)]
+            crate::sync::lockdep::switch_task(Some(&task.held_mutexes));
+            processor.current = Some(task);
+            // release processor manually
+            drop(processor);
+            unsafe {
+                __switch(idle_task_cx_ptr, next_task_cx_ptr);
+            }
+            // the task has given up this hart and its context is saved
+            #[cfg(debug_assertions)]
+            crate::sync::lockdep::switch_task(None);
+            let task = take_current_task().unwrap();
+            charge_kernel_time(&task);
+            let mut task_inner = task.inner_exclusive_access();
+            task_inner.on_cpu = false;
+            let task_status = task_inner.task_status;
+            drop(task_inner);
+            match task_status {
+                TaskStatus::Ready => add_task(task),
+                // whoever wakes it up puts it back to the run queue
+                TaskStatus::Blocked => {}
+                // the process ends with its main thread
+                TaskStatus::Exited if task.tid == 0 => TASK_MANAGER.lock().mark_exited(),
+                TaskStatus::Exited => {}
+                _ => unreachable!(),
+            }
+        } else if TASK_MANAGER.lock().all_exited() {
+            panic!("All applications completed!");
+        } else {
+            // nothing to run, sleep until an interrupt arrives
+            wait_for_interrupt();
+        }
+    }
+}
+
+/// Take the current task, leaving a None in its place
+pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
+    current_processor().lock().take_current()
+}
+
+/// Get running task
+pub fn current_task() -> Option<Arc<TaskControlBlock>> {
+    current_processor().lock().current()
+}
+
+/// Return to idle control flow for new scheduling
+///
+/// # Safety
+///
+/// `switched_task_cx_ptr` must point to the `task_cx` of the current task,
+/// which stays alive until another hart switches back to it, and no lock
+/// guard may be held across the call.
+pub unsafe fn schedule(switched_task_cx_ptr: *mut TaskContext) {
+    let mut processor = current_processor().lock();
+    let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
+    drop(processor);
+    __switch(switched_task_cx_ptr, idle_task_cx_ptr);
+}
@@ -0,0 +1,284 @@
+//! POSIX-like signals
+//!
+//! Each thread has its own set of pending signals and its own signal mask,
+//! while the actions are shared by all threads of a process. Pending signals
+//! are delivered by [`handle_signals()`] right before a thread returns to
+//! userspace. A thread sleeping in an interruptible wait, like a read of the
+//! tty, is woken up by a signal it does not mask, and its wait fails with
+//! `EINTR` so that it can get back to userspace for the signal.
+//!
+//! To run a handler, the user context of the thread is saved in a
+//! [`SignalFrame`] pushed on its user stack, and the trap context is rewritten
+//! to enter the handler with the signal number in `a0`. The handler has to end
+//! with `sigreturn`, which restores the context from the frame.
+
+use super::{
+    current_task, exit_current_process_and_run_next, wakeup_task, FpContext, TaskControlBlock,
+    TaskStatus,
+};
+use crate::trap::TrapContext;
+use alloc::sync::Arc;
+use bitflags::*;
+use riscv::register::sstatus::FS;
+
+/// the largest signal number
+pub const MAX_SIG: usize = 31;
+
+bitflags! {
+    /// A set of signals, signal `n` being bit `n`
+    pub struct SignalFlags: u32 {
+        /// Hangup
+        const SIGHUP = 1 << 1;
+        /// Interrupt from keyboard
+        const SIGINT = 1 << 2;
+        /// Quit from keyboard
+        const SIGQUIT = 1 << 3;
+        /// Illegal instruction
+        const SIGILL = 1 << 4;
+        /// Trace or breakpoint trap
+        const SIGTRAP = 1 << 5;
+        /// Abort
+        const SIGABRT = 1 << 6;
+        /// Bus error
+        const SIGBUS = 1 << 7;
+        /// Floating-point exception
+        const SIGFPE = 1 << 8;
+        /// Kill, which can be neither caught nor blocked
+        const SIGKILL = 1 << 9;
+        /// User-defined signal 1
+        const SIGUSR1 = 1 << 10;
+        /// Invalid memory reference
+        const SIGSEGV = 1 << 11;
+        /// User-defined signal 2
+        const SIGUSR2 = 1 << 12;
+        /// Broken pipe
+        const SIGPIPE = 1 << 13;
+        /// Timer signal of `ITIMER_REAL`
+        const SIGALRM = 1 << 14;
+        /// Termination
+        const SIGTERM = 1 << 15;
+        /// Stack fault on coprocessor
+        const SIGSTKFLT = 1 << 16;
+        /// Child stopped or terminated
+        const SIGCHLD = 1 << 17;
+        /// Continue if stopped
+        const SIGCONT = 1 << 18;
+        /// Stop, which can be neither caught nor blocked
+        const SIGSTOP = 1 << 19;
+        /// Stop typed at terminal
+        const SIGTSTP = 1 << 20;
+        /// Terminal input for background process
+        const SIGTTIN = 1 << 21;
+        /// Terminal output for background process
+        const SIGTTOU = 1 << 22;
+        /// Urgent condition on socket
+        const SIGURG = 1 << 23;
+        /// CPU time limit exceeded
+        const SIGXCPU = 1 << 24;
+        /// File size limit exceeded
+        const SIGXFSZ = 1 << 25;
+        /// Timer signal of `ITIMER_VIRTUAL`
+        const SIGVTALRM = 1 << 26;
+        /// Timer signal of `ITIMER_PROF`
+        const SIGPROF = 1 << 27;
+        /// Window resize
+        const SIGWINCH = 1 << 28;
+        /// I/O now possible
+        const SIGIO = 1 << 29;
+        /// Power failure
+        const SIGPWR = 1 << 30;
+        /// Bad system call
+        const SIGSYS = 1 << 31;
+    }
+}
+
+impl SignalFlags {
+    /// The set holding signal `signum` only, if it is a valid signal number
+    pub fn from_signum(signum: usize) -> Option<Self> {
+        if (1..=MAX_SIG).contains(&signum) {
+            Self::from_bits(1 << signum)
+        } else {
+            None
+        }
+    }
+    /// Signals that can be neither caught nor blocked
+    pub fn unmaskable() -> Self {
+        Self::SIGKILL | Self::SIGSTOP
+    }
+    /// Whether the default action of the signal is to do nothing. Without job
+    /// control, stopping and continuing are ignored as well.
+    fn ignored_by_default(&self) -> bool {
+        (Self::SIGCHLD
+            | Self::SIGCONT
+            | Self::SIGURG
+            | Self::SIGWINCH
+            | Self::SIGSTOP
+            | Self::SIGTSTP
+            | Self::SIGTTIN
+            | Self::SIGTTOU)
+            .contains(*self)
+    }
+}
+
+/// `SignalAction::handler` of the default action
+pub const SIG_DFL: usize = 0;
+/// `SignalAction::handler` of ignoring the signal
+pub const SIG_IGN: usize = 1;
+
+/// What a process does on a signal, as exchanged with `sys_sigaction`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalAction {
+    /// address of the handler, or [`SIG_DFL`] or [`SIG_IGN`]
+    pub handler: usize,
+    /// signals blocked while the handler runs, besides the signal itself
+    pub mask: SignalFlags,
+}
+
+impl Default for SignalAction {
+    fn default() -> Self {
+        Self {
+            handler: SIG_DFL,
+            mask: SignalFlags::empty(),
+        }
+    }
+}
+
+/// The actions of a process, indexed by signal number
+pub type SignalActions = [SignalAction; MAX_SIG + 1];
+
+/// The user context saved on the user stack while a handler runs
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SignalFrame {
+    /// general-purpose registers
+    x: [usize; 32],
+    /// where the thread was interrupted
+    sepc: usize,
+    /// signal mask before the handler
+    mask: SignalFlags,
+    /// FP registers, as the handler may use them too
+    fp_cx: FpContext,
+    /// the frame of the handler interrupted by this one, or 0
+    prev: usize,
+}
+
+/// Make signal `signal` pending for `task`, waking it up if it sleeps in an
+/// interruptible wait and does not mask the signal
+pub fn send_signal(task: &Arc<TaskControlBlock>, signal: SignalFlags) {
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner.signals |= signal;
+    let wake = task_inner.task_status == TaskStatus::Blocked
+        && task_inner.interruptible
+        && !(signal - task_inner.signal_mask).is_empty();
+    drop(task_inner);
+    if wake {
+        wakeup_task(task.clone());
+    }
+}
+
+/// Whether the current thread has a signal to take on its return to userspace
+pub fn current_signal_pending() -> bool {
+    let task = current_task().unwrap();
+    let task_inner = task.inner_exclusive_access();
+    !(task_inner.signals - task_inner.signal_mask).is_empty()
+}
+
+/// A synchronous fault of the current thread raises `signal`. Returns false if
+/// no handler can run for it, in which case the caller has to end the process,
+/// as returning to the faulting instruction would only fault again.
+pub fn raise_fault_signal(signal: SignalFlags) -> bool {
+    let task = current_task().unwrap();
+    let process = task.process.upgrade().unwrap();
+    let action =
+        process.inner_exclusive_access().signal_actions[signal.bits().trailing_zeros() as usize];
+    let mut task_inner = task.inner_exclusive_access();
+    if action.handler == SIG_DFL
+        || action.handler == SIG_IGN
+        || task_inner.signal_mask.contains(signal)
+    {
+        return false;
+    }
+    task_inner.signals |= signal;
+    true
+}
+
+/// Leave the handler of the current thread at the next return to userspace,
+/// restoring the context saved when it was entered. Returns -1 if the thread
+/// is not running a handler.
+pub fn sigreturn() -> isize {
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    if task_inner.signal_frame == 0 {
+        return -1;
+    }
+    let frame = unsafe { (task_inner.signal_frame as *const SignalFrame).read() };
+    task_inner.signal_frame = frame.prev;
+    task_inner.sigreturn_frame = Some(frame);
+    0
+}
+
+/// Finish a `sigreturn` and deliver pending signals of the current thread, on
+/// its way back to userspace with trap context `cx`
+pub fn handle_signals(cx: &mut TrapContext) {
+    let task = current_task().unwrap();
+    let Some(process) = task.process.upgrade() else {
+        return;
+    };
+    // copy them out first, as the PCB must be locked before the TCB
+    let actions = process.inner_exclusive_access().signal_actions;
+    let mut task_inner = task.inner_exclusive_access();
+    if let Some(frame) = task_inner.sigreturn_frame.take() {
+        cx.x = frame.x;
+        cx.sepc = frame.sepc;
+        task_inner.signal_mask = frame.mask - SignalFlags::unmaskable();
+        task_inner.fp_cx = frame.fp_cx;
+        if cx.sstatus.fs() != FS::Off {
+            task_inner.fp_cx.restore();
+            cx.set_fs(FS::Clean);
+        }
+    }
+    loop {
+        let deliverable =
+            task_inner.signals & !(task_inner.signal_mask - SignalFlags::unmaskable());
+        if deliverable.is_empty() {
+            return;
+        }
+        let signum = deliverable.bits().trailing_zeros() as usize;
+        let signal = SignalFlags::from_signum(signum).unwrap();
+        task_inner.signals.remove(signal);
+        let action = actions[signum];
+        match action.handler {
+            SIG_IGN => {}
+            SIG_DFL if signal.ignored_by_default() => {}
+            SIG_DFL => {
+                drop(task_inner);
+                drop(task);
+                drop(process);
+                println!("[kernel] Application killed by {:?}.", signal);
+                exit_current_process_and_run_next(-(signum as i32));
+                unreachable!();
+            }
+            handler => {
+                let frame_ptr = (cx.x[2] - core::mem::size_of::<SignalFrame>()) & !0xf;
+                let frame = SignalFrame {
+                    x: cx.x,
+                    sepc: cx.sepc,
+                    mask: task_inner.signal_mask,
+                    fp_cx: task_inner.fp_cx,
+                    prev: task_inner.signal_frame,
+                };
+                unsafe {
+                    (frame_ptr as *mut SignalFrame).write(frame);
+                }
+                task_inner.signal_frame = frame_ptr;
+                task_inner.signal_mask |= action.mask | signal;
+                cx.x[2] = frame_ptr;
+                cx.x[10] = signum;
+                cx.sepc = handler;
+                // one handler at a time, the others wait for its sigreturn
+                return;
+            }
+        }
+    }
+}
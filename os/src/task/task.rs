@@ -1,8 +1,19 @@
 //! Types related to task management
+//!
+//! A task is a thread of a [`ProcessControlBlock`], the unit the scheduler runs.
 
-use crate::config::SYSCALL_NUM;
+use super::id::{kstack_alloc, ustack_alloc, KernelStack, UserStack};
+use super::signal::SignalFrame;
+use super::{FpContext, ProcessControlBlock, SignalFlags, TaskContext};
+use crate::config::{MAX_ARG_SIZE, SYSCALL_NUM};
+use crate::loader::TlsTemplate;
+#[cfg(debug_assertions)]
+use crate::sync::lockdep::TaskLocks;
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::trap::TrapContext;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 
-use super::TaskContext;
 /// syscall ID and times corresponding
 #[derive(Copy, Clone)]
 pub struct IDTimesPair {
@@ -12,14 +23,60 @@ pub struct IDTimesPair {
     pub syscall_times: u32,
 }
 
+/// The syscalls whose times are counted for `sys_task_info`
+const COUNTED_SYSCALLS: [usize; SYSCALL_NUM] = [64, 93, 124, 169, 410];
 
-/// The task control block (TCB) of a task.
-#[derive(Copy, Clone)]
+/// The task control block (TCB) of a task, i.e. of a thread.
+///
+/// A TCB may be touched by every hart, so its mutable part lives behind a lock.
+/// When both are needed, lock the PCB of its process before the TCB.
 pub struct TaskControlBlock {
+    /// The process this thread belongs to
+    pub process: Weak<ProcessControlBlock>,
+    /// Thread id, unique in the process
+    pub tid: usize,
+    /// Kernel stack, holding the trap context on its top
+    pub kstack: KernelStack,
+    /// Sleeping mutexes held by the thread, for lockdep
+    #[cfg(debug_assertions)]
+    pub held_mutexes: TaskLocks,
+    /// Mutable part of the TCB
+    inner: SpinLock<TaskControlBlockInner>,
+}
+
+/// Mutable part of the TCB
+pub struct TaskControlBlockInner {
     /// The task status in it's lifecycle
     pub task_status: TaskStatus,
     /// The task context
     pub task_cx: TaskContext,
+    /// Floating-point registers, saved lazily
+    pub fp_cx: FpContext,
+    /// Whether a hart is still executing the task, i.e. its context is not saved yet
+    pub on_cpu: bool,
+    /// Whether the task is blocked in a wait a signal may cut short
+    pub interruptible: bool,
+    /// User stack, given back as soon as the thread exits
+    pub ustack: Option<UserStack>,
+    /// Exit code, set once the thread has exited
+    pub exit_code: Option<i32>,
+    /// Signals sent to the thread and not delivered yet
+    pub signals: SignalFlags,
+    /// Signals whose delivery is blocked
+    pub signal_mask: SignalFlags,
+    /// Address of the frame on the user stack of the running handler, or 0
+    pub signal_frame: usize,
+    /// Frame popped by `sys_sigreturn`, restored on the return to userspace
+    pub sigreturn_frame: Option<SignalFrame>,
+    /// Context of the app started by `sys_exec`, replacing the trap context on
+    /// the return to userspace
+    pub new_trap_cx: Option<TrapContext>,
+    /// Time spent in userspace, in microseconds
+    pub user_time_us: usize,
+    /// Time spent in the kernel, in microseconds
+    pub kernel_time_us: usize,
+    /// When the running time was last charged, in microseconds
+    pub time_stamp_us: usize,
     /// The time when first called
     pub first_call_time: usize,
     /// Whether first call
@@ -28,6 +85,166 @@ pub struct TaskControlBlock {
     pub id_times_pairs: [IDTimesPair; SYSCALL_NUM],
 }
 
+impl TaskControlBlock {
+    /// Create a ready thread `tid` of `process`, starting at `entry` with
+    /// `arg` in `a0` and `tls` as the template of its thread-local storage.
+    /// Returns None if no stack is left.
+    pub fn new(
+        process: &Arc<ProcessControlBlock>,
+        tid: usize,
+        entry: usize,
+        arg: usize,
+        tls: Option<&TlsTemplate>,
+    ) -> Option<Self> {
+        let kstack = kstack_alloc()?;
+        let ustack = ustack_alloc()?;
+        let trap_cx = user_init_context(entry, arg, ustack.get_top(), tls)?;
+        let kstack_ptr = kstack.push_context(trap_cx);
+        let id_times_pairs = COUNTED_SYSCALLS.map(|syscall_id| IDTimesPair {
+            syscall_id,
+            syscall_times: 0,
+        });
+        Some(Self {
+            process: Arc::downgrade(process),
+            tid,
+            kstack,
+            #[cfg(debug_assertions)]
+            held_mutexes: TaskLocks::new(),
+            inner: SpinLock::new(TaskControlBlockInner {
+                task_status: TaskStatus::Ready,
+                task_cx: TaskContext::goto_restore(kstack_ptr),
+                fp_cx: FpContext::zero_init(),
+                on_cpu: false,
+                interruptible: false,
+                ustack: Some(ustack),
+                exit_code: None,
+                signals: SignalFlags::empty(),
+                signal_mask: SignalFlags::empty(),
+                signal_frame: 0,
+                sigreturn_frame: None,
+                new_trap_cx: None,
+                user_time_us: 0,
+                kernel_time_us: 0,
+                time_stamp_us: 0,
+                first_call_time: 0,
+                first_call: true,
+                id_times_pairs,
+            }),
+        })
+    }
+    /// Lock the mutable part of the TCB
+    pub fn inner_exclusive_access(&self) -> SpinLockGuard<'_, TaskControlBlockInner> {
+        self.inner.lock()
+    }
+    /// Pass `args` and `envs` to the main thread of a new process, which has
+    /// not run yet. Returns false if they take more than [`MAX_ARG_SIZE`].
+    pub fn push_args(&self, args: &[Vec<u8>], envs: &[Vec<u8>]) -> bool {
+        push_args(self.kstack.get_trap_cx(), args, envs)
+    }
+    /// Get the context the thread starts a new app at `entry` with, reusing
+    /// its user stack from the top and passing it `args` and `envs`. Handlers
+    /// of the old app are forgotten, while pending signals and the signal mask
+    /// are kept.
+    ///
+    /// The FP registers of the old app are forgotten too. The context starts
+    /// with `sstatus.FS` off, as for any new app, so that the first use of
+    /// them loads cleared ones.
+    pub fn exec_trap_cx(
+        &self,
+        entry: usize,
+        tls: Option<&TlsTemplate>,
+        args: &[Vec<u8>],
+        envs: &[Vec<u8>],
+    ) -> Option<TrapContext> {
+        let mut inner = self.inner_exclusive_access();
+        inner.signal_frame = 0;
+        inner.sigreturn_frame = None;
+        inner.fp_cx = FpContext::zero_init();
+        let ustack_top = inner.ustack.as_ref()?.get_top();
+        let mut trap_cx = user_init_context(entry, 0, ustack_top, tls)?;
+        push_args(&mut trap_cx, args, envs).then_some(trap_cx)
+    }
+}
+
+/// Size the arguments `args` and the environment `envs` take on the user
+/// stack, as laid out by [`push_args`]
+pub fn args_size(args: &[Vec<u8>], envs: &[Vec<u8>]) -> usize {
+    let strings: usize = args.iter().chain(envs).map(|s| s.len() + 1).sum();
+    // argc, argv with its NULL, envp with its NULL and an empty auxv
+    let words = 1 + args.len() + 1 + envs.len() + 1 + 2;
+    // each part is aligned to 16 bytes
+    (strings + 15 + words * core::mem::size_of::<usize>() + 15) & !0xf
+}
+
+/// Push `args` and `envs` onto the user stack of `trap_cx`, setting `a0` to
+/// `argc`, `a1` to `argv` and `a2` to `envp`. Returns false if they take
+/// more than [`MAX_ARG_SIZE`].
+///
+/// They are laid out as on other Unix systems, from the stack pointer up:
+///
+/// ```text
+/// argc | argv[0] .. argv[argc - 1] | NULL | envp[0] .. | NULL | AT_NULL, 0 | strings
+/// ```
+fn push_args(trap_cx: &mut TrapContext, args: &[Vec<u8>], envs: &[Vec<u8>]) -> bool {
+    if args_size(args, envs) > MAX_ARG_SIZE {
+        return false;
+    }
+    let mut sp = trap_cx.x[2];
+    let mut push_str = |s: &Vec<u8>| {
+        sp -= s.len() + 1;
+        unsafe {
+            core::ptr::copy_nonoverlapping(s.as_ptr(), sp as *mut u8, s.len());
+            *((sp + s.len()) as *mut u8) = 0;
+        }
+        sp
+    };
+    let arg_ptrs: Vec<usize> = args.iter().map(&mut push_str).collect();
+    let env_ptrs: Vec<usize> = envs.iter().map(&mut push_str).collect();
+    let mut words = Vec::with_capacity(args.len() + envs.len() + 5);
+    words.push(args.len());
+    words.extend(arg_ptrs);
+    words.push(0);
+    words.extend(env_ptrs);
+    words.push(0);
+    // AT_NULL ends the empty auxiliary vector
+    words.extend([0, 0]);
+    sp = ((sp & !0xf) - words.len() * core::mem::size_of::<usize>()) & !0xf;
+    unsafe {
+        core::ptr::copy_nonoverlapping(words.as_ptr(), sp as *mut usize, words.len());
+    }
+    let word = core::mem::size_of::<usize>();
+    trap_cx.x[2] = sp;
+    trap_cx.x[10] = args.len();
+    trap_cx.x[11] = sp + word;
+    trap_cx.x[12] = sp + (args.len() + 2) * word;
+    true
+}
+
+/// Build the context of a thread entering the app at `entry` with `arg` in
+/// `a0`, on the user stack ending at `ustack_top`.
+///
+/// If the app has thread-local storage, the thread gets its own copy of it
+/// on the top of its user stack, with `tp` pointing to it.
+fn user_init_context(
+    entry: usize,
+    arg: usize,
+    ustack_top: usize,
+    tls: Option<&TlsTemplate>,
+) -> Option<TrapContext> {
+    let (sp, tp) = match tls {
+        Some(tls) => {
+            let tp = tls.init_block(ustack_top)?;
+            // keep the stack 16-byte aligned below the block
+            (tp & !0xf, tp)
+        }
+        None => (ustack_top, 0),
+    };
+    let mut trap_cx = TrapContext::app_init_context(entry, sp);
+    trap_cx.set_tp(tp);
+    trap_cx.x[10] = arg;
+    Some(trap_cx)
+}
+
 /// The status of a task
 #[derive(Copy, Clone, PartialEq)]
 pub enum TaskStatus {
@@ -37,6 +254,8 @@ pub enum TaskStatus {
     Ready,
     /// running
     Running,
+    /// sleeping on some wait queue
+    Blocked,
     /// exited
     Exited,
 }
@@ -1,7 +1,18 @@
 //! RISC-V timer-related functionality
+//!
+//! Besides the clock, a global timer queue runs callbacks once their deadline
+//! has passed. It is checked on every timer interrupt of every hart, so its
+//! resolution is one tick.
 
 use crate::config::CLOCK_FREQ;
 use crate::sbi::set_timer;
+use crate::sync::SpinLock;
+use alloc::boxed::Box;
+use alloc::collections::BinaryHeap;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::sync::atomic::{self, AtomicUsize};
+use lazy_static::*;
 use riscv::register::time;
 /// The number of ticks per second
 const TICKS_PER_SEC: usize = 100;
@@ -33,3 +44,74 @@ pub fn get_time_us() -> usize {
 pub fn set_next_trigger() {
     set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
 }
+
+/// A callback waiting in the timer queue
+struct Timer {
+    /// deadline in milliseconds
+    expire_ms: usize,
+    /// to cancel the timer with [`remove_timer`]
+    id: usize,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    /// `BinaryHeap` is a max-heap, so the earliest deadline is the greatest
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.expire_ms, other.id).cmp(&(self.expire_ms, self.id))
+    }
+}
+
+lazy_static! {
+    /// Timers of all harts, earliest deadline first
+    static ref TIMERS: SpinLock<BinaryHeap<Timer>> = SpinLock::new(BinaryHeap::new());
+}
+
+/// id of the next timer
+static NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Run `callback` in interrupt context once the time reaches `expire_ms`.
+///
+/// Returns an id to cancel the timer with [`remove_timer`].
+pub fn add_timer(expire_ms: usize, callback: impl FnOnce() + Send + 'static) -> usize {
+    let id = NEXT_TIMER_ID.fetch_add(1, atomic::Ordering::Relaxed);
+    TIMERS.lock().push(Timer {
+        expire_ms,
+        id,
+        callback: Box::new(callback),
+    });
+    id
+}
+
+/// Cancel timer `id`, if it has not fired yet
+pub fn remove_timer(id: usize) {
+    TIMERS.lock().retain(|timer| timer.id != id);
+}
+
+/// Run the callbacks of all expired timers
+pub fn check_timer() {
+    let now = get_time_ms();
+    let mut expired = Vec::new();
+    let mut timers = TIMERS.lock();
+    while timers.peek().is_some_and(|timer| timer.expire_ms <= now) {
+        expired.push(timers.pop().unwrap());
+    }
+    drop(timers);
+    // callbacks may add timers of their own
+    for timer in expired {
+        (timer.callback)();
+    }
+}
@@ -1,6 +1,7 @@
-use riscv::register::sstatus::{self, Sstatus, SPP};
+use riscv::register::sstatus::{self, Sstatus, FS, SPP};
 
-#[repr(C)]
+// aligned so that the kernel stack below it stays 16-byte aligned
+#[repr(C, align(16))]
 #[derive(Debug)]
 /// trap context structure containing sstatus, sepc and registers
 pub struct TrapContext {
@@ -10,6 +11,8 @@ pub struct TrapContext {
     pub sstatus: Sstatus,
     /// Supervisor Exception Program Counter
     pub sepc: usize,
+    /// tp of the kernel, i.e. the hart id, while the thread runs in userspace
+    pub kernel_tp: usize,
 }
 
 impl TrapContext {
@@ -17,6 +20,17 @@ impl TrapContext {
     pub fn set_sp(&mut self, sp: usize) {
         self.x[2] = sp;
     }
+    /// put the tp(thread pointer) into x\[4\] field of TrapContext
+    pub fn set_tp(&mut self, tp: usize) {
+        self.x[4] = tp;
+    }
+    /// set the FS field of the saved sstatus, which the riscv crate only
+    /// knows how to set on the CSR itself
+    pub fn set_fs(&mut self, fs: FS) {
+        // `Sstatus` is nothing but the bits of the CSR, as `trap.S` also assumes
+        let bits = unsafe { &mut *(&mut self.sstatus as *mut Sstatus as *mut usize) };
+        *bits = (*bits & !(0b11 << 13)) | ((fs as usize) << 13);
+    }
     /// init the trap context of an application
     pub fn app_init_context(entry: usize, sp: usize) -> Self {
         let mut sstatus = sstatus::read(); // CSR sstatus
@@ -24,9 +38,11 @@ impl TrapContext {
         let mut cx = Self {
             x: [0; 32],
             sstatus,
-            sepc: entry, // entry point of app
+            sepc: entry,  // entry point of app
+            kernel_tp: 0, // set by `__restore`
         };
         cx.set_sp(sp); // app's user stack pointer
+        cx.set_fs(FS::Off); // FP registers are turned on by their first use
         cx // return initial Trap Context of app
     }
 }
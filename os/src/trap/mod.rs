@@ -1,38 +1,64 @@
 //! Trap handling functionality
 //!
-//! For rCore, we have a single trap entry point, namely `__alltraps`. At
-//! initialization in [`init()`], we set the `stvec` CSR to point to it.
+//! For rCore, traps from userspace enter at `__alltraps`, while traps taken
+//! in the kernel enter at `__alltraps_k`. `stvec` points to the kernel entry
+//! whenever a hart runs kernel code, and `__restore` switches it back to the
+//! user entry right before returning to userspace.
 //!
-//! All traps go through `__alltraps`, which is defined in `trap.S`. The
-//! assembly language code does just enough work restore the kernel space
-//! context, ensuring that Rust code safely runs, and transfers control to
-//! [`trap_handler()`].
+//! All traps from userspace go through `__alltraps`, which is defined in
+//! `trap.S`. The assembly language code does just enough work restore the
+//! kernel space context, ensuring that Rust code safely runs, and transfers
+//! control to [`trap_handler()`]. The kernel only ever takes interrupts, and
+//! only while a hart waits for work in [`wait_for_interrupt()`]; they are
+//! handled by [`trap_from_kernel()`].
 //!
 //! It then calls different functionality based on what exactly the exception
-//! was. For example, timer interrupts trigger task preemption, and syscalls go
-//! to [`syscall()`].
+//! was. For example, timer interrupts trigger task preemption, interrupts of
+//! devices go to their drivers through [`handle_external_interrupt()`], and
+//! syscalls go to [`syscall()`].
 
 mod context;
 
+use crate::drivers::handle_external_interrupt;
 use crate::syscall::syscall;
-use crate::task::{exit_current_and_run_next, suspend_current_and_run_next};
-use crate::timer::set_next_trigger;
-use core::arch::global_asm;
+use crate::task::{
+    charge_kernel_time, charge_user_time, current_process_ended, current_task, enable_current_fp,
+    exit_current_and_run_next, exit_current_process_and_run_next, handle_signals, hart_id,
+    raise_fault_signal, save_current_fp, suspend_current_and_run_next, take_new_trap_cx,
+    SignalFlags,
+};
+use crate::timer::{check_timer, set_next_trigger};
+use core::arch::{asm, global_asm};
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self, Exception, Interrupt, Trap},
-    sie, stval, stvec,
+    sie, sstatus, stval, stvec,
 };
 
 global_asm!(include_str!("trap.S"));
 
 /// Initialize trap handling
 pub fn init() {
+    set_kernel_trap_entry();
+}
+
+/// Traps taken in the kernel go to `__alltraps_k`
+fn set_kernel_trap_entry() {
     extern "C" {
-        fn __alltraps();
+        fn __alltraps_k();
+    }
+    unsafe {
+        stvec::write(__alltraps_k as usize, TrapMode::Direct);
     }
+}
+
+/// Enable interrupts on this hart and wait for one of them, which is handled
+/// by [`trap_from_kernel()`] before this function returns
+pub fn wait_for_interrupt() {
     unsafe {
-        stvec::write(__alltraps as usize, TrapMode::Direct);
+        sstatus::set_sie();
+        asm!("wfi");
+        sstatus::clear_sie();
     }
 }
 
@@ -43,31 +69,65 @@ pub fn enable_timer_interrupt() {
     }
 }
 
+/// enable external interrupt in supervisor mode, i.e. interrupts of devices
+pub fn enable_external_interrupt() {
+    unsafe {
+        sie::set_sext();
+    }
+}
+
 /// trap handler
 #[no_mangle]
 pub fn trap_handler(cx: &mut TrapContext) -> &mut TrapContext {
+    set_kernel_trap_entry();
     let scause = scause::read(); // get trap cause
     let stval = stval::read(); // get extra value
                                // trace!("into {:?}", scause.cause());
+    charge_user_time(&current_task().unwrap());
+    save_current_fp(cx);
+    // other threads of the process follow the exit of its main thread
+    if current_process_ended() {
+        exit_current_and_run_next(-1);
+    }
     match scause.cause() {
         Trap::Exception(Exception::UserEnvCall) => {
             // jump to next instruction anyway
             cx.sepc += 4;
             // get system call return value
-            cx.x[10] = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12]]) as usize;
+            cx.x[10] = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12], cx.x[13]]) as usize;
+            // sys_exec leaves the context of the new app for us to switch to
+            if let Some(new_cx) = take_new_trap_cx() {
+                *cx = new_cx;
+            }
         }
-        Trap::Exception(Exception::StoreFault) | Trap::Exception(Exception::StorePageFault) => {
-            println!("[kernel] PageFault in application, bad addr = {:#x}, bad instruction = {:#x}, kernel killed it.", stval, cx.sepc);
-            exit_current_and_run_next();
+        Trap::Exception(Exception::StoreFault)
+        | Trap::Exception(Exception::StorePageFault)
+        | Trap::Exception(Exception::LoadFault)
+        | Trap::Exception(Exception::LoadPageFault)
+        | Trap::Exception(Exception::InstructionFault)
+        | Trap::Exception(Exception::InstructionPageFault) => {
+            if !raise_fault_signal(SignalFlags::SIGSEGV) {
+                println!("[kernel] PageFault in application, bad addr = {:#x}, bad instruction = {:#x}, kernel killed it.", stval, cx.sepc);
+                exit_current_process_and_run_next(-2);
+            }
+        }
+        Trap::Exception(Exception::IllegalInstruction) if enable_current_fp(cx) => {
+            // retry the instruction with the FP registers turned on
         }
         Trap::Exception(Exception::IllegalInstruction) => {
-            println!("[kernel] IllegalInstruction in application, kernel killed it.");
-            exit_current_and_run_next();
+            if !raise_fault_signal(SignalFlags::SIGILL) {
+                println!("[kernel] IllegalInstruction in application, kernel killed it.");
+                exit_current_process_and_run_next(-3);
+            }
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             set_next_trigger();
+            check_timer();
             suspend_current_and_run_next();
         }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            handle_external_interrupt(hart_id());
+        }
         _ => {
             panic!(
                 "Unsupported trap {:?}, stval = {:#x}!",
@@ -76,7 +136,37 @@ pub fn trap_handler(cx: &mut TrapContext) -> &mut TrapContext {
             );
         }
     }
+    // the process may have ended while we were in the kernel
+    if current_process_ended() {
+        exit_current_and_run_next(-1);
+    }
+    charge_kernel_time(&current_task().unwrap());
+    handle_signals(cx);
     cx
 }
 
+/// handle a trap taken in the kernel, which can only be an interrupt
+#[no_mangle]
+pub fn trap_from_kernel(_cx: &mut TrapContext) {
+    let scause = scause::read();
+    let stval = stval::read();
+    match scause.cause() {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            // no preemption in the kernel, just keep the clock ticking
+            set_next_trigger();
+            check_timer();
+        }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            handle_external_interrupt(hart_id());
+        }
+        _ => {
+            panic!(
+                "Unsupported trap from kernel: {:?}, stval = {:#x}!",
+                scause.cause(),
+                stval
+            );
+        }
+    }
+}
+
 pub use context::TrapContext;
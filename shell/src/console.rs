@@ -0,0 +1,39 @@
+//! `print!` and `println!`, writing to the standard output
+
+use super::write;
+use core::fmt::{self, Write};
+
+/// Descriptor of the standard output
+const STDOUT: usize = 1;
+
+struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(STDOUT, s.as_bytes());
+        Ok(())
+    }
+}
+
+pub fn print(args: fmt::Arguments) {
+    Stdout.write_fmt(args).unwrap();
+}
+
+/// Print to the standard output
+#[macro_export]
+macro_rules! print {
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::print(format_args!($fmt $(, $($arg)+)?));
+    }
+}
+
+/// Print to the standard output, with a newline
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::console::print(format_args!("\n"));
+    };
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
+    }
+}
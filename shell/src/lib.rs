@@ -0,0 +1,128 @@
+//! User library of the shell
+//!
+//! Just enough of a runtime for an app of the rCore kernel: the entry point,
+//! a panic handler, console output, and wrappers of the syscalls the shell
+//! needs. Apps are started by name with [`spawn`] or [`exec`], and a parent
+//! collects the exit code of a child with [`waitpid`].
+//!
+//! Names and arguments are C strings, as the kernel reads them up to their NUL.
+
+#![no_std]
+
+#[macro_use]
+pub mod console;
+mod syscall;
+
+use core::ffi::CStr;
+use core::panic::PanicInfo;
+use core::ptr::null;
+use syscall::*;
+
+/// ioctl to set the pid of the foreground process of the terminal
+pub const TIOCSPGRP: usize = 0x5410;
+/// Interrupt from keyboard
+pub const SIGINT: usize = 2;
+/// `SignalAction::handler` of the default action
+pub const SIG_DFL: usize = 0;
+/// `SignalAction::handler` of ignoring the signal
+pub const SIG_IGN: usize = 1;
+
+/// What the process does on a signal, as exchanged with [`sigaction`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalAction {
+    /// address of the handler, or [`SIG_DFL`] or [`SIG_IGN`]
+    pub handler: usize,
+    /// signals blocked while the handler runs, signal `n` being bit `n`
+    pub mask: u32,
+}
+
+#[no_mangle]
+#[link_section = ".text.entry"]
+extern "C" fn _start(argc: usize, argv: *const *const u8) -> ! {
+    extern "Rust" {
+        fn main(argc: usize, argv: *const *const u8) -> i32;
+    }
+    exit(unsafe { main(argc, argv) });
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("[user] {}", info);
+    exit(-1);
+}
+
+/// Read into `buf` from `fd`, returning the number of bytes read, 0 at the end
+/// of the file, or a negative error code
+pub fn read(fd: usize, buf: &mut [u8]) -> isize {
+    sys_read(fd, buf)
+}
+
+/// Write `buf` to `fd`, returning the number of bytes written
+pub fn write(fd: usize, buf: &[u8]) -> isize {
+    sys_write(fd, buf)
+}
+
+/// Handle device-specific `request` of the file with `fd`, which takes `arg`
+pub fn ioctl<T>(fd: usize, request: usize, arg: &mut T) -> isize {
+    sys_ioctl(fd, request, arg as *mut T as usize)
+}
+
+/// End the process with `exit_code`
+pub fn exit(exit_code: i32) -> ! {
+    sys_exit(exit_code);
+}
+
+/// Give up the hart to another task
+pub fn yield_() -> isize {
+    sys_yield()
+}
+
+/// Set the action of signal `signum`, returning -1 if it cannot be changed
+pub fn sigaction(signum: usize, action: &SignalAction) -> isize {
+    sys_sigaction(signum, action as *const SignalAction as usize, 0)
+}
+
+/// Get the pid of the process
+pub fn getpid() -> isize {
+    sys_getpid()
+}
+
+/// Start the app named `path` in a new child process, returning its pid, or
+/// -1 if there is no such app or it is already running
+pub fn spawn(path: &CStr) -> isize {
+    sys_spawn(path.as_ptr() as *const u8)
+}
+
+/// Replace the app of the process with the one named `path`, run with the
+/// arguments `argv`, which must end with a null pointer
+///
+/// Only returns, with -1, if the app cannot be started.
+pub fn exec(path: &CStr, argv: &[*const u8]) -> isize {
+    assert_eq!(
+        argv.last(),
+        Some(&null()),
+        "argv must end with a null pointer"
+    );
+    sys_exec(path.as_ptr() as *const u8, argv.as_ptr(), null())
+}
+
+/// Wait for child `pid` to exit, or for any child if it is -1, and store its
+/// exit code. Returns the pid of the child, or -1 if there is no such child.
+pub fn waitpid(pid: isize, exit_code: &mut i32) -> isize {
+    loop {
+        match sys_waitpid(pid, exit_code as *mut i32) {
+            // it has not exited yet
+            -2 => {
+                yield_();
+            }
+            ret => return ret,
+        }
+    }
+}
+
+/// Copy the names of the apps into `buf`, each ended by a NUL, returning the
+/// length of the whole list, which is only partly copied if `buf` is shorter
+pub fn list_apps(buf: &mut [u8]) -> usize {
+    sys_list_apps(buf) as usize
+}
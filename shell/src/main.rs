@@ -0,0 +1,114 @@
+//! The shell, started by the kernel as the initial process
+//!
+//! Every line read from the terminal names an app, which is spawned, given
+//! the terminal as its foreground process, and waited for. `ls` lists the
+//! apps, and `exit` or Ctrl-D on an empty line ends the shell.
+//!
+//! Ctrl-C goes to the app in the foreground and is ignored by the shell.
+
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_shell;
+
+use core::ffi::CStr;
+use user_shell::{
+    getpid, ioctl, list_apps, read, sigaction, spawn, waitpid, SignalAction, SIGINT, SIG_IGN,
+    TIOCSPGRP,
+};
+
+/// Descriptor of the standard input
+const STDIN: usize = 0;
+/// the max length of a line, which the terminal hands over in pieces if longer
+const LINE_MAX: usize = 128;
+/// room for the names of the apps
+const APP_LIST_MAX: usize = 1024;
+
+/// Print the names of the apps, one per line
+fn print_apps() {
+    let mut buf = [0u8; APP_LIST_MAX];
+    let len = list_apps(&mut buf).min(APP_LIST_MAX);
+    for name in buf[..len].split(|&byte| byte == 0) {
+        if let Ok(name) = core::str::from_utf8(name) {
+            if !name.is_empty() {
+                println!("{}", name);
+            }
+        }
+    }
+}
+
+/// Make `pid` the foreground process of the terminal
+fn set_foreground(mut pid: usize) {
+    ioctl(STDIN, TIOCSPGRP, &mut pid);
+}
+
+/// Run the app named `name` in the foreground, and wait for it to exit
+fn run(name: &CStr, shell_pid: usize) {
+    let app = name.to_str().unwrap_or("?");
+    let pid = spawn(name);
+    if pid < 0 {
+        println!("{}: no such app, or it is already running", app);
+        return;
+    }
+    set_foreground(pid as usize);
+    let mut exit_code = 0;
+    waitpid(pid, &mut exit_code);
+    set_foreground(shell_pid);
+    println!(
+        "[shell] {} (pid {}) exited with code {}",
+        app, pid, exit_code
+    );
+}
+
+#[no_mangle]
+fn main(_argc: usize, _argv: *const *const u8) -> i32 {
+    let ignore = SignalAction {
+        handler: SIG_IGN,
+        mask: 0,
+    };
+    sigaction(SIGINT, &ignore);
+    let shell_pid = getpid() as usize;
+    set_foreground(shell_pid);
+    println!("rCore shell, type `ls` to list the apps");
+    // one more byte for the NUL ending the name
+    let mut line = [0u8; LINE_MAX + 1];
+    loop {
+        print!(">> ");
+        let len = match read(STDIN, &mut line[..LINE_MAX]) {
+            0 => {
+                println!();
+                return 0;
+            }
+            // interrupted by a signal
+            len if len < 0 => {
+                println!();
+                continue;
+            }
+            len => len as usize,
+        };
+        let Ok(command) = core::str::from_utf8(&line[..len]) else {
+            println!("not UTF-8");
+            continue;
+        };
+        let command = command.trim();
+        match command {
+            "" => {}
+            "ls" => print_apps(),
+            "exit" => return 0,
+            _ if command.contains(char::is_whitespace) => {
+                println!("{}: apps take no arguments", command);
+            }
+            _ => {
+                // `command` lies in `line`, which has room for a NUL after it
+                let start = command.as_ptr() as usize - line.as_ptr() as usize;
+                let end = start + command.len();
+                line[end] = 0;
+                match CStr::from_bytes_with_nul(&line[start..=end]) {
+                    Ok(name) => run(name, shell_pid),
+                    Err(_) => println!("app names hold no NUL"),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Raw syscalls of the rCore kernel, numbered like `os/src/syscall/mod.rs`
+
+use core::arch::asm;
+
+const SYSCALL_IOCTL: usize = 29;
+const SYSCALL_READ: usize = 63;
+const SYSCALL_WRITE: usize = 64;
+const SYSCALL_EXIT: usize = 93;
+const SYSCALL_YIELD: usize = 124;
+const SYSCALL_SIGACTION: usize = 134;
+const SYSCALL_GETPID: usize = 172;
+const SYSCALL_EXEC: usize = 221;
+const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_SPAWN: usize = 400;
+const SYSCALL_LIST_APPS: usize = 401;
+
+fn syscall(id: usize, args: [usize; 3]) -> isize {
+    let mut ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") args[0] => ret,
+            in("x11") args[1],
+            in("x12") args[2],
+            in("x17") id
+        );
+    }
+    ret
+}
+
+pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
+    syscall(SYSCALL_IOCTL, [fd, request, arg])
+}
+
+pub fn sys_read(fd: usize, buf: &mut [u8]) -> isize {
+    syscall(SYSCALL_READ, [fd, buf.as_mut_ptr() as usize, buf.len()])
+}
+
+pub fn sys_write(fd: usize, buf: &[u8]) -> isize {
+    syscall(SYSCALL_WRITE, [fd, buf.as_ptr() as usize, buf.len()])
+}
+
+pub fn sys_exit(exit_code: i32) -> ! {
+    syscall(SYSCALL_EXIT, [exit_code as usize, 0, 0]);
+    unreachable!("sys_exit returned");
+}
+
+pub fn sys_yield() -> isize {
+    syscall(SYSCALL_YIELD, [0; 3])
+}
+
+pub fn sys_sigaction(signum: usize, action: usize, old_action: usize) -> isize {
+    syscall(SYSCALL_SIGACTION, [signum, action, old_action])
+}
+
+pub fn sys_getpid() -> isize {
+    syscall(SYSCALL_GETPID, [0; 3])
+}
+
+pub fn sys_exec(path: *const u8, argv: *const *const u8, envp: *const *const u8) -> isize {
+    syscall(SYSCALL_EXEC, [path as usize, argv as usize, envp as usize])
+}
+
+pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
+    syscall(SYSCALL_WAITPID, [pid as usize, exit_code as usize, 0])
+}
+
+pub fn sys_spawn(path: *const u8) -> isize {
+    syscall(SYSCALL_SPAWN, [path as usize, 0, 0])
+}
+
+pub fn sys_list_apps(buf: &mut [u8]) -> isize {
+    syscall(SYSCALL_LIST_APPS, [buf.as_mut_ptr() as usize, buf.len(), 0])
+}